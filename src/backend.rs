@@ -0,0 +1,98 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::error::Error;
+use crate::purge::{PurgeOptions, PurgedItem};
+use crate::search::{FoundItem, SearchScope};
+use anyhow::Result;
+use std::collections::HashMap;
+
+#[cfg(target_os = "linux")]
+use crate::secret_service::watch::Watcher;
+
+/// A place to store secrets, like the OS keyring or an encrypted file.
+///
+/// A backend is created for one entry, identified by a username, a service and an application,
+/// and reads and writes that entry's secret.
+pub trait Backend {
+    /// A short name identifying the backend, e.g. `secret-service`
+    fn name(&self) -> &'static str;
+
+    fn set_password(&self, password: String) -> Result<()>;
+
+    fn get_password(&self) -> Result<Option<String>>;
+
+    /// Removes the secret, returns whether there was one to remove
+    fn delete_password(&self) -> Result<bool> {
+        Err(Error::unsupported(self.name(), "deleting secrets").into())
+    }
+
+    /// Lists the entries stored for the application
+    fn list(&self) -> Result<Vec<Entry>> {
+        Err(Error::unsupported(self.name(), "listing entries").into())
+    }
+
+    /// Lists the profiles the application has secrets in, see [`Options::profile`](crate::Options::profile)
+    fn profiles(&self) -> Result<Vec<String>> {
+        Err(Error::unsupported(self.name(), "profiles").into())
+    }
+
+    /// Finds the items that have all of `attributes`, see [`SearchScope`]
+    fn search(
+        &self,
+        _attributes: &HashMap<&str, &str>,
+        _scope: SearchScope,
+    ) -> Result<Vec<FoundItem<'_>>> {
+        Err(Error::unsupported(self.name(), "searching").into())
+    }
+
+    /// Removes the items stored for the application, see [`PurgeOptions`]
+    fn purge(&self, _options: &PurgeOptions) -> Result<Vec<PurgedItem>> {
+        Err(Error::unsupported(self.name(), "purging").into())
+    }
+
+    /// Copies the secret into another collection, keeping its label and attributes
+    ///
+    /// `collection` is an alias like `default` or an object path, see
+    /// [`Options::collection`](crate::Options::collection). Returns whether there was a secret to
+    /// copy.
+    fn copy_to(&self, _collection: &str) -> Result<bool> {
+        Err(Error::unsupported(self.name(), "copying secrets between collections").into())
+    }
+
+    /// Moves the secret into another collection, keeping its label and attributes
+    ///
+    /// The secret is removed from its collection only after it was copied, so it stays where it
+    /// was when the copy fails. Returns whether there was a secret to move.
+    fn move_to(&self, _collection: &str) -> Result<bool> {
+        Err(Error::unsupported(self.name(), "moving secrets between collections").into())
+    }
+
+    /// Changes the label of the secret, returns whether there was one to relabel
+    fn relabel(&self, _label: &str) -> Result<bool> {
+        Err(Error::unsupported(self.name(), "relabeling secrets").into())
+    }
+
+    /// Returns when the secret was last modified, in seconds since the unix epoch
+    ///
+    /// Returns `None` when there is no secret or the backend doesn't track modification times.
+    fn modified(&self) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Returns a blocking iterator over changes to the secret
+    #[cfg(target_os = "linux")]
+    fn watch(&self) -> Result<Watcher> {
+        Err(Error::unsupported(self.name(), "watching for changes").into())
+    }
+}
+
+/// An entry stored in a backend.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Entry {
+    pub service: String,
+    pub username: String,
+}
@@ -0,0 +1,309 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::KeyringClient;
+use anyhow::Result;
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+
+#[cfg(target_os = "linux")]
+use crate::secret_service::watch::StopGuard;
+#[cfg(target_os = "linux")]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(target_os = "linux")]
+use std::sync::Arc;
+#[cfg(target_os = "linux")]
+use std::thread::JoinHandle;
+
+/// A read-through cache around a [`KeyringClient`].
+///
+/// Decrypted secrets are kept in locked memory that is zeroed when the entry is dropped. An entry is
+/// served until its TTL expires or the stored item changes. On Linux, changes are picked up through
+/// the Secret Service change signals, falling back to comparing the item's `Modified` property when
+/// those aren't available or stopped arriving. Dropping the cache stops listening for signals.
+pub struct CachedKeyringClient<'a> {
+    client: KeyringClient<'a>,
+    ttl: Duration,
+    entry: RefCell<Option<CacheEntry>>,
+    #[cfg(target_os = "linux")]
+    watch: Option<Watch>,
+}
+
+/// The thread listening for change signals
+#[cfg(target_os = "linux")]
+struct Watch {
+    state: Arc<WatchState>,
+    stop: Option<StopGuard>,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Default)]
+struct WatchState {
+    changed: AtomicBool,
+    stopped: AtomicBool,
+}
+
+/// Marks the watch as stopped when its thread ends, also when it panics
+#[cfg(target_os = "linux")]
+struct Stopped(Arc<WatchState>);
+
+#[cfg(target_os = "linux")]
+impl Drop for Stopped {
+    fn drop(&mut self) {
+        self.0.stopped.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Watch {
+    fn drop(&mut self) {
+        // ends the watcher's wait for a signal, so the thread can be joined
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+struct CacheEntry {
+    secret: LockedSecret,
+    fetched: Instant,
+    modified: Option<u64>,
+}
+
+impl<'a> CachedKeyringClient<'a> {
+    /// Returns a new caching client
+    ///
+    /// # Arguments
+    ///
+    /// * `client` - The client to read through to
+    /// * `ttl` - How long a secret is served from the cache
+    pub fn new(client: KeyringClient<'a>, ttl: Duration) -> Self {
+        Self {
+            #[cfg(target_os = "linux")]
+            watch: Self::watch(&client),
+            client,
+            ttl,
+            entry: RefCell::new(None),
+        }
+    }
+
+    pub fn set_password(&self, password: String) -> Result<()> {
+        self.invalidate();
+        self.client.set_password(password)
+    }
+
+    pub fn get_password(&self) -> Result<Option<String>> {
+        if let Some(secret) = self.cached()? {
+            return Ok(Some(secret));
+        }
+
+        let modified = self.client.modified()?;
+        let secret = match self.client.get_password()? {
+            Some(secret) => secret,
+            None => return Ok(None),
+        };
+
+        self.entry.replace(Some(CacheEntry {
+            secret: LockedSecret::new(&secret),
+            fetched: Instant::now(),
+            modified,
+        }));
+
+        Ok(Some(secret))
+    }
+
+    /// Drops the cached secret, so the next read goes to the keyring
+    pub fn invalidate(&self) {
+        self.entry.replace(None);
+    }
+
+    /// Returns the wrapped client
+    pub fn client(&self) -> &KeyringClient<'a> {
+        &self.client
+    }
+
+    fn cached(&self) -> Result<Option<String>> {
+        let changed = self.signaled();
+
+        let cached = self.entry.borrow();
+        let entry = match cached.as_ref() {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+
+        let valid = match changed {
+            _ if entry.fetched.elapsed() >= self.ttl => false,
+            Some(changed) => !changed,
+            // without change signals, ask the keyring whether the item was modified since, which
+            // also catches changes missed since the signals stopped
+            None => self.client.modified()? == entry.modified,
+        };
+        if valid {
+            event!(trace, "serving secret from the cache");
+            return Ok(Some(entry.secret.reveal()));
+        }
+        event!(debug, "cached secret is stale");
+
+        drop(cached);
+        self.invalidate();
+
+        Ok(None)
+    }
+
+    /// Whether a change was signaled since the last call, `None` without a running watch
+    #[cfg(target_os = "linux")]
+    fn signaled(&self) -> Option<bool> {
+        let state = &self.watch.as_ref()?.state;
+        let changed = state.changed.swap(false, Ordering::SeqCst);
+        if state.stopped.load(Ordering::SeqCst) {
+            event!(
+                debug,
+                "no longer watching for changes, checking the modification time"
+            );
+            return None;
+        }
+
+        Some(changed)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn signaled(&self) -> Option<bool> {
+        None
+    }
+
+    #[cfg(target_os = "linux")]
+    fn watch(client: &KeyringClient<'a>) -> Option<Watch> {
+        let mut watcher = client.watch().ok()?;
+        let stop = watcher.stop_guard().ok()?;
+        let state = Arc::new(WatchState::default());
+        let stopped = Stopped(state.clone());
+
+        // runs until the cache is dropped or the connection is closed
+        let thread = std::thread::spawn(move || {
+            for _event in watcher {
+                // also a change we couldn't read may have been to our item
+                stopped.0.changed.store(true, Ordering::SeqCst);
+            }
+            drop(stopped);
+        });
+
+        Some(Watch {
+            state,
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+/// A secret held in memory that is locked into RAM and zeroed on drop
+struct LockedSecret {
+    bytes: Box<[u8]>,
+}
+
+impl LockedSecret {
+    fn new(secret: &str) -> Self {
+        // the buffer is locked before the secret is copied in, so it's never swapped out, and sized
+        // exactly, so it's never reallocated leaving a copy behind
+        let mut bytes = vec![0; secret.len()].into_boxed_slice();
+        // SAFETY: the pointer and length describe `bytes`, which we own and which outlives the call.
+        // Locking can fail when over RLIMIT_MEMLOCK, the secret is still zeroed on drop.
+        unsafe { memsec::mlock(bytes.as_mut_ptr(), bytes.len()) };
+        bytes.copy_from_slice(secret.as_bytes());
+
+        Self { bytes }
+    }
+
+    fn reveal(&self) -> String {
+        String::from_utf8_lossy(&self.bytes).into_owned()
+    }
+}
+
+impl Drop for LockedSecret {
+    fn drop(&mut self) {
+        // SAFETY: the pointer and length describe `bytes`, which is still alive here and locked by
+        // `new`, unlocking memory that failed to lock is harmless
+        unsafe {
+            memsec::memzero(self.bytes.as_mut_ptr(), self.bytes.len());
+            memsec::munlock(self.bytes.as_mut_ptr(), self.bytes.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Backend;
+    use crate::mock::MockKeyring;
+
+    const APPLICATION: &str = "cache-tests";
+
+    fn cached(service: &'static str, ttl: Duration) -> CachedKeyringClient<'static> {
+        let client = KeyringClient::with_backend(MockKeyring::new("alice", service, APPLICATION));
+        CachedKeyringClient::new(client, ttl)
+    }
+
+    #[test]
+    fn serves_the_cached_secret_until_the_ttl_expires() {
+        let service = "cache-ttl";
+        let other = MockKeyring::new("alice", service, APPLICATION);
+        other.set_password("first".to_string()).unwrap();
+
+        let cache = cached(service, Duration::from_secs(3600));
+        let expired = cached(service, Duration::ZERO);
+        assert_eq!(cache.get_password().unwrap().as_deref(), Some("first"));
+        assert_eq!(expired.get_password().unwrap().as_deref(), Some("first"));
+
+        let modified = other.modified().unwrap();
+        other.set_password("second".to_string()).unwrap();
+        assert_eq!(expired.get_password().unwrap().as_deref(), Some("second"));
+        // the mock's modification times are in seconds, within the same second only the TTL can
+        // tell the secrets apart
+        if other.modified().unwrap() == modified {
+            assert_eq!(cache.get_password().unwrap().as_deref(), Some("first"));
+        }
+    }
+
+    #[test]
+    fn invalidating_reads_through() {
+        let service = "cache-invalidate";
+        let other = MockKeyring::new("alice", service, APPLICATION);
+        other.set_password("first".to_string()).unwrap();
+
+        let cache = cached(service, Duration::from_secs(3600));
+        assert_eq!(cache.get_password().unwrap().as_deref(), Some("first"));
+        other.set_password("second".to_string()).unwrap();
+
+        cache.invalidate();
+        assert_eq!(cache.get_password().unwrap().as_deref(), Some("second"));
+
+        cache.set_password("third".to_string()).unwrap();
+        assert_eq!(cache.get_password().unwrap().as_deref(), Some("third"));
+        assert_eq!(other.get_password().unwrap().as_deref(), Some("third"));
+    }
+
+    #[test]
+    fn notices_secrets_deleted_elsewhere() {
+        let service = "cache-deleted";
+        let other = MockKeyring::new("alice", service, APPLICATION);
+        other.set_password("secret".to_string()).unwrap();
+
+        let cache = cached(service, Duration::from_secs(3600));
+        assert_eq!(cache.get_password().unwrap().as_deref(), Some("secret"));
+
+        // the mock can't be watched, so the cache compares modification times
+        other.delete_password().unwrap();
+        assert_eq!(cache.get_password().unwrap(), None);
+    }
+
+    #[test]
+    fn locked_secrets_keep_their_bytes() {
+        for secret in ["", "hunter2", "pässwörd"] {
+            assert_eq!(LockedSecret::new(secret).reveal(), secret);
+        }
+    }
+}
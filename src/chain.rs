@@ -0,0 +1,356 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::backend::{Backend, Entry};
+use crate::error::Error;
+use crate::purge::{PurgeOptions, PurgedItem};
+use crate::search::{FoundItem, SearchScope};
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::cell::Cell;
+use std::collections::HashMap;
+
+#[cfg(target_os = "linux")]
+use crate::secret_service::watch::Watcher;
+
+/// When a [`ChainKeyring`] moves on to the next backend.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Fallback {
+    /// Use the first backend that could be created for everything
+    FirstAvailable,
+    /// Try the backends in order for every operation, until one doesn't fail
+    ///
+    /// Only errors fall through, a backend without the secret answers `None`.
+    PerOperation,
+}
+
+/// A backend made of an ordered list of backends, e.g. the Secret Service, then an encrypted file.
+///
+/// [`Backend::name`] returns the name of the backend that served the last request, so
+/// [`KeyringClient::backend_name`](crate::KeyringClient::backend_name) tells which one is in use.
+pub struct ChainKeyring<'a> {
+    backends: Vec<Box<dyn Backend + 'a>>,
+    fallback: Fallback,
+    serving: Cell<usize>,
+}
+
+/// Collects the backends of a [`ChainKeyring`], see [`ChainKeyring::builder`].
+pub struct ChainBuilder<'a> {
+    backends: Vec<Box<dyn Backend + 'a>>,
+    errors: Vec<anyhow::Error>,
+    fallback: Fallback,
+}
+
+impl<'a> ChainKeyring<'a> {
+    /// Returns a builder for a chain
+    ///
+    /// # Arguments
+    ///
+    /// * `fallback` - When to move on to the next backend
+    pub fn builder(fallback: Fallback) -> ChainBuilder<'a> {
+        ChainBuilder {
+            backends: Vec::new(),
+            errors: Vec::new(),
+            fallback,
+        }
+    }
+
+    /// Runs `f` on the backends in order until one succeeds
+    fn run<'s, T, F>(&'s self, operation: &str, f: F) -> Result<T>
+    where
+        F: Fn(&'s dyn Backend) -> Result<T>,
+    {
+        let mut errors = Vec::new();
+
+        for (index, backend) in self.backends.iter().enumerate() {
+            match f(backend.as_ref()) {
+                Ok(result) => {
+                    event!(debug, backend = backend.name(), operation, "served by");
+                    self.serving.set(index);
+                    return Ok(result);
+                }
+                Err(err) if self.fallback == Fallback::PerOperation => {
+                    event!(debug, backend = backend.name(), "falling back: {:#}", err);
+                    errors.push(format!("{}: {:#}", backend.name(), err));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Err(anyhow!(
+            "no backend could {}: {}",
+            operation,
+            errors.join("; ")
+        ))
+    }
+}
+
+impl<'a> ChainBuilder<'a> {
+    /// Adds a backend to the end of the chain
+    ///
+    /// Backends that couldn't be created, like the Secret Service without a session bus, are
+    /// skipped.
+    pub fn backend<B: Backend + 'a>(mut self, backend: Result<B>) -> Self {
+        match backend {
+            Ok(backend) => self.backends.push(Box::new(backend)),
+            Err(err) => {
+                event!(debug, "skipping unavailable backend: {:#}", err);
+                self.errors.push(err);
+            }
+        }
+
+        self
+    }
+
+    /// Returns the chain, or an error when none of its backends is available
+    pub fn build(mut self) -> Result<ChainKeyring<'a>> {
+        if self.backends.is_empty() {
+            let errors: Vec<String> = self.errors.iter().map(|err| format!("{:#}", err)).collect();
+            return Err(anyhow!("no backend is available: {}", errors.join("; ")));
+        }
+        if self.fallback == Fallback::FirstAvailable {
+            self.backends.truncate(1);
+        }
+
+        Ok(ChainKeyring {
+            backends: self.backends,
+            fallback: self.fallback,
+            serving: Cell::new(0),
+        })
+    }
+}
+
+impl Backend for ChainKeyring<'_> {
+    fn name(&self) -> &'static str {
+        self.backends[self.serving.get()].name()
+    }
+
+    fn set_password(&self, password: String) -> Result<()> {
+        self.run("store the secret", |backend| {
+            backend.set_password(password.clone())
+        })
+    }
+
+    fn get_password(&self) -> Result<Option<String>> {
+        self.run("read the secret", |backend| backend.get_password())
+    }
+
+    fn delete_password(&self) -> Result<bool> {
+        self.run("delete the secret", |backend| backend.delete_password())
+    }
+
+    fn list(&self) -> Result<Vec<Entry>> {
+        self.run("list entries", |backend| backend.list())
+    }
+
+    fn profiles(&self) -> Result<Vec<String>> {
+        self.run("list profiles", |backend| backend.profiles())
+    }
+
+    fn search(
+        &self,
+        attributes: &HashMap<&str, &str>,
+        scope: SearchScope,
+    ) -> Result<Vec<FoundItem<'_>>> {
+        self.run("search", |backend| backend.search(attributes, scope))
+    }
+
+    fn copy_to(&self, collection: &str) -> Result<bool> {
+        self.run("copy the secret", |backend| backend.copy_to(collection))
+    }
+
+    fn move_to(&self, collection: &str) -> Result<bool> {
+        self.run("move the secret", |backend| backend.move_to(collection))
+    }
+
+    fn relabel(&self, label: &str) -> Result<bool> {
+        self.run("relabel the secret", |backend| backend.relabel(label))
+    }
+
+    /// Purges every backend of the chain that supports it, not just the first
+    ///
+    /// When a backend fails after others were purged, the items already removed are returned in
+    /// [`Error::PartialPurge`].
+    fn purge(&self, options: &PurgeOptions) -> Result<Vec<PurgedItem>> {
+        let mut purged = Vec::new();
+        let mut supported = false;
+
+        for backend in &self.backends {
+            match backend.purge(options) {
+                Ok(items) => {
+                    supported = true;
+                    purged.extend(items);
+                }
+                Err(err) if matches!(err.downcast_ref(), Some(Error::Unsupported { .. })) => {
+                    event!(debug, backend = backend.name(), "can't purge, skipping");
+                }
+                Err(err) if purged.is_empty() => return Err(err),
+                Err(cause) => return Err(Error::PartialPurge { purged, cause }.into()),
+            }
+        }
+        if !supported {
+            return Err(Error::unsupported("chain", "purging").into());
+        }
+
+        Ok(purged)
+    }
+
+    fn modified(&self) -> Result<Option<u64>> {
+        self.run("read the modification time", |backend| backend.modified())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn watch(&self) -> Result<Watcher> {
+        self.run("watch for changes", |backend| backend.watch())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockKeyring;
+
+    /// Fails everything, like a keyring whose daemon is gone
+    struct Broken;
+
+    impl Backend for Broken {
+        fn name(&self) -> &'static str {
+            "broken"
+        }
+
+        fn set_password(&self, _password: String) -> Result<()> {
+            Err(anyhow!("the daemon is gone"))
+        }
+
+        fn get_password(&self) -> Result<Option<String>> {
+            Err(anyhow!("the daemon is gone"))
+        }
+
+        fn purge(&self, _options: &PurgeOptions) -> Result<Vec<PurgedItem>> {
+            Err(anyhow!("the daemon is gone"))
+        }
+    }
+
+    fn chain<'a>(
+        fallback: Fallback,
+        first: impl Backend + 'a,
+        second: impl Backend + 'a,
+    ) -> ChainKeyring<'a> {
+        ChainKeyring::builder(fallback)
+            .backend(Ok(first))
+            .backend(Ok(second))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn first_available_uses_the_first_backend() {
+        let mock = MockKeyring::new("alice", "chain-first", "chain-first-tests");
+        let chain = chain(Fallback::FirstAvailable, Broken, mock);
+
+        let err = chain.set_password("secret".to_string()).unwrap_err();
+        assert_eq!(format!("{:#}", err), "the daemon is gone");
+        assert!(chain.get_password().is_err());
+        assert_eq!(chain.name(), "broken");
+    }
+
+    #[test]
+    fn first_available_skips_unavailable_backends() {
+        let mock = MockKeyring::new("alice", "chain-skip", "chain-skip-tests");
+        let chain = ChainKeyring::builder(Fallback::FirstAvailable)
+            .backend::<Broken>(Err(anyhow!("no session bus")))
+            .backend(Ok(mock))
+            .build()
+            .unwrap();
+
+        chain.set_password("secret".to_string()).unwrap();
+        assert_eq!(chain.get_password().unwrap().as_deref(), Some("secret"));
+        assert_eq!(chain.name(), "mock");
+    }
+
+    #[test]
+    fn no_available_backend_fails_to_build() {
+        let err = ChainKeyring::builder(Fallback::PerOperation)
+            .backend::<Broken>(Err(anyhow!("no session bus")))
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "no backend is available: no session bus");
+    }
+
+    #[test]
+    fn per_operation_falls_through_errors() {
+        let mock = MockKeyring::new("alice", "chain-per-operation", "chain-per-operation-tests");
+        let chain = chain(Fallback::PerOperation, Broken, mock);
+
+        chain.set_password("secret".to_string()).unwrap();
+        assert_eq!(chain.name(), "mock");
+        assert_eq!(chain.get_password().unwrap().as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn per_operation_stops_at_missing_secrets() {
+        let empty = MockKeyring::new("alice", "chain-missing", "chain-missing-tests");
+        let other = MockKeyring::new("alice", "chain-missing", "chain-missing-other-tests");
+        other.set_password("secret".to_string()).unwrap();
+        let chain = chain(Fallback::PerOperation, empty, other);
+
+        assert_eq!(chain.get_password().unwrap(), None);
+    }
+
+    #[test]
+    fn per_operation_reports_every_error() {
+        let chain = chain(Fallback::PerOperation, Broken, Broken);
+
+        let err = chain.get_password().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "no backend could read the secret: broken: the daemon is gone; broken: the daemon is gone"
+        );
+    }
+
+    #[test]
+    fn purges_every_backend() {
+        let first = MockKeyring::new("alice", "chain-purge", "chain-purge-first-tests");
+        let second = MockKeyring::new("alice", "chain-purge", "chain-purge-second-tests");
+        first.set_password("first".to_string()).unwrap();
+        second.set_password("second".to_string()).unwrap();
+        let chain = chain(Fallback::PerOperation, first, second);
+
+        let purged = chain.purge(&PurgeOptions::default()).unwrap();
+        assert_eq!(purged.len(), 2);
+        assert_eq!(chain.get_password().unwrap(), None);
+    }
+
+    #[test]
+    fn returns_partial_purges_with_the_error() {
+        let mock = MockKeyring::new("alice", "chain-partial", "chain-partial-tests");
+        mock.set_password("secret".to_string()).unwrap();
+        let chain = chain(Fallback::PerOperation, mock, Broken);
+
+        let err = chain.purge(&PurgeOptions::default()).unwrap_err();
+        match err.downcast_ref() {
+            Some(Error::PartialPurge { purged, cause }) => {
+                assert_eq!(purged.len(), 1);
+                assert_eq!(purged[0].label, "chain-partial: alice");
+                assert_eq!(cause.to_string(), "the daemon is gone");
+            }
+            _ => panic!("expected a partial purge, got {:#}", err),
+        }
+    }
+
+    #[test]
+    fn purge_failures_before_any_purge_are_returned_as_is() {
+        let mock = MockKeyring::new("alice", "chain-failed", "chain-failed-tests");
+        mock.set_password("secret".to_string()).unwrap();
+        let chain = chain(Fallback::PerOperation, Broken, mock);
+
+        let err = chain.purge(&PurgeOptions::default()).unwrap_err();
+        assert_eq!(err.to_string(), "the daemon is gone");
+        assert!(chain.get_password().unwrap().is_some());
+    }
+}
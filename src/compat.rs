@@ -0,0 +1,367 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! The surface of the [`keyring`](https://docs.rs/keyring) crate on top of [`KeyringClient`].
+//!
+//! Code written against the `keyring` crate moves over by importing
+//! `tmuntaner_keyring::compat::{Entry, Error, Result}` instead of `keyring::{Entry, Error, Result}`.
+//! Entries use the [`AttributeLayout::KeyringCrate`] layout, so they find the secrets the
+//! `keyring` crate stored in the Secret Service, and are otherwise configured like
+//! [`KeyringClient::from_env`] for the application `rust-keyring`.
+//!
+//! Some things differ from the `keyring` crate:
+//!
+//! * An [`Entry`] connects to the keyring when it's created, so [`Entry::new`] fails when the
+//!   keyring can't be used, and an entry can't be sent to or shared with other threads.
+//! * [`Error::Ambiguous`] holds how many credentials match the entry, not the credentials. Only
+//!   entries are ambiguous, a [`KeyringClient`] returns the secret of the first matching item.
+//! * Secrets have to be UTF-8, [`Entry::set_secret`] fails with [`Error::BadEncoding`] otherwise.
+
+use crate::config::Config;
+use crate::error::Error as KeyringError;
+use crate::options::{AttributeLayout, Options};
+use crate::search::{FoundItem, SearchScope};
+use crate::KeyringClient;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::{Mutex, PoisonError};
+
+// the application the `keyring` crate stores its items under
+const APPLICATION: &str = "rust-keyring";
+
+// the services and users of entries, which their clients borrow for the rest of the process
+static NAMES: Mutex<BTreeSet<&'static str>> = Mutex::new(BTreeSet::new());
+
+/// The errors of the `keyring` crate.
+#[derive(Debug)]
+pub enum Error {
+    /// The keyring failed in an unexpected way
+    PlatformFailure(Box<dyn std::error::Error + Send + Sync>),
+    /// The keyring is locked or unavailable, e.g. the user dismissed the unlock prompt
+    NoStorageAccess(Box<dyn std::error::Error + Send + Sync>),
+    /// There is no secret for the entry
+    NoEntry,
+    /// The secret isn't UTF-8, holds the secret
+    BadEncoding(Vec<u8>),
+    /// An attribute is longer than the keyring allows, holds its name and the limit
+    TooLong(String, u32),
+    /// An attribute is invalid, holds its name and why
+    Invalid(String, String),
+    /// Several items with different secrets match the entry, holds how many
+    ///
+    /// The `keyring` crate holds the matching credentials instead.
+    Ambiguous(usize),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::PlatformFailure(err) => write!(f, "platform secure storage failure: {}", err),
+            Error::NoStorageAccess(err) => {
+                write!(f, "couldn't access platform secure storage: {}", err)
+            }
+            Error::NoEntry => write!(f, "no matching entry found in secure storage"),
+            Error::BadEncoding(_) => write!(f, "the password isn't UTF-8"),
+            Error::TooLong(name, limit) => write!(
+                f,
+                "the {} attribute is longer than the platform limit of {} characters",
+                name, limit
+            ),
+            Error::Invalid(name, reason) => {
+                write!(f, "the {} attribute is invalid: {}", name, reason)
+            }
+            Error::Ambiguous(count) => write!(
+                f,
+                "the entry matches {} credentials, remove the ones you don't need",
+                count
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::PlatformFailure(err) | Error::NoStorageAccess(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(err: anyhow::Error) -> Self {
+        let cause = err
+            .chain()
+            .find_map(|err| err.downcast_ref::<KeyringError>());
+
+        match cause {
+            Some(
+                KeyringError::PromptDismissed
+                | KeyringError::PromptRequired
+                | KeyringError::ServiceUnknown
+                | KeyringError::Unsupported { .. },
+            ) => Error::NoStorageAccess(err.into()),
+            _ => Error::PlatformFailure(err.into()),
+        }
+    }
+}
+
+/// A credential identified by a service and a user, like the `keyring` crate's `Entry`.
+///
+/// The client for the keyring is created with the entry and used for all of its calls.
+pub struct Entry {
+    target: Option<String>,
+    service: &'static str,
+    user: &'static str,
+    options: Options,
+    client: KeyringClient<'static>,
+}
+
+impl fmt::Debug for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Entry")
+            .field("target", &self.target)
+            .field("service", &self.service)
+            .field("user", &self.user)
+            .field("backend", &self.client.backend_name())
+            .finish()
+    }
+}
+
+impl Entry {
+    /// Returns the entry for `user` at `service`
+    ///
+    /// Fails when the configured keyring can't be used, e.g. when there is no Secret Service.
+    pub fn new(service: &str, user: &str) -> Result<Entry> {
+        Self::build(None, service, user)
+    }
+
+    /// Returns the entry for `user` at `service`, stored with the `target` attribute
+    pub fn new_with_target(target: &str, service: &str, user: &str) -> Result<Entry> {
+        if target.is_empty() {
+            return Err(Error::Invalid(
+                "target".to_string(),
+                "cannot be empty".to_string(),
+            ));
+        }
+
+        Self::build(Some(target), service, user)
+    }
+
+    fn build(target: Option<&str>, service: &str, user: &str) -> Result<Entry> {
+        if service.is_empty() {
+            return Err(Error::Invalid(
+                "service".to_string(),
+                "cannot be empty".to_string(),
+            ));
+        }
+
+        let service = intern(service);
+        let user = intern(user);
+        let mut config = Config::load(APPLICATION)?;
+        config.layout.get_or_insert(AttributeLayout::KeyringCrate);
+        if let Some(target) = target {
+            config
+                .attributes
+                .insert("target".to_string(), target.to_string());
+        }
+
+        Ok(Entry {
+            target: target.map(String::from),
+            service,
+            user,
+            options: config.options()?,
+            client: config.client(user, service, APPLICATION)?,
+        })
+    }
+
+    pub fn set_password(&self, password: &str) -> Result<()> {
+        Ok(self.client.set_password(password.to_string())?)
+    }
+
+    /// Stores a secret, which has to be UTF-8 like every secret of this crate
+    pub fn set_secret(&self, secret: &[u8]) -> Result<()> {
+        let password =
+            std::str::from_utf8(secret).map_err(|_| Error::BadEncoding(secret.to_vec()))?;
+
+        self.set_password(password)
+    }
+
+    /// Returns the password, or [`Error::NoEntry`] when there is none
+    ///
+    /// Fails with [`Error::Ambiguous`] when several items with different secrets match the entry.
+    pub fn get_password(&self) -> Result<String> {
+        let password = self.client.get_password()?.ok_or(Error::NoEntry)?;
+        match self.ambiguous()? {
+            Some(count) => Err(Error::Ambiguous(count)),
+            None => Ok(password),
+        }
+    }
+
+    pub fn get_secret(&self) -> Result<Vec<u8>> {
+        Ok(self.get_password()?.into_bytes())
+    }
+
+    /// Removes the credential, or returns [`Error::NoEntry`] when there is none
+    pub fn delete_credential(&self) -> Result<()> {
+        if self.client.delete_password()? {
+            Ok(())
+        } else {
+            Err(Error::NoEntry)
+        }
+    }
+
+    /// The name of [`Entry::delete_credential`] in versions of the `keyring` crate before 3
+    pub fn delete_password(&self) -> Result<()> {
+        self.delete_credential()
+    }
+
+    /// How many items match the entry, when they don't all hold the same secret
+    ///
+    /// Backends that can't search hold a single item for an entry.
+    fn ambiguous(&self) -> Result<Option<usize>> {
+        let attributes = self
+            .options
+            .lookup_attributes(APPLICATION, self.service, self.user);
+        let items = match self.client.search(&attributes, SearchScope::Collection) {
+            Ok(items) => items,
+            Err(err) if is_unsupported(&err) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let items: Vec<FoundItem<'_>> = items
+            .into_iter()
+            .filter(|item| self.options.in_profile(&item.attributes))
+            .collect();
+        let secrets = items
+            .iter()
+            .map(FoundItem::secret)
+            .collect::<anyhow::Result<BTreeSet<String>>>()?;
+
+        Ok(Some(items.len()).filter(|_| secrets.len() > 1))
+    }
+}
+
+fn is_unsupported(err: &anyhow::Error) -> bool {
+    err.chain().any(|err| {
+        matches!(
+            err.downcast_ref::<KeyringError>(),
+            Some(KeyringError::Unsupported { .. })
+        )
+    })
+}
+
+/// Returns `value` with a `'static` lifetime
+///
+/// Each distinct value is allocated once and kept for the rest of the process, later calls
+/// return the same allocation.
+fn intern(value: &str) -> &'static str {
+    let mut names = NAMES.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(name) = names.get(value) {
+        return name;
+    }
+
+    let name: &'static str = Box::leak(value.into());
+    names.insert(name);
+
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Backend;
+    use crate::mock::MockKeyring;
+    use anyhow::{anyhow, Context};
+
+    fn entry(service: &'static str, user: &'static str) -> Entry {
+        Entry {
+            target: None,
+            service,
+            user,
+            options: Options::default().keyring_crate(),
+            client: KeyringClient::with_backend(MockKeyring::new(user, service, APPLICATION)),
+        }
+    }
+
+    #[test]
+    fn maps_errors() {
+        let err = Error::from(anyhow!(KeyringError::PromptDismissed).context("failed to unlock"));
+        assert!(matches!(err, Error::NoStorageAccess(_)), "{:?}", err);
+        assert!(std::error::Error::source(&err).is_some());
+
+        let err = Error::from(anyhow!(KeyringError::unsupported("pass", "profiles")));
+        assert!(matches!(err, Error::NoStorageAccess(_)), "{:?}", err);
+
+        let err = Error::from(
+            Err::<(), _>(anyhow!("broken pipe"))
+                .context("failed to read the item")
+                .unwrap_err(),
+        );
+        assert!(matches!(err, Error::PlatformFailure(_)), "{:?}", err);
+        assert!(
+            err.to_string().contains("failed to read the item"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn rejects_empty_names() {
+        let err = Entry::new("", "alice").unwrap_err();
+        assert!(matches!(err, Error::Invalid(ref name, _) if name == "service"));
+
+        let err = Entry::new_with_target("", "example.com", "alice").unwrap_err();
+        assert!(matches!(err, Error::Invalid(ref name, _) if name == "target"));
+    }
+
+    #[test]
+    fn interns_names() {
+        let name = intern("compat-interned");
+        assert!(std::ptr::eq(name, intern(&String::from("compat-interned"))));
+        assert_eq!(name, "compat-interned");
+    }
+
+    #[test]
+    fn round_trip() {
+        let entry = entry("compat-round-trip", "alice");
+        assert!(matches!(entry.get_password(), Err(Error::NoEntry)));
+
+        entry.set_password("hunter2").unwrap();
+        assert_eq!(entry.get_password().unwrap(), "hunter2");
+        assert_eq!(entry.get_secret().unwrap(), b"hunter2");
+        assert!(matches!(
+            entry.set_secret(&[0xff]),
+            Err(Error::BadEncoding(secret)) if secret == [0xff]
+        ));
+
+        entry.delete_credential().unwrap();
+        assert!(matches!(entry.delete_credential(), Err(Error::NoEntry)));
+    }
+
+    #[test]
+    fn different_secrets_are_ambiguous() {
+        let entry = entry("compat-ambiguous", "alice");
+        entry.set_password("hunter2").unwrap();
+        // the `keyring` crate finds the items of every application by service and user
+        let other = MockKeyring::new("alice", "compat-ambiguous", "other-app");
+        other.set_password("hunter2".to_string()).unwrap();
+        assert_eq!(entry.get_password().unwrap(), "hunter2");
+
+        other.set_password("different".to_string()).unwrap();
+        assert!(matches!(entry.get_password(), Err(Error::Ambiguous(2))));
+
+        // a client still reads its own item
+        assert_eq!(
+            entry.client.get_password().unwrap().as_deref(),
+            Some("hunter2")
+        );
+        other.delete_password().unwrap();
+        entry.delete_credential().unwrap();
+    }
+}
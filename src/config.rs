@@ -0,0 +1,443 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::chain::{ChainKeyring, Fallback};
+use crate::options::{AttributeLayout, Options, PromptPolicy};
+use crate::registry;
+use crate::schema::AttributeType;
+use crate::{KeyringClient, BACKEND_VARIABLE};
+use anyhow::{anyhow, Context, Result};
+use serde::de::{DeserializeOwned, IntoDeserializer};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const FILE_NAME: &str = "keyring.toml";
+
+// override the settings of the same name, see `Config::apply_env`
+const BACKENDS_VARIABLE: &str = "KEYRING_BACKENDS";
+const FALLBACK_VARIABLE: &str = "KEYRING_FALLBACK";
+const LABEL_VARIABLE: &str = "KEYRING_LABEL";
+const COLLECTION_VARIABLE: &str = "KEYRING_COLLECTION";
+const ATTRIBUTES_VARIABLE: &str = "KEYRING_ATTRIBUTES";
+const CALL_TIMEOUT_VARIABLE: &str = "KEYRING_CALL_TIMEOUT";
+const PROMPT_TIMEOUT_VARIABLE: &str = "KEYRING_PROMPT_TIMEOUT";
+const PROMPT_VARIABLE: &str = "KEYRING_PROMPT";
+const PROFILE_VARIABLE: &str = "KEYRING_PROFILE";
+const LAYOUT_VARIABLE: &str = "KEYRING_LAYOUT";
+
+/// Settings from an application's `keyring.toml`.
+///
+/// The file is `<config dir>/<application>/keyring.toml`, e.g.
+/// `$XDG_CONFIG_HOME/<application>/keyring.toml` on Linux. Every setting is optional, and the
+/// ones left out keep the defaults of [`Options`].
+///
+/// ```toml
+/// # the backends to try in order, or a single one with `backend = "file"`
+/// backends = ["secret-service", "file"]
+/// fallback = "per-operation"
+/// label = "{application}: {username} at {service}"
+/// collection = "login"
+/// profile = "staging"
+/// layout = "python-keyring"
+/// call_timeout = 10
+/// prompt_timeout = 120
+/// prompt = "deny"
+///
+/// [attributes]
+/// team = "payments"
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The registered backend to use, see [`register_backend`](crate::register_backend)
+    pub backend: Option<String>,
+    /// Registered backends to chain in order, instead of a single `backend`
+    #[serde(default)]
+    pub backends: Vec<String>,
+    /// When the chain of `backends` moves on to the next one, per operation by default
+    pub fallback: Option<Fallback>,
+    /// See [`Options::label`]
+    pub label: Option<String>,
+    /// See [`Options::collection`]
+    pub collection: Option<String>,
+    /// See [`Options::attributes`]
+    #[serde(default)]
+    pub attributes: BTreeMap<String, String>,
+    /// See [`Options::profile`]
+    pub profile: Option<String>,
+    /// See [`Options::layout`], `python-keyring` and `keyring-crate` also apply
+    /// [`Options::python_keyring`] and [`Options::keyring_crate`]
+    pub layout: Option<AttributeLayout>,
+    /// How long to wait for the keyring to answer a call in seconds, `0` waits forever
+    pub call_timeout: Option<f64>,
+    /// How long to wait for the user to answer a prompt in seconds, `0` waits forever
+    pub prompt_timeout: Option<f64>,
+    /// See [`Options::prompt`]
+    pub prompt: Option<PromptPolicy>,
+}
+
+impl Config {
+    /// Reads the application's configuration and applies the environment overrides
+    ///
+    /// The configuration is empty when there is no file, see [`Config::apply_env`] for the
+    /// overrides.
+    pub fn load(application: &str) -> Result<Self> {
+        let mut config = Self::read(&Self::path(application)?)?;
+        config.apply_env()?;
+
+        Ok(config)
+    }
+
+    /// Reads a configuration file, which is empty when it doesn't exist
+    pub fn read(path: &Path) -> Result<Self> {
+        let config: Self = match fs::read_to_string(path) {
+            Ok(text) => {
+                toml::from_str(&text).with_context(|| format!("failed to parse {:?}", path))?
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err).context(format!("failed to read {:?}", path)),
+        };
+        if config.backend.is_some() && !config.backends.is_empty() {
+            return Err(anyhow!(
+                "{:?} sets both backend and backends, pick one",
+                path
+            ));
+        }
+
+        Ok(config)
+    }
+
+    /// The location of the application's configuration file
+    pub fn path(application: &str) -> Result<PathBuf> {
+        let config_dir = dirs::config_dir().ok_or_else(|| anyhow!("no config directory"))?;
+
+        Ok(config_dir.join(application).join(FILE_NAME))
+    }
+
+    /// Overrides settings with the environment variables that are set
+    ///
+    /// `KEYRING_BACKEND` and `KEYRING_BACKENDS`, a comma separated list, replace both `backend`
+    /// and `backends`. `KEYRING_FALLBACK`, `KEYRING_LABEL`, `KEYRING_COLLECTION`,
+    /// `KEYRING_PROFILE`, `KEYRING_LAYOUT`, `KEYRING_CALL_TIMEOUT`, `KEYRING_PROMPT_TIMEOUT` and
+    /// `KEYRING_PROMPT` replace the setting of the same name. `KEYRING_ATTRIBUTES`, like
+    /// `team=payments,env=prod`, adds attributes.
+    pub fn apply_env(&mut self) -> Result<()> {
+        if let Some(backend) = variable(BACKEND_VARIABLE) {
+            self.backend = Some(backend);
+            self.backends.clear();
+        } else if let Some(backends) = variable(BACKENDS_VARIABLE) {
+            self.backend = None;
+            self.backends = backends
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(String::from)
+                .collect();
+        }
+        if let Some(fallback) = variable(FALLBACK_VARIABLE) {
+            self.fallback = Some(parse(FALLBACK_VARIABLE, &fallback)?);
+        }
+        if let Some(label) = variable(LABEL_VARIABLE) {
+            self.label = Some(label);
+        }
+        if let Some(collection) = variable(COLLECTION_VARIABLE) {
+            self.collection = Some(collection);
+        }
+        if let Some(profile) = variable(PROFILE_VARIABLE) {
+            self.profile = Some(profile);
+        }
+        if let Some(layout) = variable(LAYOUT_VARIABLE) {
+            self.layout = Some(parse(LAYOUT_VARIABLE, &layout)?);
+        }
+        if let Some(attributes) = variable(ATTRIBUTES_VARIABLE) {
+            for attribute in attributes.split(',').filter(|pair| !pair.trim().is_empty()) {
+                let (key, value) = attribute.split_once('=').ok_or_else(|| {
+                    anyhow!("{} must look like key=value,key=value", ATTRIBUTES_VARIABLE)
+                })?;
+                self.attributes
+                    .insert(key.trim().to_string(), value.trim().to_string());
+            }
+        }
+        if let Some(timeout) = variable(CALL_TIMEOUT_VARIABLE) {
+            self.call_timeout = Some(seconds(CALL_TIMEOUT_VARIABLE, &timeout)?);
+        }
+        if let Some(timeout) = variable(PROMPT_TIMEOUT_VARIABLE) {
+            self.prompt_timeout = Some(seconds(PROMPT_TIMEOUT_VARIABLE, &timeout)?);
+        }
+        if let Some(prompt) = variable(PROMPT_VARIABLE) {
+            self.prompt = Some(parse(PROMPT_VARIABLE, &prompt)?);
+        }
+
+        Ok(())
+    }
+
+    /// The client settings, the defaults overridden by the configured ones
+    pub fn options(&self) -> Result<Options> {
+        let mut options = match self.layout {
+            Some(AttributeLayout::PythonKeyring) => Options::default().python_keyring(),
+            Some(AttributeLayout::KeyringCrate) => Options::default().keyring_crate(),
+            Some(AttributeLayout::Keyring) | None => Options::default(),
+        };
+        if let Some(label) = &self.label {
+            options.label = label.clone();
+        }
+        if let Some(collection) = &self.collection {
+            options.collection = collection.clone();
+        }
+        for (name, value) in &self.attributes {
+            options.schema = options.schema.attribute(name, AttributeType::String);
+            options.attributes.insert(name.clone(), value.clone());
+        }
+        options.profile = self.profile.clone();
+        if let Some(timeout) = self.call_timeout {
+            options.call_timeout = timeout_from(timeout).context("invalid call_timeout")?;
+        }
+        if let Some(timeout) = self.prompt_timeout {
+            options.prompt_timeout = timeout_from(timeout).context("invalid prompt_timeout")?;
+        }
+        if let Some(prompt) = self.prompt {
+            options.prompt = prompt;
+        }
+
+        Ok(options)
+    }
+
+    /// Returns a client configured by these settings
+    ///
+    /// The backend is the one passed to [`force_backend`](crate::force_backend), a chain of
+    /// `backends`, the `backend` or else the OS keyring. When `KEYRING_ENV_OVERRIDE` is set,
+    /// secrets are read from environment variables first, see [`EnvKeyring`](crate::EnvKeyring).
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username to store secrets under
+    /// * `service` - A unique identifier within your application
+    /// * `application` - The name of your application
+    pub fn client<'a>(
+        &self,
+        username: &'a str,
+        service: &'a str,
+        application: &'a str,
+    ) -> Result<KeyringClient<'a>> {
+        let options = self.options()?;
+        if self.backends.is_empty() || registry::forced_backend().is_some() {
+            return KeyringClient::select(
+                self.backend.clone(),
+                username,
+                service,
+                application,
+                options,
+            );
+        }
+
+        let mut chain = ChainKeyring::builder(self.fallback.unwrap_or(Fallback::PerOperation));
+        for name in &self.backends {
+            chain = chain.backend(KeyringClient::with_backend_name(
+                name,
+                username,
+                service,
+                application,
+                options.clone(),
+            ));
+        }
+        let client = chain.build().map(KeyringClient::with_backend);
+
+        KeyringClient::overlay(client, username, service, options.profile.as_deref())
+    }
+}
+
+/// The value of an environment variable, unless it's unset or empty
+fn variable(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// Parses a setting's value from the environment the way it's read from the file
+fn parse<T: DeserializeOwned>(name: &str, value: &str) -> Result<T> {
+    T::deserialize(value.into_deserializer())
+        .map_err(|err: serde::de::value::Error| anyhow!("invalid {}: {}", name, err))
+}
+
+fn seconds(name: &str, value: &str) -> Result<f64> {
+    value
+        .parse()
+        .with_context(|| format!("{} must be a number of seconds", name))
+}
+
+/// A timeout of `seconds`, where `0` means waiting forever
+fn timeout_from(seconds: f64) -> Result<Option<Duration>> {
+    if seconds == 0.0 {
+        return Ok(None);
+    }
+    if !seconds.is_finite() || seconds < 0.0 {
+        return Err(anyhow!(
+            "a timeout is a positive number of seconds or 0 to wait forever, not {}",
+            seconds
+        ));
+    }
+
+    Ok(Some(Duration::try_from_secs_f64(seconds)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // the tests changing the environment can't run at the same time
+    static ENV: Mutex<()> = Mutex::new(());
+
+    fn read(text: &str) -> Result<Config> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(FILE_NAME);
+        fs::write(&path, text).unwrap();
+
+        Config::read(&path)
+    }
+
+    #[test]
+    fn reads_settings() {
+        let config = read(
+            r#"
+            backends = ["secret-service", "file"]
+            fallback = "first-available"
+            label = "{service}"
+            call_timeout = 2.5
+            prompt = "deny"
+
+            [attributes]
+            team = "payments"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.backend, None);
+        assert_eq!(config.backends, ["secret-service", "file"]);
+        assert_eq!(config.fallback, Some(Fallback::FirstAvailable));
+        assert_eq!(config.label.as_deref(), Some("{service}"));
+        assert_eq!(config.call_timeout, Some(2.5));
+        assert_eq!(config.prompt, Some(PromptPolicy::Deny));
+        assert_eq!(config.attributes["team"], "payments");
+    }
+
+    #[test]
+    fn missing_files_are_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::read(&dir.path().join(FILE_NAME)).unwrap();
+
+        assert_eq!(config.backend, None);
+        assert!(config.backends.is_empty());
+        assert!(config.attributes.is_empty());
+    }
+
+    #[test]
+    fn rejects_backend_and_backends() {
+        let err = read("backend = \"file\"\nbackends = [\"secret-service\"]").unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with("sets both backend and backends, pick one"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_settings() {
+        let err = read("lable = \"typo\"").unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("unknown field `lable`"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let _env = ENV.lock().unwrap();
+        let mut config =
+            read("label = \"from the file\"\n[attributes]\nteam = \"payments\"").unwrap();
+
+        std::env::set_var(LABEL_VARIABLE, "from the environment");
+        std::env::set_var(ATTRIBUTES_VARIABLE, "env=prod, team = billing,");
+        let result = config.apply_env();
+        std::env::remove_var(LABEL_VARIABLE);
+        std::env::remove_var(ATTRIBUTES_VARIABLE);
+        result.unwrap();
+
+        assert_eq!(config.label.as_deref(), Some("from the environment"));
+        assert_eq!(config.attributes["team"], "billing");
+        assert_eq!(config.attributes["env"], "prod");
+    }
+
+    #[test]
+    fn rejects_malformed_attributes() {
+        let _env = ENV.lock().unwrap();
+        let mut config = Config::default();
+
+        std::env::set_var(ATTRIBUTES_VARIABLE, "team=payments,prod");
+        let result = config.apply_env();
+        std::env::remove_var(ATTRIBUTES_VARIABLE);
+
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "KEYRING_ATTRIBUTES must look like key=value,key=value"
+        );
+    }
+
+    #[test]
+    fn builds_options() {
+        let config = read(
+            r#"
+            layout = "keyring-crate"
+            collection = "login"
+            profile = "staging"
+            call_timeout = 0
+            prompt_timeout = 90
+
+            [attributes]
+            team = "payments"
+            "#,
+        )
+        .unwrap();
+        let options = config.options().unwrap();
+        let crate_defaults = Options::default().keyring_crate();
+
+        assert_eq!(options.layout, AttributeLayout::KeyringCrate);
+        assert_eq!(options.label, crate_defaults.label);
+        assert_eq!(options.collection, "login");
+        assert_eq!(options.profile.as_deref(), Some("staging"));
+        assert_eq!(options.call_timeout, None);
+        assert_eq!(options.prompt_timeout, Some(Duration::from_secs(90)));
+        assert_eq!(options.attributes["team"], "payments");
+    }
+
+    #[test]
+    fn converts_timeouts() {
+        assert_eq!(timeout_from(0.0).unwrap(), None);
+        assert_eq!(
+            timeout_from(1.5).unwrap(),
+            Some(Duration::from_millis(1500))
+        );
+
+        for seconds in [-1.0, f64::NAN, f64::INFINITY] {
+            let err = timeout_from(seconds).unwrap_err();
+            assert!(
+                err.to_string()
+                    .starts_with("a timeout is a positive number of seconds"),
+                "{}",
+                err
+            );
+        }
+        let err = read("call_timeout = -5").unwrap().options().unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "invalid call_timeout: a timeout is a positive number of seconds or 0 to wait forever, not -5"
+        );
+    }
+}
@@ -0,0 +1,422 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::backend::{Backend, Entry};
+use crate::error::Error;
+use crate::purge::{PurgeOptions, PurgedItem};
+use crate::search::{FoundItem, SearchScope};
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+#[cfg(target_os = "linux")]
+use crate::secret_service::watch::Watcher;
+
+// set to `1` or `true` to make `KeyringClient::new` read secrets from environment variables
+const ENABLE_VARIABLE: &str = "KEYRING_ENV_OVERRIDE";
+// set to `1` or `true` to let the overlay write secrets to the process environment
+const WRITABLE_VARIABLE: &str = "KEYRING_ENV_WRITABLE";
+// explicit variable names, as `service/username=VARIABLE` separated by commas
+const MAP_VARIABLE: &str = "KEYRING_ENV_MAP";
+
+// variable names to what writable overlays set them to, `None` when they were deleted
+type Overrides = BTreeMap<String, Option<String>>;
+
+// read before the environment, which writable overlays never change
+static OVERRIDES: RwLock<Overrides> = RwLock::new(BTreeMap::new());
+
+/// What the overlay holds for an entry.
+enum Value {
+    Set(String),
+    /// A writable overlay deleted the secret, which hides the overlaid backend's
+    Deleted,
+    /// The variable isn't set, so the overlaid backend answers
+    Unset,
+}
+
+/// Reads secrets from environment variables, e.g. ones injected by CI, in front of another
+/// backend.
+///
+/// An entry's variable is `KEYRING_<SERVICE>_<USERNAME>`, upper-cased with everything but letters
+/// and digits replaced by `_`, unless it's mapped explicitly. The rule can give entries the same
+/// variable, `a-b`/`c` and `a`/`b-c` are both `KEYRING_A_B_C`, map such entries explicitly. When
+/// the variable isn't set, the overlaid backend answers, if there is one.
+///
+/// In a profile, see [`Options::profile`](crate::Options::profile), the variable is
+/// `KEYRING_<PROFILE>_<SERVICE>_<USERNAME>` and explicit variables come from
+/// `KEYRING_ENV_MAP_<PROFILE>`, so every profile reads its own variables.
+///
+/// The overlay is read-only by default, writes and deletes go to the overlaid backend. A writable
+/// overlay keeps what it writes and deletes in memory, in front of the variables, and never
+/// changes the process environment, which isn't safe while other threads read it. A secret it
+/// deleted stays deleted, even when the overlaid backend still holds one.
+///
+/// [`KeyringClient::new`](crate::KeyringClient::new) puts it in front of the OS keyring when
+/// `KEYRING_ENV_OVERRIDE` is `1` or `true`. `KEYRING_ENV_WRITABLE` makes it writable and
+/// `KEYRING_ENV_MAP` maps entries to variables, e.g. `api/deploy=DEPLOY_TOKEN,db/admin=DB_PASS`.
+pub struct EnvKeyring<'a> {
+    username: String,
+    service: String,
+    variable: String,
+    writable: bool,
+    backend: Option<Box<dyn Backend + 'a>>,
+}
+
+impl<'a> EnvKeyring<'a> {
+    /// Returns a read-only backend reading the entry's environment variable
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username the secret is stored under
+    /// * `service` - A unique identifier within your application
+    pub fn new(username: &str, service: &str) -> Self {
+        Self {
+            username: username.to_string(),
+            service: service.to_string(),
+            variable: Self::variable_name(service, username),
+            writable: false,
+            backend: None,
+        }
+    }
+
+    /// Returns the overlay configured by the `KEYRING_ENV_*` variables, or `None` when it's not
+    /// enabled
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username the secret is stored under
+    /// * `service` - A unique identifier within your application
+    /// * `profile` - The client's profile, see [`EnvKeyring::with_profile`]
+    pub fn from_env(username: &str, service: &str, profile: Option<&str>) -> Result<Option<Self>> {
+        if !flag(ENABLE_VARIABLE)? {
+            return Ok(None);
+        }
+
+        let mut keyring = Self::new(username, service).writable(flag(WRITABLE_VARIABLE)?);
+        let map_variable = match profile {
+            Some(profile) => {
+                keyring = keyring.with_profile(profile);
+                normalize(&format!("{}_{}", MAP_VARIABLE, profile))
+            }
+            None => MAP_VARIABLE.to_string(),
+        };
+        if let Some(map) = env::var_os(&map_variable) {
+            let map = map
+                .into_string()
+                .map_err(|_| anyhow!("${} is not valid unicode", map_variable))?;
+            if let Some(variable) = mapped_variable(&map, service, username)? {
+                keyring = keyring.with_variable(variable);
+            }
+        }
+
+        Ok(Some(keyring))
+    }
+
+    /// Returns the variable named by the naming rule for an entry, e.g. `KEYRING_API_DEPLOY`
+    pub fn variable_name(service: &str, username: &str) -> String {
+        normalize(&format!("KEYRING_{}_{}", service, username))
+    }
+
+    /// Reads the variable the naming rule gives the entry in `profile`, e.g.
+    /// `KEYRING_STAGING_API_DEPLOY`
+    pub fn with_profile(mut self, profile: &str) -> Self {
+        self.variable = normalize(&format!(
+            "KEYRING_{}_{}_{}",
+            profile, self.service, self.username
+        ));
+        self
+    }
+
+    /// Reads the secret from `variable` instead of the one named by the naming rule
+    pub fn with_variable(mut self, variable: &str) -> Self {
+        self.variable = variable.to_string();
+        self
+    }
+
+    /// Lets writes and deletes override the variable for every overlay in the current process
+    pub fn writable(mut self, writable: bool) -> Self {
+        self.writable = writable;
+        self
+    }
+
+    /// Falls back to `backend` when the variable isn't set
+    ///
+    /// A backend that couldn't be created, like the Secret Service on a CI runner, is left out.
+    pub fn overlay<B: Backend + 'a>(mut self, backend: Result<B>) -> Self {
+        if let Ok(backend) = backend {
+            self.backend = Some(Box::new(backend));
+        }
+
+        self
+    }
+
+    /// The name of the variable holding the secret
+    pub fn variable(&self) -> &str {
+        &self.variable
+    }
+
+    fn value(&self) -> Result<Value> {
+        match overrides()?.get(&self.variable) {
+            Some(Some(value)) => return Ok(Value::Set(value.clone())),
+            Some(None) => return Ok(Value::Deleted),
+            None => {}
+        }
+
+        match env::var(&self.variable) {
+            Ok(value) => Ok(Value::Set(value)),
+            Err(env::VarError::NotPresent) => Ok(Value::Unset),
+            Err(env::VarError::NotUnicode(_)) => {
+                Err(anyhow!("${} is not valid unicode", self.variable))
+            }
+        }
+    }
+
+    fn backend(&self, operation: &'static str) -> Result<&dyn Backend> {
+        match &self.backend {
+            Some(backend) => Ok(backend.as_ref()),
+            None => Err(Error::unsupported("env", operation).into()),
+        }
+    }
+}
+
+impl Backend for EnvKeyring<'_> {
+    fn name(&self) -> &'static str {
+        match (&self.backend, self.value()) {
+            (Some(backend), Ok(Value::Unset)) => backend.name(),
+            _ => "env",
+        }
+    }
+
+    fn set_password(&self, password: String) -> Result<()> {
+        if self.writable {
+            overrides_mut()?.insert(self.variable.clone(), Some(password));
+            return Ok(());
+        }
+
+        self.backend("storing secrets")?.set_password(password)
+    }
+
+    fn get_password(&self) -> Result<Option<String>> {
+        match (self.value()?, &self.backend) {
+            (Value::Set(value), _) => {
+                event!(debug, variable = %self.variable, "read the secret from the environment");
+                Ok(Some(value))
+            }
+            (Value::Unset, Some(backend)) => backend.get_password(),
+            (Value::Deleted, _) | (Value::Unset, None) => Ok(None),
+        }
+    }
+
+    fn delete_password(&self) -> Result<bool> {
+        if self.writable {
+            let existed = self.get_password()?.is_some();
+            overrides_mut()?.insert(self.variable.clone(), None);
+            return Ok(existed);
+        }
+
+        self.backend("deleting secrets")?.delete_password()
+    }
+
+    fn list(&self) -> Result<Vec<Entry>> {
+        self.backend("listing entries")?.list()
+    }
+
+    fn profiles(&self) -> Result<Vec<String>> {
+        self.backend("profiles")?.profiles()
+    }
+
+    fn search(
+        &self,
+        attributes: &HashMap<&str, &str>,
+        scope: SearchScope,
+    ) -> Result<Vec<FoundItem<'_>>> {
+        self.backend("searching")?.search(attributes, scope)
+    }
+
+    fn purge(&self, options: &PurgeOptions) -> Result<Vec<PurgedItem>> {
+        self.backend("purging")?.purge(options)
+    }
+
+    fn copy_to(&self, collection: &str) -> Result<bool> {
+        self.backend("copying secrets between collections")?
+            .copy_to(collection)
+    }
+
+    fn move_to(&self, collection: &str) -> Result<bool> {
+        self.backend("moving secrets between collections")?
+            .move_to(collection)
+    }
+
+    fn relabel(&self, label: &str) -> Result<bool> {
+        self.backend("relabeling secrets")?.relabel(label)
+    }
+
+    fn modified(&self) -> Result<Option<u64>> {
+        match (&self.backend, self.value()?) {
+            (Some(backend), Value::Unset) => backend.modified(),
+            _ => Ok(None),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn watch(&self) -> Result<Watcher> {
+        self.backend("watching for changes")?.watch()
+    }
+}
+
+fn overrides() -> Result<RwLockReadGuard<'static, Overrides>> {
+    OVERRIDES
+        .read()
+        .map_err(|_| anyhow!("a thread panicked while writing to the environment overlay"))
+}
+
+fn overrides_mut() -> Result<RwLockWriteGuard<'static, Overrides>> {
+    OVERRIDES
+        .write()
+        .map_err(|_| anyhow!("a thread panicked while writing to the environment overlay"))
+}
+
+/// Upper-cases `name` and replaces everything but letters and digits by `_`
+fn normalize(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn flag(variable: &str) -> Result<bool> {
+    match env::var(variable) {
+        Ok(value) => match value.as_str() {
+            "1" | "true" => Ok(true),
+            "" | "0" | "false" => Ok(false),
+            _ => Err(anyhow!("${} must be 1, true, 0 or false", variable)),
+        },
+        Err(env::VarError::NotPresent) => Ok(false),
+        Err(env::VarError::NotUnicode(_)) => Err(anyhow!("${} is not valid unicode", variable)),
+    }
+}
+
+/// Returns the variable `map` assigns to an entry
+fn mapped_variable<'m>(map: &'m str, service: &str, username: &str) -> Result<Option<&'m str>> {
+    for mapping in map.split(',').map(str::trim).filter(|m| !m.is_empty()) {
+        let (entry, variable) = mapping
+            .rsplit_once('=')
+            .ok_or_else(|| anyhow!("${} has no `=` in {:?}", MAP_VARIABLE, mapping))?;
+        let (mapped_service, mapped_username) = entry
+            .split_once('/')
+            .ok_or_else(|| anyhow!("${} has no `/` in {:?}", MAP_VARIABLE, mapping))?;
+
+        if mapped_service == service && mapped_username == username {
+            return Ok(Some(variable));
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockKeyring;
+
+    #[test]
+    fn naming_rule() {
+        assert_eq!(
+            EnvKeyring::variable_name("api", "deploy"),
+            "KEYRING_API_DEPLOY"
+        );
+        assert_eq!(
+            EnvKeyring::variable_name("db.example.com", "admin@corp"),
+            "KEYRING_DB_EXAMPLE_COM_ADMIN_CORP"
+        );
+        // the documented collision
+        assert_eq!(
+            EnvKeyring::variable_name("a-b", "c"),
+            EnvKeyring::variable_name("a", "b-c")
+        );
+    }
+
+    #[test]
+    fn profiles_have_their_own_variables() {
+        let keyring = EnvKeyring::new("deploy", "api");
+        assert_eq!(keyring.variable(), "KEYRING_API_DEPLOY");
+        let keyring = keyring.with_profile("staging");
+        assert_eq!(keyring.variable(), "KEYRING_STAGING_API_DEPLOY");
+    }
+
+    #[test]
+    fn map_parsing() {
+        let map = " api/deploy=DEPLOY_TOKEN, ,db/admin=DB_PASS,url/a=b=C";
+        assert_eq!(
+            mapped_variable(map, "api", "deploy").unwrap(),
+            Some("DEPLOY_TOKEN")
+        );
+        assert_eq!(
+            mapped_variable(map, "db", "admin").unwrap(),
+            Some("DB_PASS")
+        );
+        // the variable is after the last `=`
+        assert_eq!(mapped_variable(map, "url", "a=b").unwrap(), Some("C"));
+        assert_eq!(mapped_variable(map, "db", "other").unwrap(), None);
+
+        assert!(mapped_variable("api/deploy", "api", "deploy").is_err());
+        assert!(mapped_variable("deploy=TOKEN", "api", "deploy").is_err());
+    }
+
+    #[test]
+    fn writes_stay_out_of_the_environment() {
+        let keyring = EnvKeyring::new("writer", "env-tests").writable(true);
+        keyring.set_password("secret".to_string()).unwrap();
+
+        assert_eq!(keyring.get_password().unwrap().as_deref(), Some("secret"));
+        assert!(env::var_os(keyring.variable()).is_none());
+        assert!(keyring.delete_password().unwrap());
+        assert_eq!(keyring.get_password().unwrap(), None);
+        assert!(!keyring.delete_password().unwrap());
+    }
+
+    #[test]
+    fn deletes_hide_the_backends_secret() {
+        let backend = MockKeyring::new("deleter", "env-tests", "app");
+        backend
+            .set_password("from the backend".to_string())
+            .unwrap();
+        let keyring = EnvKeyring::new("deleter", "env-tests")
+            .writable(true)
+            .overlay(Ok(MockKeyring::new("deleter", "env-tests", "app")));
+        assert_eq!(
+            keyring.get_password().unwrap().as_deref(),
+            Some("from the backend")
+        );
+
+        keyring.set_password("secret".to_string()).unwrap();
+        assert_eq!(keyring.get_password().unwrap().as_deref(), Some("secret"));
+        assert!(keyring.delete_password().unwrap());
+        assert_eq!(keyring.get_password().unwrap(), None);
+        assert_eq!(keyring.name(), "env");
+        assert_eq!(keyring.modified().unwrap(), None);
+        // the backend keeps its secret, the overlay only hides it
+        assert_eq!(
+            backend.get_password().unwrap().as_deref(),
+            Some("from the backend")
+        );
+        backend.delete_password().unwrap();
+    }
+
+    #[test]
+    fn read_only_by_default() {
+        let keyring = EnvKeyring::new("reader", "env-tests");
+
+        assert_eq!(keyring.get_password().unwrap(), None);
+        assert!(keyring.set_password("secret".to_string()).is_err());
+    }
+}
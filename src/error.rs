@@ -0,0 +1,230 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::purge::PurgedItem;
+use std::fmt;
+use std::time::Duration;
+
+/// Errors the keyring client reports in a form callers can match on.
+///
+/// They're returned inside [`anyhow::Error`], use `downcast_ref::<Error>()` to get at them.
+#[derive(Debug)]
+pub enum Error {
+    /// The keyring daemon no longer knows our session
+    NoSession,
+    /// Nothing owns the keyring's bus name, e.g. the daemon exited
+    ServiceUnknown,
+    /// The connection to the bus was lost
+    Disconnected,
+    /// The session was lost and reconnecting didn't help
+    ///
+    /// Holds the error that made the client reconnect.
+    Reconnect(Box<Error>),
+    /// The keyring didn't answer within the configured timeout
+    Timeout(Duration),
+    /// The user dismissed the keyring's prompt
+    PromptDismissed,
+    /// The keyring wanted to prompt the user, which [`PromptPolicy::Deny`](crate::PromptPolicy)
+    /// doesn't allow
+    PromptRequired,
+    /// The backend can't do what was asked of it
+    Unsupported {
+        backend: &'static str,
+        operation: &'static str,
+    },
+    /// A chain's purge failed after some of its backends were purged
+    ///
+    /// Holds the items removed before the failure and the error that stopped the purge.
+    PartialPurge {
+        purged: Vec<PurgedItem>,
+        cause: anyhow::Error,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoSession => write!(f, "the keyring session no longer exists"),
+            Error::ServiceUnknown => write!(f, "the keyring service is not running"),
+            Error::Disconnected => write!(f, "the connection to the keyring was lost"),
+            Error::Reconnect(cause) => write!(f, "failed to reconnect to the keyring: {}", cause),
+            Error::Timeout(timeout) => write!(f, "the keyring didn't answer within {:?}", timeout),
+            Error::PromptDismissed => write!(f, "the keyring prompt was dismissed"),
+            Error::PromptRequired => write!(f, "the keyring needs to prompt, which isn't allowed"),
+            Error::Unsupported { backend, operation } => {
+                write!(f, "the {} backend doesn't support {}", backend, operation)
+            }
+            Error::PartialPurge { purged, cause } => {
+                write!(f, "purged {} items, then failed: {:#}", purged.len(), cause)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    pub(crate) fn unsupported(backend: &'static str, operation: &'static str) -> Self {
+        Error::Unsupported { backend, operation }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Error {
+    /// Returns the error if `err` means the Secret Service session or connection is gone
+    pub(crate) fn session_lost(err: &anyhow::Error) -> Option<Error> {
+        err.chain().find_map(|err| {
+            if let Some(err) = err.downcast_ref::<zbus::Error>() {
+                Self::from_zbus(err)
+            } else if let Some(err) = err.downcast_ref::<zbus::fdo::Error>() {
+                Self::from_fdo(err)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Returns whether `err` is likely to go away when the call is repeated
+    ///
+    /// Timeouts aren't, a hung daemon would keep the caller waiting for every attempt.
+    pub(crate) fn transient(err: &anyhow::Error) -> bool {
+        if Self::timed_out(err) {
+            return false;
+        }
+
+        err.chain().any(|err| {
+            if let Some(zbus::Error::MethodError(name, _, _)) = err.downcast_ref::<zbus::Error>() {
+                Self::transient_name(name.as_str())
+            } else if let Some(err) = err.downcast_ref::<zbus::fdo::Error>() {
+                Self::transient_name(zbus::DBusError::name(err).as_str())
+            } else {
+                false
+            }
+        })
+    }
+
+    fn timed_out(err: &anyhow::Error) -> bool {
+        err.chain().any(|err| {
+            if let Some(Error::Timeout(_)) = err.downcast_ref::<Error>() {
+                true
+            } else if let Some(zbus::Error::MethodError(name, _, _)) =
+                err.downcast_ref::<zbus::Error>()
+            {
+                Self::timeout_name(name.as_str())
+            } else if let Some(err) = err.downcast_ref::<zbus::fdo::Error>() {
+                Self::timeout_name(zbus::DBusError::name(err).as_str())
+            } else {
+                false
+            }
+        })
+    }
+
+    /// The errors the bus answers with when the service didn't reply in time
+    fn timeout_name(name: &str) -> bool {
+        matches!(
+            name,
+            "org.freedesktop.DBus.Error.NoReply"
+                | "org.freedesktop.DBus.Error.Timeout"
+                | "org.freedesktop.DBus.Error.TimedOut"
+        )
+    }
+
+    /// The errors the bus answers with while it's busy or the service is restarting
+    fn transient_name(name: &str) -> bool {
+        matches!(
+            name,
+            "org.freedesktop.DBus.Error.LimitsExceeded"
+                | "org.freedesktop.DBus.Error.ServiceUnknown"
+                | "org.freedesktop.DBus.Error.NameHasNoOwner"
+        )
+    }
+
+    fn from_zbus(err: &zbus::Error) -> Option<Error> {
+        match err {
+            zbus::Error::MethodError(name, _, _) => Self::from_name(name.as_str()),
+            zbus::Error::FDO(err) => Self::from_fdo(err),
+            zbus::Error::InputOutput(_) => Some(Error::Disconnected),
+            _ => None,
+        }
+    }
+
+    fn from_fdo(err: &zbus::fdo::Error) -> Option<Error> {
+        match err {
+            zbus::fdo::Error::ZBus(err) => Self::from_zbus(err),
+            err => Self::from_name(zbus::DBusError::name(err).as_str()),
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Error> {
+        match name {
+            "org.freedesktop.Secret.Error.NoSession" => Some(Error::NoSession),
+            "org.freedesktop.DBus.Error.ServiceUnknown" => Some(Error::ServiceUnknown),
+            "org.freedesktop.DBus.Error.Disconnected" => Some(Error::Disconnected),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn timeouts_are_not_transient() {
+        let timeout = anyhow::Error::from(Error::Timeout(Duration::from_secs(1)));
+        assert!(!Error::transient(&timeout));
+        assert!(!Error::transient(&timeout.context("failed to get secret")));
+
+        let no_reply = anyhow::Error::from(zbus::fdo::Error::NoReply(String::new()));
+        assert!(!Error::transient(&no_reply));
+    }
+
+    #[test]
+    fn limits_exceeded_is_transient() {
+        let err = anyhow::Error::from(zbus::fdo::Error::LimitsExceeded(String::new()));
+        assert!(Error::transient(&err));
+    }
+
+    #[test]
+    fn restarting_services_are_transient() {
+        let errors = [
+            zbus::fdo::Error::ServiceUnknown(String::new()),
+            zbus::fdo::Error::NameHasNoOwner(String::new()),
+        ];
+        for err in errors {
+            let err = anyhow::Error::from(err).context("failed to search items");
+            assert!(Error::transient(&err), "{:#}", err);
+        }
+
+        // as a method error, like the proxies return them
+        let name =
+            zbus::names::OwnedErrorName::try_from("org.freedesktop.DBus.Error.NameHasNoOwner")
+                .unwrap();
+        let message = zbus::MessageBuilder::signal("/", "org.example", "Test")
+            .unwrap()
+            .build(&())
+            .unwrap();
+        let err = anyhow::Error::from(zbus::Error::MethodError(name, None, Arc::new(message)));
+        assert!(Error::transient(&err));
+    }
+
+    #[test]
+    fn other_errors_are_not_transient() {
+        let errors = [
+            zbus::fdo::Error::AccessDenied(String::new()),
+            zbus::fdo::Error::UnknownMethod(String::new()),
+            zbus::fdo::Error::TimedOut(String::new()),
+        ];
+        for err in errors {
+            let err = anyhow::Error::from(err);
+            assert!(!Error::transient(&err), "{:#}", err);
+        }
+        assert!(!Error::transient(&anyhow::anyhow!("not a D-Bus error")));
+    }
+}
@@ -0,0 +1,600 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::backend::{Backend, Entry};
+use anyhow::{anyhow, Context, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MAGIC: &[u8; 8] = b"KRSFILE\0";
+const VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const KEY_LEN: usize = 32;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 3 * 4 + SALT_LEN;
+
+// <https://cheatsheetseries.owasp.org/cheatsheets/Password_Storage_Cheat_Sheet.html#argon2id>
+const ARGON2_MEMORY_KIB: u32 = 19 * 1024;
+const ARGON2_ITERATIONS: u32 = 2;
+const ARGON2_PARALLELISM: u32 = 1;
+
+// the header is only authenticated after the key is derived, so a corrupted or hostile file
+// mustn't make the derivation allocate or run for too long
+const MAX_ARGON2_MEMORY_KIB: u32 = 1024 * 1024;
+const MAX_ARGON2_ITERATIONS: u32 = 64;
+const MAX_ARGON2_PARALLELISM: u32 = 16;
+
+/// Stores secrets in a file encrypted with a passphrase, for machines without a keyring daemon.
+///
+/// The key is derived from the passphrase with Argon2id and the entries are encrypted with
+/// XChaCha20-Poly1305. Writes go to a temporary file that replaces the keyring file, and a lock
+/// file next to it serializes access from concurrent processes.
+pub struct FileKeyring<'a> {
+    username: &'a str,
+    service: &'a str,
+    application: &'a str,
+    profile: Option<String>,
+    path: PathBuf,
+    passphrase: String,
+    key: RefCell<Option<([u8; SALT_LEN], [u8; KEY_LEN])>>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+struct Store {
+    entries: Vec<StoredEntry>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct StoredEntry {
+    application: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    profile: Option<String>,
+    service: String,
+    username: String,
+    password: String,
+    modified: u64,
+}
+
+struct Header {
+    memory: u32,
+    iterations: u32,
+    parallelism: u32,
+    salt: [u8; SALT_LEN],
+}
+
+impl<'a> FileKeyring<'a> {
+    /// Returns a file keyring stored in the user's data directory
+    ///
+    /// The file is `<data dir>/<application>/keyring.enc`, e.g.
+    /// `~/.local/share/<application>/keyring.enc` on Linux.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username to store secrets under
+    /// * `service` - A unique identifier within your application
+    /// * `application` - The name of your application
+    /// * `passphrase` - The passphrase the file is encrypted with
+    pub fn new(
+        username: &'a str,
+        service: &'a str,
+        application: &'a str,
+        passphrase: &str,
+    ) -> Result<Self> {
+        let path = Self::default_path(application)?;
+
+        Ok(Self::with_path(
+            username,
+            service,
+            application,
+            passphrase,
+            path,
+        ))
+    }
+
+    /// Returns a file keyring stored at `path`
+    pub fn with_path<P: Into<PathBuf>>(
+        username: &'a str,
+        service: &'a str,
+        application: &'a str,
+        passphrase: &str,
+        path: P,
+    ) -> Self {
+        Self {
+            username,
+            service,
+            application,
+            profile: None,
+            path: path.into(),
+            passphrase: passphrase.to_string(),
+            key: RefCell::new(None),
+        }
+    }
+
+    /// Keeps the secrets of `profile` apart from those of other profiles, see
+    /// [`Options::profile`](crate::Options::profile)
+    pub fn with_profile(mut self, profile: &str) -> Self {
+        self.profile = Some(profile.to_string());
+        self
+    }
+
+    /// The default location of an application's keyring file
+    pub fn default_path(application: &str) -> Result<PathBuf> {
+        let data_dir = dirs::data_dir().ok_or_else(|| anyhow!("no data directory"))?;
+
+        Ok(data_dir.join(application).join("keyring.enc"))
+    }
+
+    fn is_ours(&self, entry: &StoredEntry) -> bool {
+        self.in_profile(entry) && entry.service == self.service && entry.username == self.username
+    }
+
+    /// Whether the entry belongs to the application and the client's profile
+    fn in_profile(&self, entry: &StoredEntry) -> bool {
+        entry.application == self.application && entry.profile == self.profile
+    }
+
+    /// Reads the store under a shared lock
+    fn read(&self) -> Result<Store> {
+        let lock = self.lock_file()?;
+        lock.lock_shared()?;
+
+        let store = match fs::read(&self.path) {
+            Ok(data) => self.decrypt(&data)?.1,
+            Err(err) if err.kind() == ErrorKind::NotFound => Store::default(),
+            Err(err) => return Err(err).context(format!("failed to read {:?}", self.path)),
+        };
+
+        Ok(store)
+    }
+
+    /// Changes the store under an exclusive lock and writes it back
+    fn update<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Store) -> T,
+    {
+        let lock = self.lock_file()?;
+        lock.lock()?;
+
+        let (header, mut store) = match fs::read(&self.path) {
+            Ok(data) => self.decrypt(&data)?,
+            Err(err) if err.kind() == ErrorKind::NotFound => (Header::new(), Store::default()),
+            Err(err) => return Err(err).context(format!("failed to read {:?}", self.path)),
+        };
+
+        let result = f(&mut store);
+        let data = self.encrypt(&header, &store)?;
+        self.write_atomically(&data)?;
+
+        Ok(result)
+    }
+
+    fn decrypt(&self, data: &[u8]) -> Result<(Header, Store)> {
+        if data.len() < HEADER_LEN + NONCE_LEN {
+            return Err(anyhow!("{:?} is not a keyring file", self.path));
+        }
+        let (header_bytes, rest) = data.split_at(HEADER_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let header = Header::parse(header_bytes)
+            .with_context(|| format!("{:?} is not a keyring file", self.path))?;
+
+        let cipher = XChaCha20Poly1305::new(&self.key(&header)?.into());
+        let payload = Payload {
+            msg: ciphertext,
+            aad: header_bytes,
+        };
+        let plaintext = cipher
+            .decrypt(XNonce::from_slice(nonce), payload)
+            .map_err(|_| anyhow!("failed to decrypt {:?}, wrong passphrase?", self.path))?;
+
+        Ok((header, serde_json::from_slice(&plaintext)?))
+    }
+
+    fn encrypt(&self, header: &Header, store: &Store) -> Result<Vec<u8>> {
+        let header_bytes = header.to_bytes();
+        let plaintext = serde_json::to_vec(store)?;
+
+        let cipher = XChaCha20Poly1305::new(&self.key(header)?.into());
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: &plaintext,
+            aad: &header_bytes,
+        };
+        let ciphertext = cipher
+            .encrypt(&nonce, payload)
+            .map_err(|_| anyhow!("failed to encrypt the keyring"))?;
+
+        let mut data = header_bytes;
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&ciphertext);
+
+        Ok(data)
+    }
+
+    /// Derives the key for the header's salt, reusing it while the salt doesn't change
+    fn key(&self, header: &Header) -> Result<[u8; KEY_LEN]> {
+        if let Some((salt, key)) = self.key.borrow().as_ref() {
+            if salt == &header.salt {
+                return Ok(*key);
+            }
+        }
+
+        let params = Params::new(
+            header.memory,
+            header.iterations,
+            header.parallelism,
+            Some(KEY_LEN),
+        )
+        .map_err(|err| anyhow!("invalid key derivation parameters: {}", err))?;
+        let mut key = [0u8; KEY_LEN];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(self.passphrase.as_bytes(), &header.salt, &mut key)
+            .map_err(|err| anyhow!("failed to derive the keyring key: {}", err))?;
+        self.key.replace(Some((header.salt, key)));
+
+        Ok(key)
+    }
+
+    fn lock_file(&self) -> Result<File> {
+        lock_file(&self.path)
+    }
+
+    fn write_atomically(&self, data: &[u8]) -> Result<()> {
+        let dir = self.directory();
+        let mut name = std::ffi::OsString::from(".");
+        name.push(self.path.file_name().unwrap_or_default());
+        name.push(format!(".{}.tmp", std::process::id()));
+        let tmp_path = dir.join(name);
+
+        let mut tmp = private_options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        tmp.write_all(data)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("failed to replace {:?}", self.path))?;
+
+        // make the rename itself durable
+        #[cfg(unix)]
+        File::open(dir)?.sync_all()?;
+
+        Ok(())
+    }
+
+    fn directory(&self) -> &Path {
+        match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        }
+    }
+}
+
+impl Backend for FileKeyring<'_> {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn set_password(&self, password: String) -> Result<()> {
+        let modified = now();
+
+        self.update(|store| {
+            match store.entries.iter_mut().find(|entry| self.is_ours(entry)) {
+                Some(entry) => {
+                    entry.password = password;
+                    entry.modified = modified;
+                }
+                None => store.entries.push(StoredEntry {
+                    application: self.application.to_string(),
+                    profile: self.profile.clone(),
+                    service: self.service.to_string(),
+                    username: self.username.to_string(),
+                    password,
+                    modified,
+                }),
+            };
+        })
+    }
+
+    fn get_password(&self) -> Result<Option<String>> {
+        let store = self.read()?;
+
+        Ok(store
+            .entries
+            .into_iter()
+            .find(|entry| self.is_ours(entry))
+            .map(|entry| entry.password))
+    }
+
+    fn delete_password(&self) -> Result<bool> {
+        if self.get_password()?.is_none() {
+            return Ok(false);
+        }
+
+        self.update(|store| {
+            let count = store.entries.len();
+            store.entries.retain(|entry| !self.is_ours(entry));
+
+            store.entries.len() != count
+        })
+    }
+
+    fn list(&self) -> Result<Vec<Entry>> {
+        let store = self.read()?;
+
+        Ok(store
+            .entries
+            .into_iter()
+            .filter(|entry| self.in_profile(entry))
+            .map(|entry| Entry {
+                service: entry.service,
+                username: entry.username,
+            })
+            .collect())
+    }
+
+    fn profiles(&self) -> Result<Vec<String>> {
+        let store = self.read()?;
+
+        let profiles: BTreeSet<String> = store
+            .entries
+            .into_iter()
+            .filter(|entry| entry.application == self.application)
+            .filter_map(|entry| entry.profile)
+            .collect();
+
+        Ok(profiles.into_iter().collect())
+    }
+
+    fn modified(&self) -> Result<Option<u64>> {
+        let store = self.read()?;
+
+        Ok(store
+            .entries
+            .iter()
+            .find(|entry| self.is_ours(entry))
+            .map(|entry| entry.modified))
+    }
+}
+
+impl Header {
+    fn new() -> Self {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+
+        Self {
+            memory: ARGON2_MEMORY_KIB,
+            iterations: ARGON2_ITERATIONS,
+            parallelism: ARGON2_PARALLELISM,
+            salt,
+        }
+    }
+
+    fn parse(bytes: &[u8]) -> Result<Self> {
+        let (magic, rest) = bytes.split_at(MAGIC.len());
+        if magic != MAGIC {
+            return Err(anyhow!("bad magic"));
+        }
+        if rest[0] != VERSION {
+            return Err(anyhow!("unsupported version {}", rest[0]));
+        }
+        let u32_at = |offset: usize| -> Result<u32> {
+            Ok(u32::from_le_bytes(rest[offset..offset + 4].try_into()?))
+        };
+        let header = Self {
+            memory: u32_at(1)?,
+            iterations: u32_at(5)?,
+            parallelism: u32_at(9)?,
+            salt: rest[13..13 + SALT_LEN].try_into()?,
+        };
+
+        if header.memory > MAX_ARGON2_MEMORY_KIB {
+            return Err(anyhow!(
+                "argon2 memory of {} KiB is too high",
+                header.memory
+            ));
+        }
+        if header.iterations > MAX_ARGON2_ITERATIONS {
+            return Err(anyhow!(
+                "{} argon2 iterations are too many",
+                header.iterations
+            ));
+        }
+        if header.parallelism > MAX_ARGON2_PARALLELISM {
+            return Err(anyhow!(
+                "argon2 parallelism of {} is too high",
+                header.parallelism
+            ));
+        }
+
+        Ok(header)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN);
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&self.memory.to_le_bytes());
+        bytes.extend_from_slice(&self.iterations.to_le_bytes());
+        bytes.extend_from_slice(&self.parallelism.to_le_bytes());
+        bytes.extend_from_slice(&self.salt);
+
+        bytes
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// Opens the lock file next to `path`, `<file name>.lock`, which serializes access to `path`
+pub(crate) fn lock_file(path: &Path) -> Result<File> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    create_private_dir(dir)?;
+
+    let mut name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{:?} is not a file", path))?
+        .to_os_string();
+    name.push(".lock");
+
+    let lock = private_options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dir.join(name))?;
+
+    Ok(lock)
+}
+
+/// Creates `dir` and its parents, readable only by the user on unix
+pub(crate) fn create_private_dir(dir: &Path) -> Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+
+    builder
+        .create(dir)
+        .with_context(|| format!("failed to create {:?}", dir))
+}
+
+/// Options for files only the user can read on unix
+pub(crate) fn private_options() -> OpenOptions {
+    #[allow(unused_mut)]
+    let mut options = OpenOptions::new();
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyring<'a>(dir: &Path, username: &'a str, passphrase: &str) -> FileKeyring<'a> {
+        FileKeyring::with_path(
+            username,
+            "service",
+            "application",
+            passphrase,
+            dir.join("keyring.enc"),
+        )
+    }
+
+    /// Rewrites a `u32` of the header, at an offset after the version byte
+    fn patch_header(path: &Path, offset: usize, value: u32) {
+        let mut data = fs::read(path).unwrap();
+        let start = MAGIC.len() + offset;
+        data[start..start + 4].copy_from_slice(&value.to_le_bytes());
+        fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = keyring(dir.path(), "alice", "passphrase");
+        let bob = keyring(dir.path(), "bob", "passphrase");
+
+        assert_eq!(alice.get_password().unwrap(), None);
+        alice.set_password("secret".to_string()).unwrap();
+        bob.set_password("other".to_string()).unwrap();
+        alice.set_password("changed".to_string()).unwrap();
+
+        let reopened = keyring(dir.path(), "alice", "passphrase");
+        assert_eq!(reopened.get_password().unwrap().as_deref(), Some("changed"));
+        assert_eq!(reopened.list().unwrap().len(), 2);
+        assert!(reopened.delete_password().unwrap());
+        assert!(!reopened.delete_password().unwrap());
+        assert_eq!(bob.get_password().unwrap().as_deref(), Some("other"));
+    }
+
+    #[test]
+    fn profiles_are_kept_apart() {
+        let dir = tempfile::tempdir().unwrap();
+        let default = keyring(dir.path(), "alice", "passphrase");
+        let staging = keyring(dir.path(), "alice", "passphrase").with_profile("staging");
+        let prod = keyring(dir.path(), "alice", "passphrase").with_profile("prod");
+
+        default.set_password("default".to_string()).unwrap();
+        staging.set_password("staging".to_string()).unwrap();
+        assert_eq!(prod.get_password().unwrap(), None);
+        assert_eq!(staging.get_password().unwrap().as_deref(), Some("staging"));
+        assert_eq!(default.get_password().unwrap().as_deref(), Some("default"));
+        assert_eq!(default.list().unwrap().len(), 1);
+        assert_eq!(prod.list().unwrap().len(), 0);
+        assert_eq!(default.profiles().unwrap(), vec!["staging".to_string()]);
+
+        assert!(staging.delete_password().unwrap());
+        assert_eq!(default.get_password().unwrap().as_deref(), Some("default"));
+    }
+
+    #[test]
+    fn wrong_passphrase() {
+        let dir = tempfile::tempdir().unwrap();
+        keyring(dir.path(), "alice", "passphrase")
+            .set_password("secret".to_string())
+            .unwrap();
+
+        let err = keyring(dir.path(), "alice", "wrong")
+            .get_password()
+            .unwrap_err();
+        assert!(err.to_string().contains("wrong passphrase"), "{:#}", err);
+    }
+
+    #[test]
+    fn tampered_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = keyring(dir.path(), "alice", "passphrase");
+        alice.set_password("secret".to_string()).unwrap();
+
+        // one iteration less is a valid header, but not the one the store was encrypted with
+        patch_header(&alice.path, 5, ARGON2_ITERATIONS - 1);
+        let err = keyring(dir.path(), "alice", "passphrase")
+            .get_password()
+            .unwrap_err();
+        assert!(err.to_string().contains("failed to decrypt"), "{:#}", err);
+    }
+
+    #[test]
+    fn parameter_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let alice = keyring(dir.path(), "alice", "passphrase");
+        alice.set_password("secret".to_string()).unwrap();
+
+        for (offset, value) in [(1, u32::MAX), (5, 1000), (9, 255)] {
+            patch_header(&alice.path, offset, value);
+            let err = keyring(dir.path(), "alice", "passphrase")
+                .get_password()
+                .unwrap_err();
+            assert!(
+                format!("{:#}", err).contains("too"),
+                "offset {}: {:#}",
+                offset,
+                err
+            );
+            patch_header(&alice.path, offset, 1);
+        }
+    }
+}
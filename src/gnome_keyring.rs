@@ -0,0 +1,528 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::backend::{Backend, Entry};
+use crate::error::Error;
+use aes::cipher::block_padding::NoPadding;
+use aes::cipher::{BlockDecryptMut, KeyIvInit};
+use aes::Aes128;
+use anyhow::{anyhow, Context, Result};
+use openssl::hash::{hash, MessageDigest};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+type Aes128CbcDec = cbc::Decryptor<Aes128>;
+
+const HEADER: &[u8; 16] = b"GnomeKeyring\n\r\0\n";
+const SALT_LEN: usize = 8;
+const BLOCK_LEN: usize = 16;
+const HASH_LEN: usize = 16;
+
+// the attribute type of numbers, strings are 0
+const ATTRIBUTE_UINT32: u32 = 1;
+
+// gnome-keyring picks between 1000 and 4096 iterations, a corrupted or hostile file mustn't make
+// the key derivation run for too long
+const MAX_ITERATIONS: u32 = 100_000;
+
+/// Reads a gnome-keyring `.keyring` file without the daemon, e.g. from a backup or a mounted disk
+/// image.
+///
+/// Only the binary format of password protected keyrings is supported, it's decrypted with the
+/// keyring's password when the backend is created. Items have the attributes and labels the
+/// Secret Service would return for them, including `xdg:schema`, and the backend finds them the
+/// same way the Secret Service backend does. It is read-only.
+///
+/// <https://gitlab.gnome.org/GNOME/gnome-keyring/-/blob/master/pkcs11/secret-store/gkm-secret-binary.c>
+pub struct GnomeKeyringFile<'a> {
+    username: &'a str,
+    service: &'a str,
+    application: &'a str,
+    name: String,
+    items: Vec<GnomeKeyringItem>,
+}
+
+/// An item of a gnome-keyring file.
+#[derive(Clone, Debug)]
+pub struct GnomeKeyringItem {
+    pub label: String,
+    pub attributes: HashMap<String, String>,
+    pub secret: Vec<u8>,
+    /// Seconds since the unix epoch
+    pub created: u64,
+    /// Seconds since the unix epoch
+    pub modified: u64,
+}
+
+/// Reads the big endian values of the file format.
+struct Reader<'d> {
+    data: &'d [u8],
+    offset: usize,
+}
+
+impl<'a> GnomeKeyringFile<'a> {
+    /// Reads and decrypts the keyring file at `path`
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username the secret is stored under
+    /// * `service` - A unique identifier within your application
+    /// * `application` - The name of your application
+    /// * `path` - The keyring file, see [`GnomeKeyringFile::default_path`]
+    /// * `password` - The keyring's password, usually the login password for `login.keyring`
+    pub fn open<P: AsRef<Path>>(
+        username: &'a str,
+        service: &'a str,
+        application: &'a str,
+        path: P,
+        password: &str,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let data = fs::read(path).with_context(|| format!("failed to read {:?}", path))?;
+        let (name, items) = Self::parse(&data, password)
+            .with_context(|| format!("failed to read the keyring {:?}", path))?;
+        event!(
+            debug,
+            keyring = name.as_str(),
+            items = items.len(),
+            "read keyring file"
+        );
+
+        Ok(Self {
+            username,
+            service,
+            application,
+            name,
+            items,
+        })
+    }
+
+    /// The location of the user's keyring called `name`, e.g. `login`
+    pub fn default_path(name: &str) -> Result<PathBuf> {
+        let data_dir = dirs::data_dir().ok_or_else(|| anyhow!("no data directory"))?;
+
+        Ok(data_dir.join("keyrings").join(format!("{}.keyring", name)))
+    }
+
+    /// The keyring's display name
+    pub fn keyring_name(&self) -> &str {
+        &self.name
+    }
+
+    /// Every item in the keyring
+    pub fn items(&self) -> &[GnomeKeyringItem] {
+        &self.items
+    }
+
+    /// Returns the items that have all of `attributes`
+    pub fn search(&self, attributes: &HashMap<&str, &str>) -> Vec<&GnomeKeyringItem> {
+        self.items
+            .iter()
+            .filter(|item| {
+                attributes.iter().all(|(name, value)| {
+                    item.attributes.get(*name).map(String::as_str) == Some(*value)
+                })
+            })
+            .collect()
+    }
+
+    /// The attributes the Secret Service backend stores secrets with
+    fn attributes(&self) -> HashMap<&str, &str> {
+        let mut attributes: HashMap<&str, &str> = HashMap::new();
+        attributes.insert("application", self.application);
+        attributes.insert("service", self.service);
+
+        attributes
+    }
+
+    /// Returns the entry's item, preferring the one labelled with the username when several
+    /// match
+    fn item(&self) -> Option<&GnomeKeyringItem> {
+        let items = self.search(&self.attributes());
+        let label = format!("Password for {}: {}", self.service, self.username);
+
+        items
+            .iter()
+            .find(|item| item.label == label)
+            .or_else(|| items.first())
+            .copied()
+    }
+
+    fn parse(data: &[u8], password: &str) -> Result<(String, Vec<GnomeKeyringItem>)> {
+        if !data.starts_with(HEADER) {
+            if data.starts_with(b"[keyring]") {
+                return Err(anyhow!("unencrypted keyrings aren't supported"));
+            }
+            return Err(anyhow!("not a keyring file"));
+        }
+        let mut reader = Reader::new(&data[HEADER.len()..]);
+
+        // major and minor version, cipher (AES) and hash (MD5)
+        if reader.bytes(4)? != [0, 0, 0, 0] {
+            return Err(anyhow!("unsupported keyring version"));
+        }
+        let name = reader.string()?.unwrap_or_default();
+        reader.time()?; // created
+        reader.time()?; // modified
+        reader.u32()?; // flags
+        reader.u32()?; // lock timeout
+        let iterations = reader.u32()?;
+        if iterations > MAX_ITERATIONS {
+            return Err(anyhow!("{} hash iterations are too many", iterations));
+        }
+        let salt = reader.bytes(SALT_LEN)?.to_vec();
+        for _ in 0..4 {
+            reader.u32()?; // reserved
+        }
+
+        // the unencrypted part only has item types and hashed attributes
+        let count = reader.u32()?;
+        let mut types = Vec::new();
+        for _ in 0..count {
+            reader.u32()?; // id
+            types.push(reader.u32()?);
+            reader.attributes()?;
+        }
+
+        let size = reader.u32()? as usize;
+        if !size.is_multiple_of(BLOCK_LEN) || size < HASH_LEN {
+            return Err(anyhow!("corrupted encrypted data"));
+        }
+        let mut encrypted = reader.bytes(size)?.to_vec();
+        let decrypted = Self::decrypt(&mut encrypted, password, &salt, iterations)?;
+
+        let mut reader = Reader::new(decrypted);
+        let mut items = Vec::new();
+        for item_type in types {
+            items.push(reader.item(item_type)?);
+        }
+
+        Ok((name, items))
+    }
+
+    fn decrypt<'d>(
+        data: &'d mut [u8],
+        password: &str,
+        salt: &[u8],
+        iterations: u32,
+    ) -> Result<&'d [u8]> {
+        // `egg_symkey_generate_simple` with SHA-256, one digest holds both the key and the IV
+        let mut digest = hash(
+            MessageDigest::sha256(),
+            &[password.as_bytes(), salt].concat(),
+        )?;
+        for _ in 1..iterations {
+            digest = hash(MessageDigest::sha256(), &digest)?;
+        }
+        let (key, iv) = digest.split_at(BLOCK_LEN);
+
+        let decrypted = Aes128CbcDec::new_from_slices(key, iv)?
+            .decrypt_padded_mut::<NoPadding>(data)
+            .map_err(|_| anyhow!("corrupted encrypted data"))?;
+        let (checksum, decrypted) = decrypted.split_at(HASH_LEN);
+        if *hash(MessageDigest::md5(), decrypted)? != *checksum {
+            return Err(anyhow!("wrong keyring password"));
+        }
+
+        Ok(decrypted)
+    }
+}
+
+impl Backend for GnomeKeyringFile<'_> {
+    fn name(&self) -> &'static str {
+        "gnome-keyring-file"
+    }
+
+    fn set_password(&self, _password: String) -> Result<()> {
+        Err(Error::unsupported(self.name(), "storing secrets").into())
+    }
+
+    fn get_password(&self) -> Result<Option<String>> {
+        match self.item() {
+            Some(item) => Ok(Some(String::from_utf8(item.secret.clone())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Lists the application's entries, the username comes from the label the Secret Service
+    /// backend gives items
+    fn list(&self) -> Result<Vec<Entry>> {
+        let mut attributes = HashMap::new();
+        attributes.insert("application", self.application);
+
+        Ok(self
+            .search(&attributes)
+            .into_iter()
+            .filter_map(|item| {
+                let service = item.attributes.get("service")?;
+                let username = item
+                    .label
+                    .strip_prefix(&format!("Password for {}: ", service))?;
+
+                Some(Entry {
+                    service: service.clone(),
+                    username: username.to_string(),
+                })
+            })
+            .collect())
+    }
+
+    fn modified(&self) -> Result<Option<u64>> {
+        Ok(self.item().map(|item| item.modified))
+    }
+}
+
+impl<'d> Reader<'d> {
+    fn new(data: &'d [u8]) -> Self {
+        Self { data, offset: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'d [u8]> {
+        let end = self
+            .offset
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| anyhow!("truncated keyring file"))?;
+        let bytes = &self.data[self.offset..end];
+        self.offset = end;
+
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into()?))
+    }
+
+    /// A time is stored as its high and low 32 bits
+    fn time(&mut self) -> Result<u64> {
+        let high = self.u32()? as u64;
+        let low = self.u32()? as u64;
+
+        Ok(high << 32 | low)
+    }
+
+    /// Length-prefixed bytes, a length of `u32::MAX` is NULL
+    fn byte_array(&mut self) -> Result<Option<&'d [u8]>> {
+        match self.u32()? {
+            u32::MAX => Ok(None),
+            len => Ok(Some(self.bytes(len as usize)?)),
+        }
+    }
+
+    fn string(&mut self) -> Result<Option<String>> {
+        match self.byte_array()? {
+            Some(bytes) => Ok(Some(String::from_utf8(bytes.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Numbers are returned as the decimal strings the Secret Service shows
+    fn attributes(&mut self) -> Result<HashMap<String, String>> {
+        let count = self.u32()?;
+        let mut attributes = HashMap::new();
+
+        for _ in 0..count {
+            let name = self.string()?.unwrap_or_default();
+            let value = match self.u32()? {
+                ATTRIBUTE_UINT32 => self.u32()?.to_string(),
+                _ => self.string()?.unwrap_or_default(),
+            };
+            attributes.insert(name, value);
+        }
+
+        Ok(attributes)
+    }
+
+    fn item(&mut self, item_type: u32) -> Result<GnomeKeyringItem> {
+        let label = self.string()?.unwrap_or_default();
+        let secret = self.byte_array()?.unwrap_or_default().to_vec();
+        let created = self.time()?;
+        let modified = self.time()?;
+        self.string()?; // reserved
+        for _ in 0..4 {
+            self.u32()?; // reserved
+        }
+        let mut attributes = self.attributes()?;
+
+        // access control lists, which the Secret Service doesn't use
+        for _ in 0..self.u32()? {
+            self.u32()?; // allowed types
+            self.string()?; // display name
+            self.string()?; // path
+            self.string()?; // reserved
+            self.u32()?; // reserved
+        }
+
+        if let Some(schema) = schema(item_type) {
+            attributes
+                .entry(String::from("xdg:schema"))
+                .or_insert_with(|| schema.to_string());
+        }
+
+        Ok(GnomeKeyringItem {
+            label,
+            attributes,
+            secret,
+            created,
+            modified,
+        })
+    }
+}
+
+/// The schema gnome-keyring gives items of a type
+fn schema(item_type: u32) -> Option<&'static str> {
+    match item_type {
+        0 => Some("org.freedesktop.Secret.Generic"),
+        1 => Some("org.gnome.keyring.NetworkPassword"),
+        2 => Some("org.gnome.keyring.Note"),
+        3 => Some("org.gnome.keyring.ChainedKeyring"),
+        4 => Some("org.gnome.keyring.EncryptionKey"),
+        0x100 => Some("org.gnome.keyring.PkStorage"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // written by tests/fixtures/gnome_keyring.py
+    const FIXTURE: &[u8] = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/login.keyring"
+    ));
+    const PASSWORD: &str = "fixture password";
+
+    fn fixture() -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("login.keyring");
+        fs::write(&path, FIXTURE).unwrap();
+
+        (dir, path)
+    }
+
+    fn open<'a>(username: &'a str, service: &'a str) -> GnomeKeyringFile<'a> {
+        let (_dir, path) = fixture();
+        GnomeKeyringFile::open(username, service, "app", path, PASSWORD).unwrap()
+    }
+
+    #[test]
+    fn decrypts_items() {
+        let keyring = open("alice", "example.com");
+        assert_eq!(keyring.keyring_name(), "Login");
+        assert_eq!(keyring.items().len(), 5);
+
+        let item = &keyring.items()[0];
+        assert_eq!(item.label, "Password for example.com: alice");
+        assert_eq!(item.secret, b"hunter2");
+        assert_eq!((item.created, item.modified), (1700000000, 1700000100));
+
+        assert_eq!(keyring.get_password().unwrap().as_deref(), Some("hunter2"));
+        assert_eq!(keyring.modified().unwrap(), Some(1700000100));
+        assert_eq!(open("nobody", "missing").get_password().unwrap(), None);
+    }
+
+    #[test]
+    fn wrong_password() {
+        let (_dir, path) = fixture();
+        let err = GnomeKeyringFile::open("alice", "example.com", "app", path, "wrong")
+            .err()
+            .unwrap();
+        assert!(
+            format!("{:#}", err).contains("wrong keyring password"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn schemas_come_from_item_types() {
+        let keyring = open("alice", "example.com");
+        let schema = |label: &str| {
+            let item = keyring.items().iter().find(|item| item.label == label);
+            item.unwrap().attributes["xdg:schema"].clone()
+        };
+
+        assert_eq!(
+            schema("Password for example.com: alice"),
+            "org.freedesktop.Secret.Generic"
+        );
+        assert_eq!(
+            schema("bob@example.org"),
+            "org.gnome.keyring.NetworkPassword"
+        );
+        // a stored schema wins over the item type's
+        assert_eq!(
+            schema("Password for other.example.com: carol"),
+            "io.github.tmuntaner.Keyring"
+        );
+
+        let mut attributes = HashMap::new();
+        attributes.insert("server", "example.org");
+        attributes.insert("port", "443");
+        let items = keyring.search(&attributes);
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].secret, "pässword".as_bytes());
+    }
+
+    #[test]
+    fn lists_usernames_from_labels() {
+        let mut entries: Vec<(String, String)> = open("alice", "example.com")
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.service, entry.username))
+            .collect();
+        entries.sort();
+
+        // other applications' items and labels without a username are left out
+        assert_eq!(
+            entries,
+            vec![
+                ("example.com".to_string(), "alice".to_string()),
+                ("other.example.com".to_string(), "carol".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_files() {
+        for len in 0..FIXTURE.len() {
+            let result = GnomeKeyringFile::parse(&FIXTURE[..len], PASSWORD);
+            assert!(result.is_err(), "{} bytes were parsed", len);
+        }
+
+        let err = GnomeKeyringFile::parse(b"[keyring]\ndisplay-name=Login\n", PASSWORD)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("unencrypted"), "{:#}", err);
+    }
+
+    #[test]
+    fn iteration_limit() {
+        // the fixture's 1000 iterations, followed by its salt
+        let header = [0, 0, 0x03, 0xe8, 0, 1, 2, 3, 4, 5, 6, 7];
+        let offset = FIXTURE
+            .windows(header.len())
+            .position(|window| window == header)
+            .unwrap();
+
+        let mut data = FIXTURE.to_vec();
+        data[offset..offset + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        let err = GnomeKeyringFile::parse(&data, PASSWORD).err().unwrap();
+        assert!(err.to_string().contains("too many"), "{:#}", err);
+
+        data[offset..offset + 4].copy_from_slice(&MAX_ITERATIONS.to_be_bytes());
+        let err = GnomeKeyringFile::parse(&data, PASSWORD).err().unwrap();
+        assert!(
+            err.to_string().contains("wrong keyring password"),
+            "{:#}",
+            err
+        );
+    }
+}
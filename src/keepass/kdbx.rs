@@ -0,0 +1,818 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use super::xml::Element;
+use aes::cipher::block_padding::Pkcs7;
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecryptMut, BlockEncrypt, BlockEncryptMut, KeyInit, KeyIvInit};
+use aes::Aes256;
+use anyhow::{anyhow, Context, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64ct::{Base64, Encoding};
+use chacha20::cipher::StreamCipher;
+use chacha20::ChaCha20;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256, Sha512};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::{Read, Write};
+
+type Aes256CbcEnc = cbc::Encryptor<Aes256>;
+type Aes256CbcDec = cbc::Decryptor<Aes256>;
+type HmacSha256 = Hmac<Sha256>;
+
+const SIGNATURE_1: u32 = 0x9AA2_D903;
+const SIGNATURE_2: u32 = 0xB54B_FB67;
+const MAJOR_VERSION: u32 = 4;
+
+// outer header fields
+const END_OF_HEADER: u8 = 0;
+const CIPHER_ID: u8 = 2;
+const COMPRESSION_FLAGS: u8 = 3;
+const MASTER_SEED: u8 = 4;
+const ENCRYPTION_IV: u8 = 7;
+const KDF_PARAMETERS: u8 = 11;
+const PUBLIC_CUSTOM_DATA: u8 = 12;
+
+// inner header fields
+const INNER_RANDOM_STREAM_ID: u8 = 1;
+const INNER_RANDOM_STREAM_KEY: u8 = 2;
+const INNER_BINARY: u8 = 3;
+
+const STREAM_CHACHA20: u32 = 3;
+
+const CIPHER_AES256: [u8; 16] = [
+    0x31, 0xc1, 0xf2, 0xe6, 0xbf, 0x71, 0x43, 0x50, 0xbe, 0x58, 0x05, 0x21, 0x6a, 0xfc, 0x5a, 0xff,
+];
+const CIPHER_CHACHA20: [u8; 16] = [
+    0xd6, 0x03, 0x8a, 0x2b, 0x8b, 0x6f, 0x4c, 0xb5, 0xa5, 0x24, 0x33, 0x9a, 0x31, 0xdb, 0xb5, 0x9a,
+];
+// KDBX 3.1 and 4 have different identifiers for the same AES-KDF
+const KDF_AES_KDBX3: [u8; 16] = [
+    0xc9, 0xd9, 0xf3, 0x9a, 0x62, 0x8a, 0x44, 0x60, 0xbf, 0x74, 0x0d, 0x08, 0xc1, 0x8a, 0x4f, 0xea,
+];
+const KDF_AES_KDBX4: [u8; 16] = [
+    0x7c, 0x02, 0xbb, 0x82, 0x79, 0xa7, 0x4a, 0xc0, 0x92, 0x7d, 0x11, 0x4a, 0x00, 0x64, 0x82, 0x38,
+];
+const KDF_ARGON2D: [u8; 16] = [
+    0xef, 0x63, 0x6d, 0xdf, 0x8c, 0x29, 0x44, 0x4b, 0x91, 0xf7, 0xa9, 0xa4, 0x03, 0xe3, 0x0a, 0x0c,
+];
+const KDF_ARGON2ID: [u8; 16] = [
+    0x9e, 0x29, 0x8b, 0x19, 0x56, 0xdb, 0x47, 0x73, 0xb2, 0x3d, 0xfc, 0x3e, 0xc6, 0xf0, 0xa1, 0xe6,
+];
+
+const KEY_LEN: usize = 32;
+const HASH_LEN: usize = 32;
+const BLOCK_SIZE: usize = 1024 * 1024;
+
+// a database from somewhere else mustn't make opening it allocate or run for too long
+const MAX_AES_ROUNDS: u64 = 1_000_000_000;
+const MAX_ARGON2_MEMORY: u64 = 1024 * 1024 * 1024;
+const MAX_ARGON2_ITERATIONS: u64 = 1000;
+const MAX_ARGON2_PARALLELISM: u32 = 64;
+
+/// A KDBX 4 database.
+///
+/// The outer header and the inner header's attachments are kept as they were read, and the XML
+/// document with the values of protected strings decrypted. The seeds, IV and inner stream key are
+/// new every time the database is written, but the key derivation parameters stay the same, so the
+/// transformed key can be reused.
+///
+/// <https://keepass.info/help/kb/kdbx_4.html>
+pub struct Database {
+    version: u32,
+    cipher: Cipher,
+    compressed: bool,
+    kdf: KdfParameters,
+    public_custom_data: Option<Vec<u8>>,
+    binaries: Vec<Vec<u8>>,
+    transformed_key: [u8; KEY_LEN],
+    pub root: Element,
+}
+
+/// The key derivation function's settings, from the header's variant dictionary.
+pub struct KdfParameters {
+    bytes: Vec<u8>,
+    kdf: Kdf,
+}
+
+enum Kdf {
+    Aes {
+        seed: Vec<u8>,
+        rounds: u64,
+    },
+    Argon2 {
+        algorithm: Algorithm,
+        version: Version,
+        salt: Vec<u8>,
+        memory: u64,
+        iterations: u64,
+        parallelism: u32,
+    },
+}
+
+#[derive(Clone, Copy)]
+enum Cipher {
+    Aes256,
+    ChaCha20,
+}
+
+/// Reads the little endian values of the file format.
+struct Reader<'d> {
+    data: &'d [u8],
+    offset: usize,
+}
+
+impl Database {
+    /// Decrypts a database
+    ///
+    /// `transform` returns the transformed key for the key derivation parameters, so callers can
+    /// reuse it.
+    pub fn parse<F>(data: &[u8], transform: F) -> Result<Self>
+    where
+        F: FnOnce(&KdfParameters) -> Result<[u8; KEY_LEN]>,
+    {
+        let mut reader = Reader::new(data);
+        if reader.u32()? != SIGNATURE_1 || reader.u32()? != SIGNATURE_2 {
+            return Err(anyhow!("not a KeePass database"));
+        }
+        let version = reader.u32()?;
+        if version >> 16 != MAJOR_VERSION {
+            return Err(anyhow!(
+                "unsupported KDBX version {}.{}, only KDBX 4 is supported",
+                version >> 16,
+                version & 0xffff
+            ));
+        }
+
+        let mut cipher = None;
+        let mut compressed = false;
+        let mut master_seed = None;
+        let mut iv = None;
+        let mut kdf = None;
+        let mut public_custom_data = None;
+        loop {
+            let id = reader.u8()?;
+            let size = reader.u32()? as usize;
+            let value = reader.bytes(size)?;
+            match id {
+                END_OF_HEADER => break,
+                CIPHER_ID => cipher = Some(Cipher::from_uuid(value)?),
+                COMPRESSION_FLAGS => {
+                    compressed = match Reader::new(value).u32()? {
+                        0 => false,
+                        1 => true,
+                        flags => return Err(anyhow!("unsupported compression {}", flags)),
+                    }
+                }
+                MASTER_SEED => master_seed = Some(value),
+                ENCRYPTION_IV => iv = Some(value),
+                KDF_PARAMETERS => kdf = Some(KdfParameters::parse(value)?),
+                PUBLIC_CUSTOM_DATA => public_custom_data = Some(value.to_vec()),
+                id => return Err(anyhow!("unknown header field {}", id)),
+            }
+        }
+        let cipher = cipher.ok_or_else(|| anyhow!("the header has no cipher"))?;
+        let master_seed = master_seed
+            .filter(|seed| seed.len() == KEY_LEN)
+            .ok_or_else(|| anyhow!("the header has no master seed"))?;
+        let iv = iv.ok_or_else(|| anyhow!("the header has no IV"))?;
+        let kdf = kdf.ok_or_else(|| anyhow!("the header has no key derivation parameters"))?;
+
+        let header = &data[..reader.offset];
+        if *Sha256::digest(header) != *reader.bytes(HASH_LEN)? {
+            return Err(anyhow!("corrupted header"));
+        }
+        let header_mac = reader.bytes(HASH_LEN)?;
+        let transformed_key = transform(&kdf)?;
+        let hmac_key = hmac_key(master_seed, &transformed_key);
+        block_mac(&hmac_key, u64::MAX)?
+            .chain_update(header)
+            .verify_slice(header_mac)
+            .map_err(|_| anyhow!("wrong password or key file"))?;
+
+        let mut payload = Vec::new();
+        for index in 0u64.. {
+            let mac = reader.bytes(HASH_LEN)?;
+            let size = reader.u32()?;
+            let block = reader.bytes(size as usize)?;
+            block_mac(&hmac_key, index)?
+                .chain_update(index.to_le_bytes())
+                .chain_update(size.to_le_bytes())
+                .chain_update(block)
+                .verify_slice(mac)
+                .map_err(|_| anyhow!("corrupted block {}", index))?;
+            if block.is_empty() {
+                break;
+            }
+            payload.extend_from_slice(block);
+        }
+
+        let key = encryption_key(master_seed, &transformed_key);
+        let mut payload = cipher.decrypt(&key, iv, payload)?;
+        if compressed {
+            let mut decompressed = Vec::new();
+            GzDecoder::new(payload.as_slice())
+                .read_to_end(&mut decompressed)
+                .context("failed to decompress the database")?;
+            payload = decompressed;
+        }
+
+        let mut reader = Reader::new(&payload);
+        let mut stream_id = None;
+        let mut stream_key = None;
+        let mut binaries = Vec::new();
+        loop {
+            let id = reader.u8()?;
+            let size = reader.u32()? as usize;
+            let value = reader.bytes(size)?;
+            match id {
+                END_OF_HEADER => break,
+                INNER_RANDOM_STREAM_ID => stream_id = Some(Reader::new(value).u32()?),
+                INNER_RANDOM_STREAM_KEY => stream_key = Some(value),
+                INNER_BINARY => binaries.push(value.to_vec()),
+                id => return Err(anyhow!("unknown inner header field {}", id)),
+            }
+        }
+        if stream_id != Some(STREAM_CHACHA20) {
+            return Err(anyhow!("only ChaCha20 protected values are supported"));
+        }
+        let stream_key = stream_key.ok_or_else(|| anyhow!("the inner header has no key"))?;
+
+        let mut root = Element::parse(&payload[reader.offset..])
+            .context("failed to parse the database's XML")?;
+        apply_inner_stream(&mut root, stream_key, false)?;
+
+        Ok(Self {
+            version,
+            cipher,
+            compressed,
+            kdf,
+            public_custom_data,
+            binaries,
+            transformed_key,
+            root,
+        })
+    }
+
+    /// Encrypts the database with new seeds
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut master_seed = [0u8; KEY_LEN];
+        OsRng.fill_bytes(&mut master_seed);
+        let mut iv = vec![0u8; self.cipher.iv_len()];
+        OsRng.fill_bytes(&mut iv);
+        let mut stream_key = [0u8; 64];
+        OsRng.fill_bytes(&mut stream_key);
+
+        let mut header = Vec::new();
+        header.extend_from_slice(&SIGNATURE_1.to_le_bytes());
+        header.extend_from_slice(&SIGNATURE_2.to_le_bytes());
+        header.extend_from_slice(&self.version.to_le_bytes());
+        push_field(&mut header, CIPHER_ID, &self.cipher.uuid());
+        push_field(
+            &mut header,
+            COMPRESSION_FLAGS,
+            &(self.compressed as u32).to_le_bytes(),
+        );
+        push_field(&mut header, MASTER_SEED, &master_seed);
+        push_field(&mut header, ENCRYPTION_IV, &iv);
+        push_field(&mut header, KDF_PARAMETERS, &self.kdf.bytes);
+        if let Some(data) = &self.public_custom_data {
+            push_field(&mut header, PUBLIC_CUSTOM_DATA, data);
+        }
+        push_field(&mut header, END_OF_HEADER, b"\r\n\r\n");
+
+        let mut payload = Vec::new();
+        push_field(
+            &mut payload,
+            INNER_RANDOM_STREAM_ID,
+            &STREAM_CHACHA20.to_le_bytes(),
+        );
+        push_field(&mut payload, INNER_RANDOM_STREAM_KEY, &stream_key);
+        for binary in &self.binaries {
+            push_field(&mut payload, INNER_BINARY, binary);
+        }
+        push_field(&mut payload, END_OF_HEADER, &[]);
+        let mut root = self.root.clone();
+        apply_inner_stream(&mut root, &stream_key, true)?;
+        payload.extend_from_slice(&root.to_bytes()?);
+
+        if self.compressed {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&payload)?;
+            payload = encoder.finish()?;
+        }
+        let key = encryption_key(&master_seed, &self.transformed_key);
+        let payload = self.cipher.encrypt(&key, &iv, payload)?;
+
+        let hmac_key = hmac_key(&master_seed, &self.transformed_key);
+        let mut data = header.clone();
+        data.extend_from_slice(&Sha256::digest(&header));
+        data.extend_from_slice(
+            &block_mac(&hmac_key, u64::MAX)?
+                .chain_update(&header)
+                .finalize()
+                .into_bytes(),
+        );
+        // the last block is empty
+        let blocks = payload.chunks(BLOCK_SIZE).chain(std::iter::once(&[][..]));
+        for (index, block) in (0u64..).zip(blocks) {
+            let size = (block.len() as u32).to_le_bytes();
+            let mac = block_mac(&hmac_key, index)?
+                .chain_update(index.to_le_bytes())
+                .chain_update(size)
+                .chain_update(block)
+                .finalize()
+                .into_bytes();
+            data.extend_from_slice(&mac);
+            data.extend_from_slice(&size);
+            data.extend_from_slice(block);
+        }
+
+        Ok(data)
+    }
+}
+
+impl KdfParameters {
+    /// The serialized parameters, which identify the transformed key
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Transforms the composite key of a password and key file
+    pub fn transform(&self, key: &[u8; KEY_LEN]) -> Result<[u8; KEY_LEN]> {
+        match &self.kdf {
+            Kdf::Aes { seed, rounds } => {
+                let cipher = Aes256::new_from_slice(seed)
+                    .map_err(|_| anyhow!("the AES-KDF seed must be 32 bytes"))?;
+                let mut blocks = [
+                    GenericArray::clone_from_slice(&key[..16]),
+                    GenericArray::clone_from_slice(&key[16..]),
+                ];
+                for _ in 0..*rounds {
+                    cipher.encrypt_blocks(&mut blocks);
+                }
+
+                Ok(Sha256::new()
+                    .chain_update(blocks[0])
+                    .chain_update(blocks[1])
+                    .finalize()
+                    .into())
+            }
+            Kdf::Argon2 {
+                algorithm,
+                version,
+                salt,
+                memory,
+                iterations,
+                parallelism,
+            } => {
+                let params = Params::new(
+                    (*memory / 1024).try_into()?,
+                    (*iterations).try_into()?,
+                    *parallelism,
+                    Some(KEY_LEN),
+                )
+                .map_err(|err| anyhow!("invalid Argon2 parameters: {}", err))?;
+                let mut transformed = [0u8; KEY_LEN];
+                Argon2::new(*algorithm, *version, params)
+                    .hash_password_into(key, salt, &mut transformed)
+                    .map_err(|err| anyhow!("failed to transform the key: {}", err))?;
+
+                Ok(transformed)
+            }
+        }
+    }
+
+    /// Parses a variant dictionary with a `$UUID` and the function's parameters
+    fn parse(bytes: &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(bytes);
+        if reader.u16()? >> 8 != 1 {
+            return Err(anyhow!("unsupported key derivation parameters"));
+        }
+        let mut values = HashMap::new();
+        loop {
+            let value_type = reader.u8()?;
+            if value_type == 0 {
+                break;
+            }
+            let size = reader.u32()? as usize;
+            let name = std::str::from_utf8(reader.bytes(size)?)?;
+            let size = reader.u32()? as usize;
+            values.insert(name, reader.bytes(size)?);
+        }
+        let value = |name: &str| {
+            values
+                .get(name)
+                .copied()
+                .ok_or_else(|| anyhow!("the key derivation parameter {} is missing", name))
+        };
+        let u32_value = |name: &str| Reader::new(value(name)?).u32();
+        let u64_value = |name: &str| Reader::new(value(name)?).u64();
+
+        let kdf = match value("$UUID")? {
+            uuid if uuid == KDF_AES_KDBX3 || uuid == KDF_AES_KDBX4 => Kdf::Aes {
+                seed: value("S")?.to_vec(),
+                rounds: u64_value("R")?,
+            },
+            uuid if uuid == KDF_ARGON2D || uuid == KDF_ARGON2ID => {
+                if values.contains_key("K") || values.contains_key("A") {
+                    return Err(anyhow!("Argon2 secret keys aren't supported"));
+                }
+
+                Kdf::Argon2 {
+                    algorithm: if uuid == KDF_ARGON2D {
+                        Algorithm::Argon2d
+                    } else {
+                        Algorithm::Argon2id
+                    },
+                    version: match u32_value("V")? {
+                        0x10 => Version::V0x10,
+                        0x13 => Version::V0x13,
+                        version => return Err(anyhow!("unknown Argon2 version {:#x}", version)),
+                    },
+                    salt: value("S")?.to_vec(),
+                    memory: u64_value("M")?,
+                    iterations: u64_value("I")?,
+                    parallelism: u32_value("P")?,
+                }
+            }
+            _ => return Err(anyhow!("unsupported key derivation function")),
+        };
+        match &kdf {
+            Kdf::Aes { rounds, .. } if *rounds > MAX_AES_ROUNDS => {
+                return Err(anyhow!("{} AES-KDF rounds are too many", rounds));
+            }
+            Kdf::Argon2 { memory, .. } if *memory > MAX_ARGON2_MEMORY => {
+                return Err(anyhow!("Argon2 memory of {} bytes is too high", memory));
+            }
+            Kdf::Argon2 { iterations, .. } if *iterations > MAX_ARGON2_ITERATIONS => {
+                return Err(anyhow!("{} Argon2 iterations are too many", iterations));
+            }
+            Kdf::Argon2 { parallelism, .. } if *parallelism > MAX_ARGON2_PARALLELISM => {
+                return Err(anyhow!("Argon2 parallelism of {} is too high", parallelism));
+            }
+            _ => {}
+        }
+
+        Ok(Self {
+            bytes: bytes.to_vec(),
+            kdf,
+        })
+    }
+}
+
+impl Cipher {
+    fn from_uuid(uuid: &[u8]) -> Result<Self> {
+        if uuid == CIPHER_AES256 {
+            Ok(Cipher::Aes256)
+        } else if uuid == CIPHER_CHACHA20 {
+            Ok(Cipher::ChaCha20)
+        } else {
+            Err(anyhow!("unsupported cipher, only AES-256 and ChaCha20 are"))
+        }
+    }
+
+    fn uuid(self) -> [u8; 16] {
+        match self {
+            Cipher::Aes256 => CIPHER_AES256,
+            Cipher::ChaCha20 => CIPHER_CHACHA20,
+        }
+    }
+
+    fn iv_len(self) -> usize {
+        match self {
+            Cipher::Aes256 => 16,
+            Cipher::ChaCha20 => 12,
+        }
+    }
+
+    fn encrypt(self, key: &[u8], iv: &[u8], mut data: Vec<u8>) -> Result<Vec<u8>> {
+        match self {
+            Cipher::Aes256 => {
+                Ok(Aes256CbcEnc::new_from_slices(key, iv)?.encrypt_padded_vec_mut::<Pkcs7>(&data))
+            }
+            Cipher::ChaCha20 => {
+                ChaCha20::new_from_slices(key, iv)?.apply_keystream(&mut data);
+                Ok(data)
+            }
+        }
+    }
+
+    fn decrypt(self, key: &[u8], iv: &[u8], mut data: Vec<u8>) -> Result<Vec<u8>> {
+        match self {
+            Cipher::Aes256 => Aes256CbcDec::new_from_slices(key, iv)?
+                .decrypt_padded_vec_mut::<Pkcs7>(&data)
+                .map_err(|_| anyhow!("failed to decrypt the database")),
+            Cipher::ChaCha20 => {
+                ChaCha20::new_from_slices(key, iv)?.apply_keystream(&mut data);
+                Ok(data)
+            }
+        }
+    }
+}
+
+impl<'d> Reader<'d> {
+    fn new(data: &'d [u8]) -> Self {
+        Self { data, offset: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'d [u8]> {
+        let end = self
+            .offset
+            .checked_add(len)
+            .filter(|end| *end <= self.data.len())
+            .ok_or_else(|| anyhow!("truncated database"))?;
+        let bytes = &self.data[self.offset..end];
+        self.offset = end;
+
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into()?))
+    }
+}
+
+/// Returns the composite key of a password and the contents of a key file
+pub fn composite_key(password: Option<&str>, key_file: Option<&[u8]>) -> Result<[u8; KEY_LEN]> {
+    let mut composite = Sha256::new();
+    if let Some(password) = password {
+        composite.update(Sha256::digest(password.as_bytes()));
+    }
+    if let Some(key_file) = key_file {
+        composite.update(key_file_key(key_file)?);
+    }
+
+    Ok(composite.finalize().into())
+}
+
+/// Returns the key in a key file, which is an XML file, 32 bytes or 64 hex digits, or the hash of
+/// any other file
+fn key_file_key(data: &[u8]) -> Result<[u8; KEY_LEN]> {
+    let text = std::str::from_utf8(data).unwrap_or_default();
+    if text
+        .trim_start_matches('\u{feff}')
+        .trim_start()
+        .starts_with('<')
+    {
+        if let Ok(root) = Element::parse(data) {
+            if root.name == "KeyFile" {
+                return xml_key_file_key(&root);
+            }
+        }
+    }
+    if data.len() == KEY_LEN {
+        return Ok(data.try_into()?);
+    }
+    if data.len() == 2 * KEY_LEN {
+        if let Some(key) = decode_hex(text) {
+            return Ok(key.as_slice().try_into()?);
+        }
+    }
+
+    Ok(Sha256::digest(data).into())
+}
+
+fn xml_key_file_key(root: &Element) -> Result<[u8; KEY_LEN]> {
+    let version = root
+        .child("Meta")
+        .and_then(|meta| meta.child_text("Version"))
+        .unwrap_or_default();
+    let data = root
+        .child("Key")
+        .and_then(|key| key.child("Data"))
+        .ok_or_else(|| anyhow!("the key file has no key"))?;
+
+    let key = if version.trim().starts_with("2.") {
+        let hex: String = data.text().split_whitespace().collect();
+        let key = decode_hex(&hex).ok_or_else(|| anyhow!("corrupted key file"))?;
+        if let Some(hash) = data.attribute("Hash") {
+            let expected = decode_hex(hash);
+            if expected.as_deref() != Some(&Sha256::digest(&key)[..4]) {
+                return Err(anyhow!("corrupted key file"));
+            }
+        }
+        key
+    } else {
+        Base64::decode_vec(data.text().trim()).map_err(|_| anyhow!("corrupted key file"))?
+    };
+
+    key.as_slice()
+        .try_into()
+        .map_err(|_| anyhow!("the key file's key must be 32 bytes"))
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Decrypts or encrypts the values of protected strings, which share one ChaCha20 stream in
+/// document order
+fn apply_inner_stream(root: &mut Element, key: &[u8], encrypt: bool) -> Result<()> {
+    let hash = Sha512::digest(key);
+    let mut stream = ChaCha20::new_from_slices(&hash[..32], &hash[32..44])?;
+
+    root.visit_mut(&mut |element| {
+        let protected = element
+            .attribute("Protected")
+            .is_some_and(|protected| protected.eq_ignore_ascii_case("true"));
+        if !protected {
+            return Ok(());
+        }
+
+        if encrypt {
+            let mut value = element.text().into_bytes();
+            stream.apply_keystream(&mut value);
+            element.set_text(&Base64::encode_string(&value));
+        } else {
+            let mut value = Base64::decode_vec(element.text().trim())
+                .map_err(|_| anyhow!("corrupted protected value"))?;
+            stream.apply_keystream(&mut value);
+            element.set_text(&String::from_utf8(value)?);
+        }
+
+        Ok(())
+    })
+}
+
+fn push_field(data: &mut Vec<u8>, id: u8, value: &[u8]) {
+    data.push(id);
+    data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    data.extend_from_slice(value);
+}
+
+fn encryption_key(master_seed: &[u8], transformed_key: &[u8]) -> [u8; KEY_LEN] {
+    Sha256::new()
+        .chain_update(master_seed)
+        .chain_update(transformed_key)
+        .finalize()
+        .into()
+}
+
+fn hmac_key(master_seed: &[u8], transformed_key: &[u8]) -> [u8; 64] {
+    Sha512::new()
+        .chain_update(master_seed)
+        .chain_update(transformed_key)
+        .chain_update([1])
+        .finalize()
+        .into()
+}
+
+/// The HMAC of the block at `index`, the header's index is `u64::MAX`
+fn block_mac(hmac_key: &[u8], index: u64) -> Result<HmacSha256> {
+    let key = Sha512::new()
+        .chain_update(index.to_le_bytes())
+        .chain_update(hmac_key)
+        .finalize();
+
+    Ok(<HmacSha256 as Mac>::new_from_slice(&key)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // written by tests/fixtures/keepass.py
+    const FIXTURE: &[u8] = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/keepass.kdbx"
+    ));
+
+    fn transform(kdf: &KdfParameters) -> Result<[u8; KEY_LEN]> {
+        kdf.transform(&composite_key(Some("fixture password"), None)?)
+    }
+
+    /// A variant dictionary with the given values
+    fn variant_dictionary(values: &[(u8, &str, &[u8])]) -> Vec<u8> {
+        let mut data = 0x100u16.to_le_bytes().to_vec();
+        for (value_type, name, value) in values {
+            data.push(*value_type);
+            data.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            data.extend_from_slice(name.as_bytes());
+            data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            data.extend_from_slice(value);
+        }
+        data.push(0);
+
+        data
+    }
+
+    fn argon2(memory: u64, iterations: u64, parallelism: u32) -> Result<KdfParameters> {
+        KdfParameters::parse(&variant_dictionary(&[
+            (0x42, "$UUID", &KDF_ARGON2D),
+            (0x42, "S", &[0; 32]),
+            (0x04, "V", &0x13u32.to_le_bytes()),
+            (0x05, "M", &memory.to_le_bytes()),
+            (0x05, "I", &iterations.to_le_bytes()),
+            (0x04, "P", &parallelism.to_le_bytes()),
+        ]))
+    }
+
+    fn aes(rounds: u64) -> Result<KdfParameters> {
+        KdfParameters::parse(&variant_dictionary(&[
+            (0x42, "$UUID", &KDF_AES_KDBX4),
+            (0x42, "S", &[0; 32]),
+            (0x05, "R", &rounds.to_le_bytes()),
+        ]))
+    }
+
+    fn password(root: &Element) -> Option<String> {
+        let group = root.child("Root")?.child("Group")?;
+        let entry = group.elements().find(|element| element.name == "Entry")?;
+        entry
+            .elements()
+            .filter(|element| element.name == "String")
+            .find(|field| field.child_text("Key").as_deref() == Some("Password"))?
+            .child_text("Value")
+    }
+
+    #[test]
+    fn round_trip() {
+        let database = Database::parse(FIXTURE, transform).unwrap();
+        assert_eq!(password(&database.root).as_deref(), Some("hunter2"));
+
+        let data = database.to_bytes().unwrap();
+        assert_ne!(data, FIXTURE);
+        let database = Database::parse(&data, transform).unwrap();
+        assert_eq!(password(&database.root).as_deref(), Some("hunter2"));
+        assert_eq!(
+            database.root.child("Meta").unwrap().child_text("Generator"),
+            Some("KeePassXC".to_string())
+        );
+    }
+
+    #[test]
+    fn wrong_password() {
+        let err = Database::parse(FIXTURE, |kdf| {
+            kdf.transform(&composite_key(Some("wrong"), None)?)
+        })
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("wrong password"), "{:#}", err);
+    }
+
+    #[test]
+    fn tampered_header() {
+        // the last byte of the encryption IV, the header hash doesn't match anymore
+        let mut data = FIXTURE.to_vec();
+        let iv = data.windows(16).position(|iv| iv == [12; 16]).unwrap();
+        data[iv + 15] ^= 1;
+
+        let err = Database::parse(&data, transform).err().unwrap();
+        assert!(err.to_string().contains("corrupted header"), "{:#}", err);
+    }
+
+    #[test]
+    fn tampered_block() {
+        let mut data = FIXTURE.to_vec();
+        let last = data.len() - HASH_LEN - 4 - 1;
+        data[last] ^= 1;
+
+        let err = Database::parse(&data, transform).err().unwrap();
+        assert!(err.to_string().contains("corrupted block 0"), "{:#}", err);
+    }
+
+    #[test]
+    fn parameter_limits() {
+        assert!(argon2(64 * 1024 * 1024, 10, 2).is_ok());
+        assert!(aes(60_000_000).is_ok());
+
+        for (kdf, message) in [
+            (argon2(u64::MAX, 10, 2), "memory"),
+            (argon2(64 * 1024 * 1024, u64::MAX, 2), "iterations"),
+            (argon2(64 * 1024 * 1024, 10, u32::MAX), "parallelism"),
+            (aes(u64::MAX), "rounds"),
+        ] {
+            let err = kdf.err().unwrap().to_string();
+            assert!(err.contains(message) && err.contains("too"), "{}", err);
+        }
+    }
+}
@@ -0,0 +1,701 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::backend::{Backend, Entry};
+use crate::file::{create_private_dir, private_options};
+use anyhow::{anyhow, Context, Result};
+use base64ct::{Base64, Encoding};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use std::cell::RefCell;
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+mod kdbx;
+mod xml;
+
+use kdbx::{composite_key, Database, KdfParameters};
+use xml::{Element, Node};
+
+// seconds from 0001-01-01, where KDBX 4 times start, to the unix epoch
+const UNIX_EPOCH_OFFSET: i64 = 62_135_596_800;
+
+// KeePass' default for `HistoryMaxItems`
+const HISTORY_MAX_ITEMS: i64 = 10;
+
+// the folder icon, for groups we create
+const GROUP_ICON: &str = "48";
+
+/// How services and usernames map to the entries of a KeePass database.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeePassMapping {
+    /// The service is the entry's title, anywhere in the database
+    Title,
+    /// The service is the name of the group the entry is in, new groups are added to the root
+    /// group
+    Group,
+}
+
+impl FromStr for KeePassMapping {
+    type Err = anyhow::Error;
+
+    fn from_str(mapping: &str) -> Result<Self> {
+        match mapping {
+            "title" => Ok(KeePassMapping::Title),
+            "group" => Ok(KeePassMapping::Group),
+            _ => Err(anyhow!("unknown KeePass mapping {:?}", mapping)),
+        }
+    }
+}
+
+/// Stores secrets in a KeePass database, a KDBX 4 file as written by KeePass 2.35+ and
+/// KeePassXC 2.7+.
+///
+/// The username is the entry's `UserName` and the service its title or group, see
+/// [`KeePassMapping`]. Changed entries keep their previous version in their history, and deleted
+/// ones are recorded so synchronizing copies of the database doesn't bring them back. Entries in
+/// the recycle bin are ignored. The database is read for every operation and replaced atomically
+/// when it changes.
+///
+/// There is no lock shared with KeePass or KeePassXC, which keep the database in memory until
+/// they save it. A change made here while the database is open with unsaved changes elsewhere is
+/// lost when the other program saves, and the other way around when it saves between our read
+/// and write.
+pub struct KeePassKeyring<'a> {
+    username: &'a str,
+    service: &'a str,
+    path: PathBuf,
+    key: [u8; 32],
+    mapping: KeePassMapping,
+    transformed_key: RefCell<Option<(Vec<u8>, [u8; 32])>>,
+}
+
+impl<'a> KeePassKeyring<'a> {
+    /// Returns a backend for the database at `path`, unlocked with a password, a key file or both
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username to store secrets under
+    /// * `service` - A unique identifier within your application
+    /// * `path` - The `.kdbx` file
+    /// * `password` - The database's password
+    /// * `key_file` - The database's key file
+    pub fn new<P: Into<PathBuf>>(
+        username: &'a str,
+        service: &'a str,
+        path: P,
+        password: Option<&str>,
+        key_file: Option<&Path>,
+    ) -> Result<Self> {
+        if password.is_none() && key_file.is_none() {
+            return Err(anyhow!("a password or a key file is needed"));
+        }
+        let key_file = match key_file {
+            Some(key_file) => {
+                Some(fs::read(key_file).with_context(|| format!("failed to read {:?}", key_file))?)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            username,
+            service,
+            path: path.into(),
+            key: composite_key(password, key_file.as_deref())?,
+            mapping: KeePassMapping::Title,
+            transformed_key: RefCell::new(None),
+        })
+    }
+
+    /// Maps services to entries with `mapping` instead of by title
+    pub fn with_mapping(mut self, mapping: KeePassMapping) -> Self {
+        self.mapping = mapping;
+        self
+    }
+
+    fn open(&self) -> Result<Database> {
+        let data =
+            fs::read(&self.path).with_context(|| format!("failed to read {:?}", self.path))?;
+
+        Database::parse(&data, |kdf| self.transformed_key(kdf))
+            .with_context(|| format!("failed to open the database {:?}", self.path))
+    }
+
+    /// Transforms the key, reusing it while the key derivation parameters don't change
+    fn transformed_key(&self, kdf: &KdfParameters) -> Result<[u8; 32]> {
+        if let Some((params, key)) = self.transformed_key.borrow().as_ref() {
+            if params.as_slice() == kdf.as_bytes() {
+                return Ok(*key);
+            }
+        }
+        span!("transform_key");
+
+        let key = kdf.transform(&self.key)?;
+        self.transformed_key
+            .replace(Some((kdf.as_bytes().to_vec(), key)));
+
+        Ok(key)
+    }
+
+    /// Writes the database back to its file, replacing it atomically
+    fn write(&self, database: &Database) -> Result<()> {
+        let data = database.to_bytes()?;
+
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        create_private_dir(dir)?;
+        let mut tmp_name = std::ffi::OsString::from(".");
+        tmp_name.push(
+            self.path
+                .file_name()
+                .ok_or_else(|| anyhow!("{:?} is not a file", self.path))?,
+        );
+        tmp_name.push(format!(".{}.tmp", std::process::id()));
+        let tmp_path = dir.join(tmp_name);
+
+        let mut tmp = private_options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        tmp.write_all(&data)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("failed to replace {:?}", self.path))?;
+        File::open(dir)?.sync_all()?;
+
+        Ok(())
+    }
+
+    fn is_ours(&self, group: &Element, is_root: bool, entry: &Element) -> bool {
+        if string(entry, "UserName").as_deref() != Some(self.username) {
+            return false;
+        }
+
+        match self.mapping {
+            KeePassMapping::Title => string(entry, "Title").as_deref() == Some(self.service),
+            KeePassMapping::Group => {
+                !is_root && group.child_text("Name").as_deref() == Some(self.service)
+            }
+        }
+    }
+
+    /// Returns the group with the entry, and whether it's the root group
+    fn find_group<'e>(
+        &self,
+        group: &'e mut Element,
+        recycle_bin: Option<&str>,
+        is_root: bool,
+    ) -> Option<(&'e mut Element, bool)> {
+        if recycle_bin.is_some() && group.child_text("UUID").as_deref() == recycle_bin {
+            return None;
+        }
+        let has_entry = group
+            .elements()
+            .any(|entry| entry.name == "Entry" && self.is_ours(group, is_root, entry));
+        if has_entry {
+            return Some((group, is_root));
+        }
+
+        for child in group.elements_mut().filter(|child| child.name == "Group") {
+            if let Some(found) = self.find_group(child, recycle_bin, false) {
+                return Some(found);
+            }
+        }
+
+        None
+    }
+
+    /// Returns the group with the entry and the entry's position in it
+    fn locate<'d>(&self, database: &'d mut Database) -> Result<Option<(&'d mut Element, usize)>> {
+        let recycle_bin = recycle_bin(&database.root);
+        let (group, is_root) = match self.find_group(
+            root_group(&mut database.root)?,
+            recycle_bin.as_deref(),
+            true,
+        ) {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+
+        let index = group.children.iter().position(|child| match child {
+            Node::Element(entry) => entry.name == "Entry" && self.is_ours(group, is_root, entry),
+            Node::Text(_) => false,
+        });
+
+        Ok(index.map(|index| (group, index)))
+    }
+
+    fn entry_mut<'d>(&self, database: &'d mut Database) -> Result<Option<&'d mut Element>> {
+        Ok(match self.locate(database)? {
+            Some((group, index)) => match &mut group.children[index] {
+                Node::Element(entry) => Some(entry),
+                Node::Text(_) => None,
+            },
+            None => None,
+        })
+    }
+
+    /// Returns the group new entries go to, adding it if needed
+    fn target_group<'d>(&self, database: &'d mut Database, now: &str) -> Result<&'d mut Element> {
+        let recycle_bin = recycle_bin(&database.root);
+        let root = root_group(&mut database.root)?;
+        if self.mapping == KeePassMapping::Title {
+            return Ok(root);
+        }
+
+        let exists = root.elements().any(|group| {
+            group.name == "Group"
+                && group.child_text("Name").as_deref() == Some(self.service)
+                && group.child_text("UUID") != recycle_bin
+        });
+        if !exists {
+            let mut group = Element::new("Group");
+            group.push(Element::with_text("UUID", &new_uuid()));
+            group.push(Element::with_text("Name", self.service));
+            group.push(Element::new("Notes"));
+            group.push(Element::with_text("IconID", GROUP_ICON));
+            group.push(new_times(now));
+            group.push(Element::with_text("IsExpanded", "True"));
+            root.push(group);
+        }
+
+        root.elements_mut()
+            .find(|group| {
+                group.name == "Group"
+                    && group.child_text("Name").as_deref() == Some(self.service)
+                    && group.child_text("UUID") != recycle_bin
+            })
+            .ok_or_else(|| anyhow!("failed to add the group {:?}", self.service))
+    }
+
+    fn new_entry(&self, password: &str, now: &str) -> Element {
+        let title = match self.mapping {
+            KeePassMapping::Title => self.service,
+            KeePassMapping::Group => self.username,
+        };
+
+        let mut entry = Element::new("Entry");
+        entry.push(Element::with_text("UUID", &new_uuid()));
+        entry.push(Element::with_text("IconID", "0"));
+        entry.push(new_times(now));
+        set_string(&mut entry, "Title", title, false);
+        set_string(&mut entry, "UserName", self.username, false);
+        set_string(&mut entry, "Password", password, true);
+        set_string(&mut entry, "URL", "", false);
+        set_string(&mut entry, "Notes", "", false);
+
+        entry
+    }
+
+    fn collect(
+        &self,
+        group: &Element,
+        recycle_bin: Option<&str>,
+        is_root: bool,
+        entries: &mut Vec<Entry>,
+    ) {
+        if recycle_bin.is_some() && group.child_text("UUID").as_deref() == recycle_bin {
+            return;
+        }
+
+        for element in group.elements() {
+            match element.name.as_str() {
+                "Group" => self.collect(element, recycle_bin, false, entries),
+                "Entry" => {
+                    let service = match self.mapping {
+                        KeePassMapping::Title => string(element, "Title"),
+                        KeePassMapping::Group if is_root => None,
+                        KeePassMapping::Group => group.child_text("Name"),
+                    };
+                    if let Some(service) = service {
+                        entries.push(Entry {
+                            service,
+                            username: string(element, "UserName").unwrap_or_default(),
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Backend for KeePassKeyring<'_> {
+    fn name(&self) -> &'static str {
+        "keepass"
+    }
+
+    fn set_password(&self, password: String) -> Result<()> {
+        let mut database = self.open()?;
+        let now = format_time(SystemTime::now());
+        let history_max_items = database
+            .root
+            .child("Meta")
+            .and_then(|meta| meta.child_text("HistoryMaxItems"))
+            .and_then(|max| max.trim().parse().ok())
+            .unwrap_or(HISTORY_MAX_ITEMS);
+
+        match self.entry_mut(&mut database)? {
+            Some(entry) => {
+                let mut previous = entry.clone();
+                previous.retain(|element| element.name != "History");
+
+                set_string(entry, "Password", &password, true);
+                let times = entry.child_or_insert("Times");
+                times.child_or_insert("LastModificationTime").set_text(&now);
+                times.child_or_insert("LastAccessTime").set_text(&now);
+
+                // a negative maximum keeps every version
+                let history = entry.child_or_insert("History");
+                history.push(previous);
+                if history_max_items >= 0 {
+                    let count = history.elements().count() as i64;
+                    let mut excess = count - history_max_items;
+                    history.retain(|_| {
+                        excess -= 1;
+                        excess < 0
+                    });
+                }
+            }
+            None => {
+                let entry = self.new_entry(&password, &now);
+                self.target_group(&mut database, &now)?.push(entry);
+            }
+        }
+
+        self.write(&database)
+    }
+
+    fn get_password(&self) -> Result<Option<String>> {
+        let mut database = self.open()?;
+
+        Ok(self
+            .entry_mut(&mut database)?
+            .and_then(|entry| string(entry, "Password")))
+    }
+
+    fn delete_password(&self) -> Result<bool> {
+        let mut database = self.open()?;
+        let uuid = match self.locate(&mut database)? {
+            Some((group, index)) => match group.children.remove(index) {
+                Node::Element(entry) => entry.child_text("UUID"),
+                Node::Text(_) => None,
+            },
+            None => return Ok(false),
+        };
+
+        if let Some(uuid) = uuid {
+            let mut deleted = Element::new("DeletedObject");
+            deleted.push(Element::with_text("UUID", &uuid));
+            deleted.push(Element::with_text(
+                "DeletionTime",
+                &format_time(SystemTime::now()),
+            ));
+            database
+                .root
+                .child_or_insert("Root")
+                .child_or_insert("DeletedObjects")
+                .push(deleted);
+        }
+        self.write(&database)?;
+
+        Ok(true)
+    }
+
+    /// Lists every entry outside of the recycle bin
+    fn list(&self) -> Result<Vec<Entry>> {
+        let mut database = self.open()?;
+        let recycle_bin = recycle_bin(&database.root);
+        let mut entries = Vec::new();
+        self.collect(
+            root_group(&mut database.root)?,
+            recycle_bin.as_deref(),
+            true,
+            &mut entries,
+        );
+
+        Ok(entries)
+    }
+
+    fn modified(&self) -> Result<Option<u64>> {
+        let mut database = self.open()?;
+
+        Ok(self.entry_mut(&mut database)?.and_then(|entry| {
+            let modified = entry.child("Times")?.child_text("LastModificationTime")?;
+            parse_time(&modified)
+        }))
+    }
+}
+
+fn root_group(root: &mut Element) -> Result<&mut Element> {
+    root.child_mut("Root")
+        .and_then(|root| root.child_mut("Group"))
+        .ok_or_else(|| anyhow!("the database has no root group"))
+}
+
+fn recycle_bin(root: &Element) -> Option<String> {
+    root.child("Meta")?.child_text("RecycleBinUUID")
+}
+
+/// The value of an entry's string field, e.g. `Title` or `Password`
+fn string(entry: &Element, key: &str) -> Option<String> {
+    entry
+        .elements()
+        .filter(|element| element.name == "String")
+        .find(|field| field.child_text("Key").as_deref() == Some(key))?
+        .child_text("Value")
+}
+
+fn set_string(entry: &mut Element, key: &str, value: &str, protected: bool) {
+    let is_field = |element: &Element| {
+        element.name == "String" && element.child_text("Key").as_deref() == Some(key)
+    };
+    if !entry.elements().any(is_field) {
+        let mut field = Element::new("String");
+        field.push(Element::with_text("Key", key));
+        entry.push(field);
+    }
+
+    let value_element = entry
+        .elements_mut()
+        .find(|element| is_field(element))
+        .expect("the field exists")
+        .child_or_insert("Value");
+    value_element.set_text(value);
+    if protected {
+        value_element.set_attribute("Protected", "True");
+    }
+}
+
+fn new_times(now: &str) -> Element {
+    let mut times = Element::new("Times");
+    times.push(Element::with_text("CreationTime", now));
+    times.push(Element::with_text("LastModificationTime", now));
+    times.push(Element::with_text("LastAccessTime", now));
+    times.push(Element::with_text("ExpiryTime", now));
+    times.push(Element::with_text("Expires", "False"));
+    times.push(Element::with_text("UsageCount", "0"));
+    times.push(Element::with_text("LocationChanged", now));
+
+    times
+}
+
+fn new_uuid() -> String {
+    let mut uuid = [0u8; 16];
+    OsRng.fill_bytes(&mut uuid);
+
+    Base64::encode_string(&uuid)
+}
+
+/// KDBX 4 times are the base64 of little endian seconds since 0001-01-01
+fn format_time(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or_default();
+
+    Base64::encode_string(&(seconds + UNIX_EPOCH_OFFSET).to_le_bytes())
+}
+
+/// Returns seconds since the unix epoch
+fn parse_time(time: &str) -> Option<u64> {
+    let bytes = Base64::decode_vec(time.trim()).ok()?;
+    let seconds = i64::from_le_bytes(bytes.as_slice().try_into().ok()?);
+
+    (seconds - UNIX_EPOCH_OFFSET).try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+    const PASSWORD: &str = "fixture password";
+
+    /// Copies the fixture database `name`, written by tests/fixtures/keepass.py, to a new
+    /// directory
+    fn fixture(name: &str) -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(name);
+        fs::copy(Path::new(FIXTURES).join(name), &path).unwrap();
+
+        (dir, path)
+    }
+
+    fn key_file() -> PathBuf {
+        Path::new(FIXTURES).join("keepass.keyx")
+    }
+
+    fn entries(keyring: &KeePassKeyring) -> Vec<(String, String)> {
+        let mut entries: Vec<(String, String)> = keyring
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.service, entry.username))
+            .collect();
+        entries.sort();
+
+        entries
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(service, username)| (service.to_string(), username.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn opens_with_a_password() {
+        let (_dir, path) = fixture("keepass.kdbx");
+        let keyring = KeePassKeyring::new("alice", "example.com", &path, Some(PASSWORD), None);
+        let keyring = keyring.unwrap();
+
+        // the entry in the recycle bin has another password
+        assert_eq!(keyring.get_password().unwrap().as_deref(), Some("hunter2"));
+        assert_eq!(keyring.modified().unwrap(), Some(1700000000));
+
+        let keyring = KeePassKeyring::new("alice", "example.com", &path, Some("wrong"), None);
+        let err = keyring.unwrap().get_password().err().unwrap();
+        assert!(format!("{:#}", err).contains("wrong password"), "{:#}", err);
+    }
+
+    #[test]
+    fn opens_with_a_key_file() {
+        let (_dir, path) = fixture("keepass-key-file.kdbx");
+        let keyring = KeePassKeyring::new("admin", "Servers", &path, None, Some(&key_file()));
+        let keyring = keyring.unwrap().with_mapping(KeePassMapping::Group);
+        assert_eq!(keyring.get_password().unwrap().as_deref(), Some("pässword"));
+
+        let keyring = KeePassKeyring::new("admin", "Servers", &path, Some(PASSWORD), None);
+        assert!(keyring.unwrap().get_password().is_err());
+        assert!(KeePassKeyring::new("admin", "Servers", &path, None, None).is_err());
+    }
+
+    #[test]
+    fn title_mapping() {
+        let (_dir, path) = fixture("keepass.kdbx");
+        let keyring = |username, service| {
+            KeePassKeyring::new(username, service, &path, Some(PASSWORD), None).unwrap()
+        };
+        assert_eq!(
+            entries(&keyring("alice", "example.com")),
+            pairs(&[("db", "admin"), ("example.com", "alice")])
+        );
+
+        let bob = keyring("bob", "example.com");
+        assert_eq!(bob.get_password().unwrap(), None);
+        bob.set_password("secret".to_string()).unwrap();
+        assert_eq!(bob.get_password().unwrap().as_deref(), Some("secret"));
+
+        let alice = keyring("alice", "example.com");
+        alice.set_password("changed".to_string()).unwrap();
+        assert_eq!(alice.get_password().unwrap().as_deref(), Some("changed"));
+        assert_eq!(
+            keyring("admin", "db").get_password().unwrap().as_deref(),
+            Some("pässword")
+        );
+        assert_eq!(
+            entries(&alice),
+            pairs(&[
+                ("db", "admin"),
+                ("example.com", "alice"),
+                ("example.com", "bob")
+            ])
+        );
+
+        assert!(alice.delete_password().unwrap());
+        assert!(!alice.delete_password().unwrap());
+        assert_eq!(alice.get_password().unwrap(), None);
+        assert_eq!(
+            entries(&alice),
+            pairs(&[("db", "admin"), ("example.com", "bob")])
+        );
+    }
+
+    #[test]
+    fn group_mapping() {
+        let (_dir, path) = fixture("keepass-key-file.kdbx");
+        let key_file = key_file();
+        let keyring = |username, service| {
+            KeePassKeyring::new(username, service, &path, None, Some(&key_file))
+                .unwrap()
+                .with_mapping(KeePassMapping::Group)
+        };
+        // entries in the root group have no service
+        assert_eq!(
+            entries(&keyring("admin", "Servers")),
+            pairs(&[("Servers", "admin")])
+        );
+        assert_eq!(
+            keyring("alice", "example.com").get_password().unwrap(),
+            None
+        );
+
+        keyring("bob", "Servers")
+            .set_password("secret".to_string())
+            .unwrap();
+        keyring("carol", "Staging")
+            .set_password("staging".to_string())
+            .unwrap();
+        assert_eq!(
+            keyring("carol", "Staging")
+                .get_password()
+                .unwrap()
+                .as_deref(),
+            Some("staging")
+        );
+        assert_eq!(
+            entries(&keyring("admin", "Servers")),
+            pairs(&[
+                ("Servers", "admin"),
+                ("Servers", "bob"),
+                ("Staging", "carol")
+            ])
+        );
+
+        let admin = keyring("admin", "Servers");
+        assert!(admin.delete_password().unwrap());
+        assert_eq!(admin.get_password().unwrap(), None);
+        assert_eq!(
+            keyring("bob", "Servers").get_password().unwrap().as_deref(),
+            Some("secret")
+        );
+    }
+
+    #[test]
+    fn reopens_written_databases() {
+        let (_dir, path) = fixture("keepass.kdbx");
+        let keyring = KeePassKeyring::new("alice", "example.com", &path, Some(PASSWORD), None);
+        keyring
+            .unwrap()
+            .set_password("changed".to_string())
+            .unwrap();
+
+        // a new backend doesn't reuse the transformed key
+        let keyring = KeePassKeyring::new("alice", "example.com", &path, Some(PASSWORD), None);
+        let keyring = keyring.unwrap();
+        assert_eq!(keyring.get_password().unwrap().as_deref(), Some("changed"));
+
+        // the previous version is kept in the entry's history
+        let mut database = keyring.open().unwrap();
+        let entry = keyring.entry_mut(&mut database).unwrap().unwrap();
+        let history: Vec<Option<String>> = entry
+            .child("History")
+            .unwrap()
+            .elements()
+            .map(|previous| string(previous, "Password"))
+            .collect();
+        assert_eq!(history, vec![Some("hunter2".to_string())]);
+    }
+}
@@ -0,0 +1,219 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use anyhow::{anyhow, Result};
+use quick_xml::events::{BytesDecl, BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::{Reader, Writer};
+
+/// An XML element, kept with everything in it so a database is written back the way it was read.
+#[derive(Clone, Debug)]
+pub struct Element {
+    pub name: String,
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<Node>,
+}
+
+#[derive(Clone, Debug)]
+pub enum Node {
+    Element(Element),
+    Text(String),
+}
+
+impl Element {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            attributes: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+
+    /// Returns an element that only has `text` in it
+    pub fn with_text(name: &str, text: &str) -> Self {
+        let mut element = Self::new(name);
+        element.set_text(text);
+        element
+    }
+
+    /// Parses a document and returns its root element
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let mut reader = Reader::from_reader(data);
+        reader.config_mut().expand_empty_elements = true;
+        // the elements we're in, the innermost last
+        let mut stack: Vec<Element> = Vec::new();
+
+        loop {
+            match reader.read_event()? {
+                Event::Start(start) => {
+                    let mut element = Self::new(std::str::from_utf8(start.name().as_ref())?);
+                    for attribute in start.attributes() {
+                        let attribute = attribute?;
+                        element.attributes.push((
+                            std::str::from_utf8(attribute.key.as_ref())?.to_string(),
+                            attribute.unescape_value()?.into_owned(),
+                        ));
+                    }
+                    stack.push(element);
+                }
+                Event::End(_) => {
+                    let element = stack.pop().ok_or_else(|| anyhow!("unbalanced XML"))?;
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(Node::Element(element)),
+                        None => return Ok(element),
+                    }
+                }
+                Event::Text(text) => {
+                    if let Some(parent) = stack.last_mut() {
+                        parent.push_text(&text.unescape()?);
+                    }
+                }
+                Event::CData(data) => {
+                    if let Some(parent) = stack.last_mut() {
+                        parent.push_text(std::str::from_utf8(&data.into_inner())?);
+                    }
+                }
+                Event::Eof => return Err(anyhow!("the XML ended early")),
+                // declarations, comments and processing instructions
+                _ => {}
+            }
+        }
+    }
+
+    /// Serializes the element as a document
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut writer = Writer::new(Vec::new());
+        writer.write_event(Event::Decl(BytesDecl::new(
+            "1.0",
+            Some("utf-8"),
+            Some("yes"),
+        )))?;
+        self.write(&mut writer)?;
+
+        Ok(writer.into_inner())
+    }
+
+    fn write(&self, writer: &mut Writer<Vec<u8>>) -> Result<()> {
+        let start = BytesStart::new(self.name.as_str()).with_attributes(
+            self.attributes
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        );
+        if self.children.is_empty() {
+            writer.write_event(Event::Empty(start))?;
+            return Ok(());
+        }
+
+        writer.write_event(Event::Start(start))?;
+        for child in &self.children {
+            match child {
+                Node::Element(element) => element.write(writer)?,
+                Node::Text(text) => writer.write_event(Event::Text(BytesText::new(text)))?,
+            }
+        }
+        writer.write_event(Event::End(BytesEnd::new(self.name.as_str())))?;
+
+        Ok(())
+    }
+
+    /// Appends text, merging it with the text before it
+    fn push_text(&mut self, text: &str) {
+        match self.children.last_mut() {
+            Some(Node::Text(last)) => last.push_str(text),
+            _ => self.children.push(Node::Text(text.to_string())),
+        }
+    }
+
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn set_attribute(&mut self, name: &str, value: &str) {
+        match self.attributes.iter_mut().find(|(key, _)| key == name) {
+            Some((_, old)) => *old = value.to_string(),
+            None => self.attributes.push((name.to_string(), value.to_string())),
+        }
+    }
+
+    /// The text directly in the element
+    pub fn text(&self) -> String {
+        self.children
+            .iter()
+            .filter_map(|child| match child {
+                Node::Text(text) => Some(text.as_str()),
+                Node::Element(_) => None,
+            })
+            .collect()
+    }
+
+    /// Replaces the element's content with `text`
+    pub fn set_text(&mut self, text: &str) {
+        self.children.clear();
+        if !text.is_empty() {
+            self.children.push(Node::Text(text.to_string()));
+        }
+    }
+
+    pub fn elements(&self) -> impl Iterator<Item = &Element> {
+        self.children.iter().filter_map(|child| match child {
+            Node::Element(element) => Some(element),
+            Node::Text(_) => None,
+        })
+    }
+
+    pub fn elements_mut(&mut self) -> impl Iterator<Item = &mut Element> {
+        self.children.iter_mut().filter_map(|child| match child {
+            Node::Element(element) => Some(element),
+            Node::Text(_) => None,
+        })
+    }
+
+    /// The first child element called `name`
+    pub fn child(&self, name: &str) -> Option<&Element> {
+        self.elements().find(|element| element.name == name)
+    }
+
+    pub fn child_mut(&mut self, name: &str) -> Option<&mut Element> {
+        self.elements_mut().find(|element| element.name == name)
+    }
+
+    /// The first child element called `name`, added if there is none
+    pub fn child_or_insert(&mut self, name: &str) -> &mut Element {
+        if self.child(name).is_none() {
+            self.push(Self::new(name));
+        }
+        self.child_mut(name).expect("the child was just added")
+    }
+
+    /// The text of the first child element called `name`
+    pub fn child_text(&self, name: &str) -> Option<String> {
+        self.child(name).map(Self::text)
+    }
+
+    pub fn push(&mut self, element: Element) {
+        self.children.push(Node::Element(element));
+    }
+
+    /// Removes the child elements for which `keep` returns false
+    pub fn retain<F: FnMut(&Element) -> bool>(&mut self, mut keep: F) {
+        self.children.retain(|child| match child {
+            Node::Element(element) => keep(element),
+            Node::Text(_) => true,
+        });
+    }
+
+    /// Calls `visit` on the element and everything in it, in document order
+    pub fn visit_mut<F: FnMut(&mut Element) -> Result<()>>(&mut self, visit: &mut F) -> Result<()> {
+        visit(self)?;
+        for element in self.elements_mut() {
+            element.visit_mut(visit)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,232 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::backend::{Backend, Entry};
+use anyhow::{anyhow, Result};
+use linux_keyutils::{KeyError, KeyRing, KeyRingIdentifier, KeyType, LinkNode};
+use std::str::FromStr;
+use std::time::Duration;
+
+// the most keys we list from a keyring
+const MAX_LINKS: usize = 4096;
+
+/// Which kernel keyring a [`KernelKeyring`] stores keys in.
+///
+/// <https://man7.org/linux/man-pages/man7/keyrings.7.html>
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyringTarget {
+    /// The session keyring, shared by the processes of a login session
+    Session,
+    /// The user keyring, shared by all processes of the user
+    User,
+    /// The process keyring, private to this process
+    Process,
+    /// The user's persistent keyring, which outlives login sessions until it expires
+    Persistent,
+}
+
+impl FromStr for KeyringTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(target: &str) -> Result<Self> {
+        match target {
+            "session" => Ok(KeyringTarget::Session),
+            "user" => Ok(KeyringTarget::User),
+            "process" => Ok(KeyringTarget::Process),
+            "persistent" => Ok(KeyringTarget::Persistent),
+            _ => Err(anyhow!("unknown kernel keyring {:?}", target)),
+        }
+    }
+}
+
+/// Stores secrets in the Linux kernel keyring, which works without a D-Bus daemon.
+///
+/// Secrets are `user` keys described as `<application>:<service>:<username>`, with `:` and `\`
+/// in the parts escaped by a `\`.
+pub struct KernelKeyring<'a> {
+    username: &'a str,
+    service: &'a str,
+    application: &'a str,
+    keyring: KeyRing,
+    timeout: Option<Duration>,
+}
+
+impl<'a> KernelKeyring<'a> {
+    /// Returns a kernel keyring backend
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username to store secrets under
+    /// * `service` - A unique identifier within your application
+    /// * `application` - The name of your application
+    /// * `target` - The keyring to store keys in
+    pub fn new(
+        username: &'a str,
+        service: &'a str,
+        application: &'a str,
+        target: KeyringTarget,
+    ) -> Result<Self> {
+        let keyring = match target {
+            KeyringTarget::Session => KeyRing::from_special_id(KeyRingIdentifier::Session, true),
+            KeyringTarget::User => KeyRing::from_special_id(KeyRingIdentifier::User, true),
+            KeyringTarget::Process => KeyRing::from_special_id(KeyRingIdentifier::Process, true),
+            KeyringTarget::Persistent => KeyRing::get_persistent(KeyRingIdentifier::Session),
+        }
+        .map_err(|err| anyhow!("failed to open the {:?} kernel keyring: {}", target, err))?;
+
+        Ok(Self {
+            username,
+            service,
+            application,
+            keyring,
+            timeout: None,
+        })
+    }
+
+    /// Makes stored keys expire `timeout` after they were last set, via `keyctl_set_timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    fn description(&self) -> String {
+        [self.application, self.service, self.username]
+            .iter()
+            .map(|part| escape(part))
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+}
+
+fn escape(part: &str) -> String {
+    part.replace('\\', "\\\\").replace(':', "\\:")
+}
+
+/// Splits a description at the unescaped `:`s and unescapes the parts
+fn split_description(description: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut part = String::new();
+    let mut chars = description.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => part.extend(chars.next()),
+            ':' => parts.push(std::mem::take(&mut part)),
+            c => part.push(c),
+        }
+    }
+    parts.push(part);
+
+    parts
+}
+
+impl Backend for KernelKeyring<'_> {
+    fn name(&self) -> &'static str {
+        "keyutils"
+    }
+
+    fn set_password(&self, password: String) -> Result<()> {
+        let key = self.keyring.add_key(&self.description(), &password)?;
+        if let Some(timeout) = self.timeout {
+            // a timeout of 0 would mean no timeout
+            key.set_timeout(std::cmp::max(timeout.as_secs(), 1) as usize)?;
+        }
+
+        Ok(())
+    }
+
+    fn get_password(&self) -> Result<Option<String>> {
+        let key = match self.keyring.search(&self.description()) {
+            Ok(key) => key,
+            Err(KeyError::KeyDoesNotExist) | Err(KeyError::KeyExpired) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(Some(String::from_utf8(key.read_to_vec()?)?))
+    }
+
+    fn delete_password(&self) -> Result<bool> {
+        match self.keyring.search(&self.description()) {
+            Ok(key) => {
+                key.invalidate()?;
+                Ok(true)
+            }
+            Err(KeyError::KeyDoesNotExist) | Err(KeyError::KeyExpired) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn list(&self) -> Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+
+        for link in self.keyring.get_links(MAX_LINKS)?.iter() {
+            let key = match link {
+                LinkNode::Key(key) => key,
+                LinkNode::KeyRing(_) => continue,
+            };
+            let metadata = match key.metadata() {
+                Ok(metadata) => metadata,
+                // keys we may not view aren't ours
+                Err(_) => continue,
+            };
+            if metadata.get_type() != KeyType::User {
+                continue;
+            }
+
+            if let [application, service, username] =
+                split_description(metadata.get_description()).as_slice()
+            {
+                if application == self.application {
+                    entries.push(Entry {
+                        service: service.clone(),
+                        username: username.clone(),
+                    });
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn descriptions_round_trip() {
+        for parts in [
+            ["app", "service", "user"],
+            ["app", "host:8080", "user"],
+            ["app", "a\\", ":b"],
+            ["a:b", "", "c\\:d"],
+        ] {
+            let description = parts.iter().map(|part| escape(part)).collect::<Vec<_>>();
+            assert_eq!(split_description(&description.join(":")), parts);
+        }
+    }
+
+    #[test]
+    fn lists_services_with_colons() {
+        let keyring = match KernelKeyring::new("user:1", "host:8080", "app", KeyringTarget::Process)
+        {
+            Ok(keyring) => keyring,
+            // e.g. in containers without keyctl
+            Err(_) => return,
+        };
+        keyring.set_password("secret".to_string()).unwrap();
+
+        assert_eq!(keyring.get_password().unwrap().as_deref(), Some("secret"));
+        assert_eq!(
+            keyring.list().unwrap(),
+            vec![Entry {
+                service: "host:8080".to_string(),
+                username: "user:1".to_string(),
+            }]
+        );
+        assert!(keyring.delete_password().unwrap());
+    }
+}
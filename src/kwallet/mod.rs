@@ -0,0 +1,598 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::backend::{Backend, Entry};
+use crate::options::Options;
+use crate::secret_service::call::Caller;
+use anyhow::{anyhow, Context, Result};
+use std::cell::{Cell, RefCell};
+use zbus::blocking::Connection;
+
+// the proxy mirrors the daemon's interface, not everything in it is used
+#[allow(dead_code)]
+mod proxy;
+
+use proxy::KWalletProxy;
+
+// the daemons to try, newest first, with the object they serve the wallet on
+const DAEMONS: [(&str, &str); 2] = [
+    ("org.kde.kwalletd6", "/modules/kwalletd6"),
+    ("org.kde.kwalletd5", "/modules/kwalletd5"),
+];
+
+// `KWallet::Wallet::EntryType::Password`
+const PASSWORD_ENTRY: i32 = 1;
+
+/// Stores secrets in KDE Wallet through `kwalletd6` or `kwalletd5`, for KDE desktops without the
+/// Secret Service bridge.
+///
+/// The service is the wallet folder and the username the entry's key. The wallet is opened when it
+/// is first used, which may ask the user for the wallet's password.
+pub struct KWallet<'a> {
+    username: &'a str,
+    service: &'a str,
+    application: &'a str,
+    wallet: RefCell<Option<String>>,
+    proxy: KWalletProxy<'static>,
+    handle: Cell<Option<i32>>,
+    caller: Caller,
+}
+
+impl<'a> KWallet<'a> {
+    /// Returns a KDE Wallet backend using the network wallet, usually `kdewallet`
+    ///
+    /// Fails when neither daemon is running or can be started.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username to store secrets under
+    /// * `service` - A unique identifier within your application
+    /// * `application` - The name of your application
+    /// * `options` - Settings for the client, see [`Options`]
+    pub fn new(
+        username: &'a str,
+        service: &'a str,
+        application: &'a str,
+        options: Options,
+    ) -> Result<Self> {
+        Self::on_connection(
+            username,
+            service,
+            application,
+            options,
+            Connection::session()?,
+        )
+    }
+
+    /// Returns a KDE Wallet backend talking to the daemon on `connection`'s bus
+    fn on_connection(
+        username: &'a str,
+        service: &'a str,
+        application: &'a str,
+        options: Options,
+        connection: Connection,
+    ) -> Result<Self> {
+        options.no_profile("kwallet")?;
+        let caller = Caller::new(&options);
+        let proxy = Self::connect(&connection, &caller)?;
+
+        Ok(Self {
+            username,
+            service,
+            application,
+            wallet: RefCell::new(None),
+            proxy,
+            handle: Cell::new(None),
+            caller,
+        })
+    }
+
+    /// Uses the wallet named `wallet` instead of the network wallet
+    pub fn with_wallet(self, wallet: &str) -> Self {
+        self.wallet.replace(Some(wallet.to_string()));
+        self
+    }
+
+    fn connect(connection: &Connection, caller: &Caller) -> Result<KWalletProxy<'static>> {
+        let mut errors = Vec::new();
+
+        for (destination, path) in DAEMONS.iter() {
+            let proxy: KWalletProxy = caller.call(
+                KWalletProxy::builder(connection.inner())
+                    .destination(*destination)?
+                    .path(*path)?
+                    .build(),
+            )?;
+
+            match caller.call_idempotent(|| proxy.is_enabled()) {
+                Ok(true) => {
+                    event!(debug, daemon = destination, "connected to kwallet");
+                    return Ok(proxy);
+                }
+                Ok(false) => errors.push(format!("{}: the wallet is disabled", destination)),
+                Err(err) => errors.push(format!("{}: {:#}", destination, err)),
+            }
+        }
+
+        Err(anyhow!(
+            "no kwallet daemon is available: {}",
+            errors.join("; ")
+        ))
+    }
+
+    /// Returns the handle of the open wallet, opening it first if needed
+    fn handle(&self) -> Result<i32> {
+        if let Some(handle) = self.handle.get() {
+            return Ok(handle);
+        }
+
+        let wallet = match self.wallet.borrow().clone() {
+            Some(wallet) => wallet,
+            None => self
+                .caller
+                .call_idempotent(|| self.proxy.network_wallet())?,
+        };
+        span!("open_wallet", wallet = wallet.as_str());
+
+        // opening may ask the user for the wallet's password
+        let handle = self
+            .caller
+            .wait_for_prompt(self.proxy.open(&wallet, 0, self.application))
+            .with_context(|| format!("failed to open the wallet {:?}", wallet))?;
+        if handle < 0 {
+            return Err(anyhow!("the wallet {:?} couldn't be opened", wallet));
+        }
+        self.handle.set(Some(handle));
+        self.wallet.replace(Some(wallet));
+
+        Ok(handle)
+    }
+
+    fn has_entry(&self, handle: i32) -> Result<bool> {
+        self.caller.call_idempotent(|| {
+            self.proxy
+                .has_entry(handle, self.service, self.username, self.application)
+        })
+    }
+}
+
+impl Backend for KWallet<'_> {
+    fn name(&self) -> &'static str {
+        "kwallet"
+    }
+
+    fn set_password(&self, password: String) -> Result<()> {
+        let handle = self.handle()?;
+
+        let has_folder = self.caller.call_idempotent(|| {
+            self.proxy
+                .has_folder(handle, self.service, self.application)
+        })?;
+        if !has_folder {
+            let created = self.caller.call(self.proxy.create_folder(
+                handle,
+                self.service,
+                self.application,
+            ))?;
+            if !created {
+                return Err(anyhow!("failed to create the folder {:?}", self.service));
+            }
+        }
+
+        let result = self.caller.call(self.proxy.write_password(
+            handle,
+            self.service,
+            self.username,
+            &password,
+            self.application,
+        ))?;
+        if result != 0 {
+            return Err(anyhow!("kwallet failed to write the password ({})", result));
+        }
+
+        Ok(())
+    }
+
+    fn get_password(&self) -> Result<Option<String>> {
+        let handle = self.handle()?;
+        if !self.has_entry(handle)? {
+            return Ok(None);
+        }
+
+        let password = self.caller.call_idempotent(|| {
+            self.proxy
+                .read_password(handle, self.service, self.username, self.application)
+        })?;
+
+        Ok(Some(password))
+    }
+
+    fn delete_password(&self) -> Result<bool> {
+        let handle = self.handle()?;
+        if !self.has_entry(handle)? {
+            return Ok(false);
+        }
+
+        let result = self.caller.call(self.proxy.remove_entry(
+            handle,
+            self.service,
+            self.username,
+            self.application,
+        ))?;
+        if result != 0 {
+            return Err(anyhow!("kwallet failed to remove the entry ({})", result));
+        }
+
+        Ok(true)
+    }
+
+    /// Lists the password entries in every folder of the wallet
+    fn list(&self) -> Result<Vec<Entry>> {
+        let handle = self.handle()?;
+        let mut entries = Vec::new();
+
+        let folders = self
+            .caller
+            .call_idempotent(|| self.proxy.folder_list(handle, self.application))?;
+        for folder in folders {
+            let keys = self
+                .caller
+                .call_idempotent(|| self.proxy.entry_list(handle, &folder, self.application))?;
+            for key in keys {
+                let entry_type = self.caller.call_idempotent(|| {
+                    self.proxy
+                        .entry_type(handle, &folder, &key, self.application)
+                })?;
+                if entry_type == PASSWORD_ENTRY {
+                    entries.push(Entry {
+                        service: folder.clone(),
+                        username: key,
+                    });
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+impl Drop for KWallet<'_> {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            // the daemon closes it when we disconnect anyway
+            let _ = self
+                .caller
+                .call(self.proxy.close(handle, false, self.application));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_bus::PrivateBus;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+    use zbus::dbus_interface;
+
+    // `KWallet::Wallet::EntryType::Map`
+    const MAP_ENTRY: i32 = 3;
+
+    /// A wallet's folders, with the type and value of every entry
+    #[derive(Default)]
+    struct Wallet {
+        folders: BTreeMap<String, BTreeMap<String, (i32, String)>>,
+        opened: Vec<String>,
+    }
+
+    /// Answers like kwalletd, for a single wallet
+    struct StandIn {
+        enabled: bool,
+        open_handle: i32,
+        wallet: Arc<Mutex<Wallet>>,
+    }
+
+    #[dbus_interface(name = "org.kde.KWallet")]
+    impl StandIn {
+        #[dbus_interface(name = "isEnabled")]
+        fn is_enabled(&self) -> bool {
+            self.enabled
+        }
+
+        #[dbus_interface(name = "networkWallet")]
+        fn network_wallet(&self) -> String {
+            "kdewallet".to_string()
+        }
+
+        fn open(&self, wallet: &str, _w_id: i64, _appid: &str) -> i32 {
+            self.wallet.lock().unwrap().opened.push(wallet.to_string());
+            self.open_handle
+        }
+
+        fn close(&self, _handle: i32, _force: bool, _appid: &str) -> i32 {
+            0
+        }
+
+        #[dbus_interface(name = "folderList")]
+        fn folder_list(&self, _handle: i32, _appid: &str) -> Vec<String> {
+            self.wallet
+                .lock()
+                .unwrap()
+                .folders
+                .keys()
+                .cloned()
+                .collect()
+        }
+
+        #[dbus_interface(name = "hasFolder")]
+        fn has_folder(&self, _handle: i32, folder: &str, _appid: &str) -> bool {
+            self.wallet.lock().unwrap().folders.contains_key(folder)
+        }
+
+        #[dbus_interface(name = "createFolder")]
+        fn create_folder(&self, _handle: i32, folder: &str, _appid: &str) -> bool {
+            self.wallet
+                .lock()
+                .unwrap()
+                .folders
+                .insert(folder.to_string(), BTreeMap::new());
+            true
+        }
+
+        #[dbus_interface(name = "entryList")]
+        fn entry_list(&self, _handle: i32, folder: &str, _appid: &str) -> Vec<String> {
+            let wallet = self.wallet.lock().unwrap();
+            wallet
+                .folders
+                .get(folder)
+                .map(|entries| entries.keys().cloned().collect())
+                .unwrap_or_default()
+        }
+
+        #[dbus_interface(name = "hasEntry")]
+        fn has_entry(&self, _handle: i32, folder: &str, key: &str, _appid: &str) -> bool {
+            self.entry(folder, key).is_some()
+        }
+
+        #[dbus_interface(name = "entryType")]
+        fn entry_type(&self, _handle: i32, folder: &str, key: &str, _appid: &str) -> i32 {
+            self.entry(folder, key).map_or(0, |(kind, _)| kind)
+        }
+
+        #[dbus_interface(name = "readPassword")]
+        fn read_password(&self, _handle: i32, folder: &str, key: &str, _appid: &str) -> String {
+            self.entry(folder, key).unwrap_or_default().1
+        }
+
+        #[dbus_interface(name = "writePassword")]
+        fn write_password(
+            &self,
+            _handle: i32,
+            folder: &str,
+            key: &str,
+            value: &str,
+            _appid: &str,
+        ) -> i32 {
+            match self.wallet.lock().unwrap().folders.get_mut(folder) {
+                Some(entries) => {
+                    entries.insert(key.to_string(), (PASSWORD_ENTRY, value.to_string()));
+                    0
+                }
+                None => -1,
+            }
+        }
+
+        #[dbus_interface(name = "removeEntry")]
+        fn remove_entry(&self, _handle: i32, folder: &str, key: &str, _appid: &str) -> i32 {
+            let mut wallet = self.wallet.lock().unwrap();
+            match wallet
+                .folders
+                .get_mut(folder)
+                .and_then(|entries| entries.remove(key))
+            {
+                Some(_) => 0,
+                None => -1,
+            }
+        }
+    }
+
+    impl StandIn {
+        fn entry(&self, folder: &str, key: &str) -> Option<(i32, String)> {
+            self.wallet
+                .lock()
+                .unwrap()
+                .folders
+                .get(folder)?
+                .get(key)
+                .cloned()
+        }
+    }
+
+    /// Serves a stand-in as `daemon`, one of [`DAEMONS`]
+    fn serve(bus: &PrivateBus, daemon: usize, stand_in: StandIn) -> Connection {
+        let (name, path) = DAEMONS[daemon];
+        bus.connect()
+            .name(name)
+            .unwrap()
+            .serve_at(path, stand_in)
+            .unwrap()
+            .build()
+            .unwrap()
+    }
+
+    fn kwallet<'a>(bus: &PrivateBus, username: &'a str, service: &'a str) -> Result<KWallet<'a>> {
+        KWallet::on_connection(
+            username,
+            service,
+            "kwallet-tests",
+            Options::default(),
+            bus.connect().build()?,
+        )
+    }
+
+    fn stand_in(wallet: &Arc<Mutex<Wallet>>) -> StandIn {
+        StandIn {
+            enabled: true,
+            open_handle: 7,
+            wallet: wallet.clone(),
+        }
+    }
+
+    #[test]
+    fn falls_back_to_kwalletd5() {
+        let bus = match PrivateBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+        let kwalletd6 = Arc::new(Mutex::new(Wallet::default()));
+        let kwalletd5 = Arc::new(Mutex::new(Wallet::default()));
+        let _kwalletd6 = serve(
+            &bus,
+            0,
+            StandIn {
+                enabled: false,
+                ..stand_in(&kwalletd6)
+            },
+        );
+        let _kwalletd5 = serve(&bus, 1, stand_in(&kwalletd5));
+
+        let wallet = kwallet(&bus, "alice", "example.com").unwrap();
+        wallet.set_password("secret".to_string()).unwrap();
+
+        assert!(kwalletd6.lock().unwrap().folders.is_empty());
+        assert_eq!(kwalletd5.lock().unwrap().opened, vec!["kdewallet"]);
+        assert!(kwalletd5
+            .lock()
+            .unwrap()
+            .folders
+            .contains_key("example.com"));
+    }
+
+    #[test]
+    fn falls_back_when_kwalletd6_is_missing() {
+        let bus = match PrivateBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+        let state = Arc::new(Mutex::new(Wallet::default()));
+        let _kwalletd5 = serve(&bus, 1, stand_in(&state));
+
+        let wallet = kwallet(&bus, "alice", "example.com").unwrap();
+        wallet.set_password("secret".to_string()).unwrap();
+        assert_eq!(wallet.get_password().unwrap().as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn no_daemon() {
+        let bus = match PrivateBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+
+        let err = kwallet(&bus, "alice", "example.com").err().unwrap();
+        assert!(err.to_string().contains("no kwallet daemon"), "{:#}", err);
+    }
+
+    #[test]
+    fn profiles_are_unsupported() {
+        let bus = match PrivateBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+
+        let options = Options {
+            profile: Some("staging".to_string()),
+            ..Options::default()
+        };
+        let connection = bus.connect().build().unwrap();
+        let err = KWallet::on_connection("alice", "example.com", "app", options, connection)
+            .err()
+            .unwrap();
+        assert!(
+            matches!(err.downcast_ref(), Some(crate::Error::Unsupported { .. })),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn open_fails() {
+        let bus = match PrivateBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+        let wallet = Arc::new(Mutex::new(Wallet::default()));
+        let _daemon = serve(
+            &bus,
+            0,
+            StandIn {
+                open_handle: -1,
+                ..stand_in(&wallet)
+            },
+        );
+
+        let err = kwallet(&bus, "alice", "example.com")
+            .unwrap()
+            .get_password()
+            .unwrap_err();
+        assert!(err.to_string().contains("couldn't be opened"), "{:#}", err);
+    }
+
+    #[test]
+    fn round_trip() {
+        let bus = match PrivateBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+        let state = Arc::new(Mutex::new(Wallet::default()));
+        let _daemon = serve(&bus, 0, stand_in(&state));
+        let wallet = kwallet(&bus, "alice", "example.com")
+            .unwrap()
+            .with_wallet("work");
+
+        assert_eq!(wallet.get_password().unwrap(), None);
+        assert!(!wallet.delete_password().unwrap());
+        wallet.set_password("secret".to_string()).unwrap();
+        assert_eq!(wallet.get_password().unwrap().as_deref(), Some("secret"));
+        // the folder is created once, the wallet opened once
+        wallet.set_password("changed".to_string()).unwrap();
+        assert_eq!(wallet.get_password().unwrap().as_deref(), Some("changed"));
+        assert_eq!(state.lock().unwrap().opened, vec!["work"]);
+
+        assert!(wallet.delete_password().unwrap());
+        assert_eq!(wallet.get_password().unwrap(), None);
+    }
+
+    #[test]
+    fn lists_only_passwords() {
+        let bus = match PrivateBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+        let state = Arc::new(Mutex::new(Wallet::default()));
+        let _daemon = serve(&bus, 0, stand_in(&state));
+        kwallet(&bus, "alice", "example.com")
+            .unwrap()
+            .set_password("secret".to_string())
+            .unwrap();
+        state
+            .lock()
+            .unwrap()
+            .folders
+            .entry("example.com".to_string())
+            .or_default()
+            .insert("settings".to_string(), (MAP_ENTRY, String::new()));
+
+        let entries = kwallet(&bus, "bob", "other.com").unwrap().list().unwrap();
+        assert_eq!(
+            entries,
+            vec![Entry {
+                service: "example.com".to_string(),
+                username: "alice".to_string(),
+            }]
+        );
+    }
+}
@@ -0,0 +1,65 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use zbus::dbus_proxy;
+
+/// https://invent.kde.org/frameworks/kwallet/-/blob/master/src/api/KWallet/org.kde.KWallet.xml
+#[dbus_proxy(
+    interface = "org.kde.KWallet",
+    default_service = "org.kde.kwalletd6",
+    default_path = "/modules/kwalletd6"
+)]
+trait KWallet {
+    #[dbus_proxy(name = "isEnabled")]
+    fn is_enabled(&self) -> zbus::Result<bool>;
+
+    #[dbus_proxy(name = "networkWallet")]
+    fn network_wallet(&self) -> zbus::Result<String>;
+
+    fn open(&self, wallet: &str, w_id: i64, appid: &str) -> zbus::Result<i32>;
+
+    fn close(&self, handle: i32, force: bool, appid: &str) -> zbus::Result<i32>;
+
+    #[dbus_proxy(name = "folderList")]
+    fn folder_list(&self, handle: i32, appid: &str) -> zbus::Result<Vec<String>>;
+
+    #[dbus_proxy(name = "hasFolder")]
+    fn has_folder(&self, handle: i32, folder: &str, appid: &str) -> zbus::Result<bool>;
+
+    #[dbus_proxy(name = "createFolder")]
+    fn create_folder(&self, handle: i32, folder: &str, appid: &str) -> zbus::Result<bool>;
+
+    #[dbus_proxy(name = "entryList")]
+    fn entry_list(&self, handle: i32, folder: &str, appid: &str) -> zbus::Result<Vec<String>>;
+
+    #[dbus_proxy(name = "hasEntry")]
+    fn has_entry(&self, handle: i32, folder: &str, key: &str, appid: &str) -> zbus::Result<bool>;
+
+    #[dbus_proxy(name = "entryType")]
+    fn entry_type(&self, handle: i32, folder: &str, key: &str, appid: &str) -> zbus::Result<i32>;
+
+    #[dbus_proxy(name = "readPassword")]
+    fn read_password(
+        &self,
+        handle: i32,
+        folder: &str,
+        key: &str,
+        appid: &str,
+    ) -> zbus::Result<String>;
+
+    #[dbus_proxy(name = "writePassword")]
+    fn write_password(
+        &self,
+        handle: i32,
+        folder: &str,
+        key: &str,
+        value: &str,
+        appid: &str,
+    ) -> zbus::Result<i32>;
+
+    #[dbus_proxy(name = "removeEntry")]
+    fn remove_entry(&self, handle: i32, folder: &str, key: &str, appid: &str) -> zbus::Result<i32>;
+}
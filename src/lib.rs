@@ -5,6 +5,35 @@
  */
 
 use anyhow::Result;
+use std::collections::HashMap;
+#[cfg(target_os = "linux")]
+use std::thread::JoinHandle;
+
+// selects a registered backend by name, see `register_backend`
+pub(crate) const BACKEND_VARIABLE: &str = "KEYRING_BACKEND";
+
+#[macro_use]
+mod trace;
+
+mod backend;
+mod cache;
+mod chain;
+pub mod compat;
+mod config;
+mod env;
+mod error;
+mod file;
+mod keepass;
+mod mock;
+mod options;
+mod pass;
+mod purge;
+mod registry;
+mod schema;
+mod search;
+mod systemd;
+#[cfg(feature = "vault")]
+mod vault;
 
 #[cfg(target_os = "linux")]
 mod secret_service;
@@ -12,9 +41,58 @@ mod secret_service;
 #[cfg(target_os = "linux")]
 mod linux;
 
+#[cfg(target_os = "linux")]
+mod gnome_keyring;
+
+#[cfg(target_os = "linux")]
+mod keyutils;
+
+#[cfg(target_os = "linux")]
+mod kwallet;
+
+#[cfg(target_os = "linux")]
+mod portal;
+
+#[cfg(all(test, target_os = "linux"))]
+mod test_bus;
+
 #[cfg(target_os = "linux")]
 use linux::Keyring;
 
+pub use backend::{Backend, Entry};
+pub use cache::CachedKeyringClient;
+pub use chain::{ChainBuilder, ChainKeyring, Fallback};
+pub use config::Config;
+pub use env::EnvKeyring;
+pub use error::Error;
+pub use file::FileKeyring;
+pub use keepass::{KeePassKeyring, KeePassMapping};
+pub use mock::MockKeyring;
+pub use options::{AttributeLayout, Options, PromptPolicy, RetryPolicy};
+pub use pass::{PassEntry, PassKeyring};
+pub use purge::{PurgeOptions, PurgedItem};
+pub use registry::{backend_names, force_backend, register_backend, BackendTarget};
+pub use schema::{AttributeType, Schema};
+pub use search::{FoundItem, SearchScope};
+pub use systemd::{CredentialNames, SystemdCredentials};
+#[cfg(feature = "vault")]
+pub use vault::{VaultKeyring, VaultVersion};
+
+#[cfg(target_os = "linux")]
+pub use gnome_keyring::{GnomeKeyringFile, GnomeKeyringItem};
+
+#[cfg(target_os = "linux")]
+pub use keyutils::{KernelKeyring, KeyringTarget};
+
+#[cfg(target_os = "linux")]
+pub use kwallet::KWallet;
+
+#[cfg(target_os = "linux")]
+pub use portal::PortalKeyring;
+
+#[cfg(target_os = "linux")]
+pub use secret_service::watch::{ChangeEvent, ChangeKind, Watcher};
+
 #[cfg(target_os = "macos")]
 mod mac;
 
@@ -35,9 +113,12 @@ use win::Keyring;
 /// ## Keyring Backends:
 /// * Windows - [wincred](https://docs.microsoft.com/en-us/windows/win32/api/wincred/)
 /// * Linux - [Secret Service](https://specifications.freedesktop.org/secret-service/latest/)
+/// * Flatpak - [Secret portal](https://flatpak.github.io/xdg-desktop-portal/docs/doc-org.freedesktop.portal.Secret.html), with a libsecret-compatible keyring file
 /// * Mac - [Security Framework](https://developer.apple.com/documentation/security)
+///
+/// Other backends, like [`FileKeyring`], can be used through [`KeyringClient::with_backend`].
 pub struct KeyringClient<'a> {
-    client: Keyring<'a>,
+    client: Box<dyn Backend + 'a>,
 }
 
 impl<'a> KeyringClient<'a> {
@@ -49,9 +130,144 @@ impl<'a> KeyringClient<'a> {
     /// * `service` - A unique identifier within your application
     /// * `application` - The name of your application
     pub fn new(username: &'a str, service: &'a str, application: &'a str) -> Result<Self> {
-        let client = Keyring::new(username, service, application)?;
+        Self::with_options(username, service, application, Options::default())
+    }
+
+    /// Returns a new keyring client with custom settings
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username to store secrets under
+    /// * `service` - A unique identifier within your application
+    /// * `application` - The name of your application
+    /// * `options` - Settings for the client, see [`Options`]
+    ///
+    /// The backend is the one passed to [`force_backend`], or the one named by `KEYRING_BACKEND`,
+    /// or else the OS keyring. When `KEYRING_ENV_OVERRIDE` is set, secrets are read from
+    /// environment variables first, see [`EnvKeyring`].
+    pub fn with_options(
+        username: &'a str,
+        service: &'a str,
+        application: &'a str,
+        options: Options,
+    ) -> Result<Self> {
+        Self::select(None, username, service, application, options)
+    }
+
+    /// Returns a new keyring client configured by the application's `keyring.toml` and the
+    /// environment
+    ///
+    /// Like [`KeyringClient::new`], but the backend, label, collection, timeouts and the rest
+    /// come from [`Config::load`]. [`force_backend`] still takes precedence.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username to store secrets under
+    /// * `service` - A unique identifier within your application
+    /// * `application` - The name of your application
+    pub fn from_env(username: &'a str, service: &'a str, application: &'a str) -> Result<Self> {
+        Config::load(application)?.client(username, service, application)
+    }
+
+    /// Returns a new keyring client using the backend registered as `name`
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The backend, see [`register_backend`]
+    /// * `username` - The username to store secrets under
+    /// * `service` - A unique identifier within your application
+    /// * `application` - The name of your application
+    /// * `options` - Settings for the client, see [`Options`]
+    pub fn with_backend_name(
+        name: &str,
+        username: &'a str,
+        service: &'a str,
+        application: &'a str,
+        options: Options,
+    ) -> Result<Self> {
+        let target = BackendTarget {
+            username,
+            service,
+            application,
+            options,
+        };
+
+        Ok(KeyringClient {
+            client: registry::create(name, &target)?,
+        })
+    }
+
+    /// Creates the forced backend, the one in `KEYRING_BACKEND`, the `configured` one or the OS
+    /// keyring, with the environment variable overlay on top
+    pub(crate) fn select(
+        configured: Option<String>,
+        username: &'a str,
+        service: &'a str,
+        application: &'a str,
+        options: Options,
+    ) -> Result<Self> {
+        let name = registry::forced_backend()
+            .or_else(|| {
+                std::env::var(BACKEND_VARIABLE)
+                    .ok()
+                    .filter(|name| !name.is_empty())
+            })
+            .or(configured);
+        let profile = options.profile.clone();
+        let client = match name {
+            Some(name) => Self::with_backend_name(&name, username, service, application, options),
+            None => Self::platform(username, service, application, options),
+        };
 
-        Ok(KeyringClient { client })
+        Self::overlay(client, username, service, profile.as_deref())
+    }
+
+    /// Puts the environment variable overlay on top of `client` when `KEYRING_ENV_OVERRIDE` is set
+    pub(crate) fn overlay(
+        client: Result<Self>,
+        username: &'a str,
+        service: &'a str,
+        profile: Option<&str>,
+    ) -> Result<Self> {
+        if let Some(env) = EnvKeyring::from_env(username, service, profile)? {
+            return Ok(Self::with_backend(env.overlay(client)));
+        }
+
+        client
+    }
+
+    /// Returns a client for the OS keyring, or the Secret portal inside a Flatpak sandbox
+    fn platform(
+        username: &'a str,
+        service: &'a str,
+        application: &'a str,
+        options: Options,
+    ) -> Result<Self> {
+        #[cfg(target_os = "linux")]
+        if PortalKeyring::sandboxed() {
+            let client = PortalKeyring::new(username, service, application, options)?;
+            return Ok(Self::with_backend(client));
+        }
+
+        let client = Keyring::new(username, service, application, options)?;
+
+        Ok(Self::with_backend(client))
+    }
+
+    /// Returns a keyring client storing secrets in the given backend
+    ///
+    /// # Arguments
+    ///
+    /// * `backend` - Where to store secrets, e.g. a [`FileKeyring`]
+    pub fn with_backend<B: Backend + 'a>(backend: B) -> Self {
+        KeyringClient {
+            client: Box::new(backend),
+        }
+    }
+
+    /// The name of the backend storing the secrets, e.g. `secret-service`
+    pub fn backend_name(&self) -> &'static str {
+        self.client.name()
     }
 
     pub fn set_password(&self, password: String) -> Result<()> {
@@ -61,4 +277,192 @@ impl<'a> KeyringClient<'a> {
     pub fn get_password(&self) -> Result<Option<String>> {
         self.client.get_password()
     }
+
+    /// Removes the secret, returns whether there was one to remove
+    pub fn delete_password(&self) -> Result<bool> {
+        self.client.delete_password()
+    }
+
+    /// Lists the entries the backend stores for the application
+    pub fn list(&self) -> Result<Vec<Entry>> {
+        self.client.list()
+    }
+
+    /// Lists the profiles the application has secrets in, see [`Options::profile`]
+    pub fn profiles(&self) -> Result<Vec<String>> {
+        self.client.profiles()
+    }
+
+    /// Removes the items the application stored, in every collection, and returns them
+    ///
+    /// Only items with the client's [`Options::attributes`] are removed. In the
+    /// [`PythonKeyring`](AttributeLayout::PythonKeyring) and
+    /// [`KeyringCrate`](AttributeLayout::KeyringCrate) layouts other applications store items
+    /// with the same `application`, so the Secret Service fails unless
+    /// [`PurgeOptions::service`] is set. Locked items are unlocked first, which may prompt the
+    /// user. A chain purges all of its backends, when one of them fails after others were purged
+    /// the removed items are returned in [`Error::PartialPurge`].
+    ///
+    /// # Arguments
+    ///
+    /// * `options` - Which items to remove and whether to only report them, see [`PurgeOptions`]
+    pub fn purge(&self, options: &PurgeOptions) -> Result<Vec<PurgedItem>> {
+        self.client.purge(options)
+    }
+
+    /// Copies the stored secret into another collection, keeping its label and attributes
+    ///
+    /// Fails when the collection already holds the secret, instead of replacing it there. When
+    /// one of several items can't be copied, the copies already made are removed again. Locked
+    /// items are unlocked first, which may prompt the user. Returns whether there was a secret to
+    /// copy.
+    ///
+    /// # Arguments
+    ///
+    /// * `collection` - An alias like `default` or `session`, or an object path
+    pub fn copy_to(&self, collection: &str) -> Result<bool> {
+        self.client.copy_to(collection)
+    }
+
+    /// Moves the stored secret into another collection, e.g. from `session` to `default` when
+    /// the user asks to be remembered
+    ///
+    /// The secret is copied first and only removed from its collection once the copy exists, so
+    /// it stays where it was when the copy fails. Like [`KeyringClient::copy_to`], it fails when
+    /// the collection already holds the secret. Returns whether there was a secret to move.
+    ///
+    /// # Arguments
+    ///
+    /// * `collection` - An alias like `default` or `session`, or an object path
+    pub fn move_to(&self, collection: &str) -> Result<bool> {
+        self.client.move_to(collection)
+    }
+
+    /// Changes the label of the stored secret, returns whether there was one to relabel
+    pub fn relabel(&self, label: &str) -> Result<bool> {
+        self.client.relabel(label)
+    }
+
+    /// Finds the items that have all of `attributes`, of any application
+    ///
+    /// The attributes are matched as given, they aren't checked against [`Options::schema`] and
+    /// `xdg:schema` is only matched when it's one of them. The secrets of the items are only read
+    /// when asked for, see [`FoundItem::secret`].
+    ///
+    /// # Arguments
+    ///
+    /// * `attributes` - The attributes to match, e.g. `service` and `username`
+    /// * `scope` - Whether to search the client's collection or all of them
+    pub fn search(
+        &self,
+        attributes: &HashMap<&str, &str>,
+        scope: SearchScope,
+    ) -> Result<Vec<FoundItem<'_>>> {
+        self.client.search(attributes, scope)
+    }
+
+    /// Returns when the stored secret was last modified, in seconds since the unix epoch
+    ///
+    /// Returns `None` when there is no secret or the backend doesn't track modification times.
+    pub fn modified(&self) -> Result<Option<u64>> {
+        self.client.modified()
+    }
+
+    /// Returns a blocking iterator over changes to the secret stored by this client
+    ///
+    /// Fires when another program creates, changes, or deletes an item matching this client's
+    /// service and application.
+    #[cfg(target_os = "linux")]
+    pub fn watch(&self) -> Result<Watcher> {
+        self.client.watch()
+    }
+
+    /// Calls `callback` on a background thread for every change to the secret stored by this client
+    ///
+    /// The thread runs until the connection to the keyring is closed. Errors for single changes,
+    /// e.g. an item deleted before it could be read, are skipped.
+    ///
+    /// # Arguments
+    ///
+    /// * `callback` - Called with every change, see [`KeyringClient::watch`]
+    #[cfg(target_os = "linux")]
+    pub fn on_change<F>(&self, mut callback: F) -> Result<JoinHandle<Result<()>>>
+    where
+        F: FnMut(ChangeEvent) + Send + 'static,
+    {
+        let watcher = self.watch()?;
+
+        Ok(std::thread::spawn(move || {
+            for event in watcher {
+                match event {
+                    Ok(event) => callback(event),
+                    Err(_err) => {
+                        event!(warn, "skipping a change that couldn't be read: {:#}", _err);
+                    }
+                }
+            }
+
+            Ok(())
+        }))
+    }
+}
+
+/// A client is a backend too, so the OS keyring can be part of a [`ChainKeyring`].
+impl Backend for KeyringClient<'_> {
+    fn name(&self) -> &'static str {
+        self.client.name()
+    }
+
+    fn set_password(&self, password: String) -> Result<()> {
+        self.client.set_password(password)
+    }
+
+    fn get_password(&self) -> Result<Option<String>> {
+        self.client.get_password()
+    }
+
+    fn delete_password(&self) -> Result<bool> {
+        self.client.delete_password()
+    }
+
+    fn list(&self) -> Result<Vec<Entry>> {
+        self.client.list()
+    }
+
+    fn profiles(&self) -> Result<Vec<String>> {
+        self.client.profiles()
+    }
+
+    fn search(
+        &self,
+        attributes: &HashMap<&str, &str>,
+        scope: SearchScope,
+    ) -> Result<Vec<FoundItem<'_>>> {
+        self.client.search(attributes, scope)
+    }
+
+    fn purge(&self, options: &PurgeOptions) -> Result<Vec<PurgedItem>> {
+        self.client.purge(options)
+    }
+
+    fn copy_to(&self, collection: &str) -> Result<bool> {
+        self.client.copy_to(collection)
+    }
+
+    fn move_to(&self, collection: &str) -> Result<bool> {
+        self.client.move_to(collection)
+    }
+
+    fn relabel(&self, label: &str) -> Result<bool> {
+        self.client.relabel(label)
+    }
+
+    fn modified(&self) -> Result<Option<u64>> {
+        self.client.modified()
+    }
+
+    #[cfg(target_os = "linux")]
+    fn watch(&self) -> Result<Watcher> {
+        self.client.watch()
+    }
 }
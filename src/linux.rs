@@ -4,72 +4,564 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+use crate::backend::Backend;
+use crate::error::Error;
+use crate::options::{Options, PROFILE_ATTRIBUTE};
+use crate::purge::{PurgeOptions, PurgedItem};
+use crate::search::{FoundItem, SearchScope};
+use crate::secret_service::call::Caller;
 use crate::secret_service::collection::Collection;
+use crate::secret_service::item::Item;
 use crate::secret_service::session::Session;
-use anyhow::{anyhow, Result};
-use std::collections::HashMap;
+use crate::secret_service::watch::Watcher;
+use anyhow::{anyhow, Context, Result};
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use zvariant::OwnedObjectPath;
 
 pub struct Keyring<'a> {
     username: &'a str,
     service: &'a str,
     application: &'a str,
+    options: Options,
     session: Session<'a>,
+    collection_path: RefCell<Option<String>>,
 }
 
 impl<'a> Keyring<'a> {
-    pub fn new(username: &'a str, service: &'a str, application: &'a str) -> Result<Self> {
-        let session = Session::new()?;
+    pub fn new(
+        username: &'a str,
+        service: &'a str,
+        application: &'a str,
+        options: Options,
+    ) -> Result<Self> {
+        let session = Session::new(Caller::new(&options))
+            .context("failed to open a secret service session")?;
 
         Ok(Self {
             username,
             service,
             application,
+            options,
             session,
+            collection_path: RefCell::new(None),
         })
     }
 
-    pub fn set_password(&self, password: String) -> Result<()> {
-        let collection = self.default_collection()?;
+    /// Runs `f`, which only reads, reconnecting and retrying it once if the session was lost
+    fn call<T, F>(&self, f: F) -> Result<T>
+    where
+        F: Fn() -> Result<T>,
+    {
+        let err = match f() {
+            Ok(result) => return Ok(result),
+            Err(err) => err,
+        };
+        let lost = match Error::session_lost(&err) {
+            Some(lost) => lost,
+            None => return Err(err),
+        };
+        if !self.options.reconnect {
+            return Err(err.context(lost));
+        }
+
+        if let Err(err) = self.reconnect(&lost) {
+            return Err(err.context(Error::Reconnect(Box::new(lost))));
+        }
+
+        f().map_err(|err| err.context(Error::Reconnect(Box::new(lost))))
+    }
+
+    /// Runs `f`, which changes the keyring, once
+    ///
+    /// A lost session is reopened for later calls, but `f` isn't retried, the service may have
+    /// done what it asked before the session was lost and doing it twice could e.g. store an item
+    /// twice.
+    fn call_once<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T>,
+    {
+        let err = match f() {
+            Ok(result) => return Ok(result),
+            Err(err) => err,
+        };
+        let lost = match Error::session_lost(&err) {
+            Some(lost) => lost,
+            None => return Err(err),
+        };
+        if self.options.reconnect {
+            // the error is the call's, reconnecting only helps the next one
+            let _ = self.reconnect(&lost);
+        }
 
-        let mut attributes: HashMap<&str, &str> = HashMap::new();
-        attributes.insert("application", self.application);
-        attributes.insert("service", self.service);
-        let label = format!("Password for {}: {}", self.service, self.username);
-        collection.create_item(password, label, attributes)?;
+        Err(err.context(lost))
+    }
+
+    fn reconnect(&self, lost: &Error) -> Result<()> {
+        report!(warn, "secret service session lost ({}), reconnecting", lost);
+        if let Err(err) = self.session.reconnect() {
+            report!(warn, "failed to reconnect to the secret service: {:#}", err);
+            return Err(err);
+        }
+        self.collection_path.replace(None);
+        report!(info, "reconnected to the secret service");
 
         Ok(())
     }
 
-    pub fn get_password(&self) -> Result<Option<String>> {
-        let collection = self.default_collection()?;
+    fn attributes(&self) -> HashMap<&str, &str> {
+        self.options
+            .lookup_attributes(self.application, self.service, self.username)
+    }
 
-        let mut attributes: HashMap<&str, &str> = HashMap::new();
-        attributes.insert("application", self.application);
-        attributes.insert("service", self.service);
-        let collection = collection.search(attributes.clone())?;
-        if collection.is_empty() {
-            return Ok(None);
+    /// The items of the entry, leaving out those of other profiles
+    fn matching_paths(&self, collection: &Collection<'_>) -> Result<Vec<OwnedObjectPath>> {
+        let paths = collection.search_paths(&self.options.schema, self.attributes())?;
+        if self.options.profile.is_some() {
+            return Ok(paths);
         }
 
-        let secret = collection
-            .get(0)
-            .ok_or_else(|| anyhow!("could not get secret"))?
-            .secret(self.session.aes_key())?;
+        let mut matching = Vec::new();
+        for path in paths {
+            if self
+                .options
+                .in_profile(&collection.item(&path)?.attributes()?)
+            {
+                matching.push(path);
+            }
+        }
+
+        Ok(matching)
+    }
 
-        Ok(Some(secret))
+    /// The paths of the entry's items in the client's collection
+    fn entry_paths(&self) -> Result<Vec<OwnedObjectPath>> {
+        self.call(|| self.matching_paths(&self.default_collection()?))
     }
 
-    fn default_collection(&self) -> Result<Collection> {
-        let path = self.session.secrets_proxy().read_alias("default")?;
-        let path_str = path.as_str().to_string();
+    fn item(&self, path: &OwnedObjectPath) -> Result<Item<'_>> {
+        Item::new(
+            self.session.connection(),
+            self.session.session_path(),
+            path.as_str().to_string(),
+            self.session.caller(),
+        )
+    }
+
+    /// Reads the metadata of a found item and defers reading its secret
+    fn found(&self, path: OwnedObjectPath, locked: bool) -> Result<FoundItem<'_>> {
+        let item = self.item(&path)?;
+        // services may not show what's in a locked item
+        let (label, attributes) = match (item.label(), item.attributes()) {
+            (Ok(label), Ok(attributes)) => (label, attributes),
+            (Err(err), _) | (_, Err(err)) if !locked => return Err(err),
+            _ => (String::new(), HashMap::new()),
+        };
+
+        Ok(FoundItem::new(
+            label,
+            attributes,
+            item.created().unwrap_or_default(),
+            item.modified().unwrap_or_default(),
+            locked,
+            move || self.call(|| self.read_secret(&path)),
+        ))
+    }
+
+    /// Reads the secret of an item, unlocking it first if needed
+    fn read_secret(&self, path: &OwnedObjectPath) -> Result<String> {
+        let item = self.item(path)?;
+        if item.locked()? {
+            self.session.unlock(std::slice::from_ref(path))?;
+        }
+
+        item.secret()?.secret(self.session.aes_key())
+    }
+
+    fn default_collection(&self) -> Result<Collection<'_>> {
+        self.collection(self.default_collection_path()?)
+    }
+
+    fn collection(&self, path_str: String) -> Result<Collection<'_>> {
         let connection = self.session.connection();
         let collection_client = Collection::new(
             connection,
             self.session.session_path(),
             self.session.aes_key(),
             path_str,
+            self.session.caller(),
         )?;
 
         Ok(collection_client)
     }
+
+    /// Resolves the configured collection once and reuses it for later calls
+    fn default_collection_path(&self) -> Result<String> {
+        if let Some(path) = self.collection_path.borrow().as_ref() {
+            return Ok(path.clone());
+        }
+
+        let path_str = self.collection_path(&self.options.collection)?;
+        self.collection_path.replace(Some(path_str.clone()));
+
+        Ok(path_str)
+    }
+
+    /// The path of a collection given by an alias like `default` or by its path
+    fn collection_path(&self, collection: &str) -> Result<String> {
+        if collection.starts_with('/') {
+            return Ok(collection.to_string());
+        }
+
+        let path = self.session.read_alias(collection)?;
+        // the service answers `/` for aliases it doesn't know
+        if path.as_str() == "/" {
+            return Err(anyhow!("no collection has the alias {:?}", collection));
+        }
+
+        Ok(path.as_str().to_string())
+    }
+
+    /// Copies the entry's items into `collection`, then removes the originals when `remove`
+    ///
+    /// Every item is copied before any original is removed, so the originals stay when a copy
+    /// fails, and the copies already made are removed again. Fails without copying anything when
+    /// `collection` already holds an item of the entry, which a copy would replace.
+    fn transfer(&self, collection: &str, remove: bool) -> Result<bool> {
+        // finding the items is safe to repeat after reconnecting
+        let (paths, target_path) = self.call(|| {
+            let source = self.default_collection()?;
+            let paths = self.matching_paths(&source)?;
+            let target_path = self.collection_path(collection)?;
+            // copying an item onto itself would replace it, and moving would then remove it
+            if paths.is_empty() || source.path().as_str() == target_path {
+                return Ok((paths, None));
+            }
+
+            let existing = self.matching_paths(&self.collection(target_path.clone())?)?;
+            if !existing.is_empty() {
+                return Err(anyhow!(
+                    "{:?} already holds {} item(s) of the entry",
+                    collection,
+                    existing.len()
+                ));
+            }
+
+            // the secrets of locked items can't be read, unlock them all with one prompt
+            let mut locked = Vec::new();
+            for path in &paths {
+                if source.item(path)?.locked()? {
+                    locked.push(path.clone());
+                }
+            }
+            if !locked.is_empty() {
+                self.session.unlock(&locked)?;
+            }
+
+            Ok((paths, Some(target_path)))
+        })?;
+        let target_path = match target_path {
+            Some(target_path) => target_path,
+            None => return Ok(!paths.is_empty()),
+        };
+
+        // creating the copies again after reconnecting could leave duplicates behind
+        self.call_once(|| {
+            let source = self.default_collection()?;
+            let target = self.collection(target_path)?;
+            let mut copies = Vec::new();
+            for path in &paths {
+                match source.item(path).and_then(|item| target.copy_item(&item)) {
+                    Ok(copy) => copies.push(copy),
+                    Err(err) => {
+                        self.remove_copies(&target, &copies);
+                        return Err(
+                            err.context(format!("failed to copy the items to {:?}", collection))
+                        );
+                    }
+                }
+                event!(debug, item = path.as_str(), "copied item");
+            }
+            if remove {
+                for path in &paths {
+                    source.item(path)?.delete().with_context(|| {
+                        format!(
+                            "copied the items to {:?}, but failed to remove the originals",
+                            collection
+                        )
+                    })?;
+                }
+            }
+
+            Ok(true)
+        })
+    }
+
+    /// Removes the copies of a transfer that failed, a copy that can't be removed is logged
+    fn remove_copies(&self, target: &Collection<'_>, copies: &[OwnedObjectPath]) {
+        for copy in copies {
+            if let Err(err) = target.item(copy).and_then(|item| item.delete()) {
+                report!(
+                    warn,
+                    "failed to remove the copy {}: {:#}",
+                    copy.as_str(),
+                    err
+                );
+            }
+        }
+    }
+}
+
+impl Backend for Keyring<'_> {
+    fn name(&self) -> &'static str {
+        "secret-service"
+    }
+
+    fn set_password(&self, password: String) -> Result<()> {
+        span!(
+            "set_password",
+            service = self.service,
+            application = self.application
+        );
+
+        let stale = self.entry_paths()?;
+        self.call_once(|| {
+            let collection = self.default_collection()?;
+
+            let label = self
+                .options
+                .item_label(self.application, self.service, self.username);
+            let created = collection.create_item(
+                password,
+                label,
+                &self.options.schema,
+                self.options
+                    .stored_attributes(self.application, self.service, self.username),
+            )?;
+            // the service only replaces items with the same attributes, so copies stored without
+            // `xdg:schema` or by other clients are left over
+            for path in stale.iter().filter(|path| **path != created) {
+                event!(
+                    debug,
+                    item = path.as_str(),
+                    "removing an older copy of the item"
+                );
+                collection.item(path)?.delete()?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn get_password(&self) -> Result<Option<String>> {
+        span!(
+            "get_password",
+            service = self.service,
+            application = self.application
+        );
+
+        self.call(|| {
+            let collection = self.default_collection()?;
+
+            let paths = self.matching_paths(&collection)?;
+            let path = match paths.first() {
+                Some(path) => path,
+                None => {
+                    event!(debug, "no item matches, there is no password");
+                    return Ok(None);
+                }
+            };
+
+            let secret = collection
+                .item(path)?
+                .secret()?
+                .secret(self.session.aes_key())?;
+
+            Ok(Some(secret))
+        })
+    }
+
+    fn delete_password(&self) -> Result<bool> {
+        span!(
+            "delete_password",
+            service = self.service,
+            application = self.application
+        );
+
+        let paths = self.entry_paths()?;
+        self.call_once(|| {
+            let collection = self.default_collection()?;
+            for path in &paths {
+                collection.item(path)?.delete()?;
+            }
+
+            Ok(!paths.is_empty())
+        })
+    }
+
+    fn search(
+        &self,
+        attributes: &HashMap<&str, &str>,
+        scope: SearchScope,
+    ) -> Result<Vec<FoundItem<'_>>> {
+        span!("search", ?scope);
+
+        // the attributes aren't checked against our schema, other applications' items have others
+        let paths = self.call(|| match scope {
+            SearchScope::Collection => {
+                let collection = self.default_collection()?;
+                let mut paths = Vec::new();
+                for path in collection.search_items(attributes.clone())? {
+                    let locked = collection.item(&path)?.locked()?;
+                    paths.push((path, locked));
+                }
+
+                Ok(paths)
+            }
+            SearchScope::AllCollections => {
+                let (unlocked, locked) = self.session.search_items(attributes.clone())?;
+
+                Ok(unlocked
+                    .into_iter()
+                    .map(|path| (path, false))
+                    .chain(locked.into_iter().map(|path| (path, true)))
+                    .collect())
+            }
+        })?;
+
+        self.call(|| {
+            paths
+                .iter()
+                .map(|(path, locked)| self.found(path.clone(), *locked))
+                .collect()
+        })
+    }
+
+    fn purge(&self, options: &PurgeOptions) -> Result<Vec<PurgedItem>> {
+        span!(
+            "purge",
+            application = self.application,
+            dry_run = options.dry_run
+        );
+
+        let attributes = self.options.purge_attributes(self.application, options)?;
+
+        let (unlocked, locked) = self.call(|| {
+            let (unlocked, locked) = self
+                .session
+                .search_items(self.options.schema.lookup(attributes.clone())?)?;
+            // unlock everything at once, so the user answers one prompt
+            if !options.dry_run && !locked.is_empty() {
+                self.session.unlock(&locked)?;
+            }
+
+            Ok((unlocked, locked))
+        })?;
+
+        self.call_once(|| {
+            let mut purged = Vec::new();
+            for path in unlocked.iter().chain(&locked) {
+                let found = self.found(path.clone(), options.dry_run && locked.contains(path))?;
+                if !options.dry_run {
+                    self.item(path)?.delete()?;
+                }
+                event!(debug, item = path.as_str(), "purged");
+                purged.push(PurgedItem {
+                    backend: self.name(),
+                    label: found.label,
+                    attributes: found.attributes,
+                });
+            }
+
+            Ok(purged)
+        })
+    }
+
+    fn copy_to(&self, collection: &str) -> Result<bool> {
+        span!("copy_to", service = self.service, collection);
+
+        self.transfer(collection, false)
+    }
+
+    fn move_to(&self, collection: &str) -> Result<bool> {
+        span!("move_to", service = self.service, collection);
+
+        self.transfer(collection, true)
+    }
+
+    fn relabel(&self, label: &str) -> Result<bool> {
+        span!("relabel", service = self.service, label);
+
+        let paths = self.entry_paths()?;
+        self.call_once(|| {
+            let collection = self.default_collection()?;
+            for path in &paths {
+                collection.item(path)?.set_label(label)?;
+            }
+
+            Ok(!paths.is_empty())
+        })
+    }
+
+    /// Returns the `Modified` timestamp of the stored item, if there is one
+    fn modified(&self) -> Result<Option<u64>> {
+        self.call(|| {
+            let collection = self.default_collection()?;
+
+            let paths = self.matching_paths(&collection)?;
+            match paths.first() {
+                Some(path) => Ok(Some(collection.item(path)?.modified()?)),
+                None => Ok(None),
+            }
+        })
+    }
+
+    fn profiles(&self) -> Result<Vec<String>> {
+        self.call(|| {
+            let collection = self.default_collection()?;
+            // the application the items are stored with, which depends on the layout
+            let application =
+                self.options
+                    .stored_attributes(self.application, self.service, self.username)
+                    ["application"];
+            let mut attributes = HashMap::new();
+            attributes.insert("application", application);
+
+            let mut profiles = BTreeSet::new();
+            for path in collection.search_paths(&self.options.schema, attributes)? {
+                if let Some(profile) = collection
+                    .item(&path)?
+                    .attributes()?
+                    .remove(PROFILE_ATTRIBUTE)
+                {
+                    profiles.insert(profile);
+                }
+            }
+
+            Ok(profiles.into_iter().collect())
+        })
+    }
+
+    fn watch(&self) -> Result<Watcher> {
+        self.call(|| {
+            let collection = self.default_collection()?;
+            let known = self.matching_paths(&collection)?;
+            let attributes = self
+                .options
+                .schema
+                .lookup(self.attributes())?
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect();
+
+            Watcher::new(
+                self.session.connection(),
+                self.session.session_path(),
+                collection.path(),
+                attributes,
+                known,
+                self.session.caller(),
+            )
+        })
+    }
 }
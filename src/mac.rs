@@ -4,6 +4,8 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+use crate::backend::Backend;
+use crate::options::Options;
 use anyhow::Result;
 use security_framework::os::macos::keychain::SecKeychain;
 use security_framework::os::macos::passwords::find_generic_password;
@@ -14,18 +16,31 @@ pub struct Keyring<'a> {
 }
 
 impl<'a> Keyring<'a> {
-    pub fn new(username: &'a str, service: &'a str, _application: &'a str) -> Result<Self> {
+    pub fn new(
+        username: &'a str,
+        service: &'a str,
+        _application: &'a str,
+        options: Options,
+    ) -> Result<Self> {
+        options.no_profile("macos-keychain")?;
+
         Ok(Keyring { username, service })
     }
+}
 
-    pub fn set_password(&self, password: String) -> Result<()> {
+impl Backend for Keyring<'_> {
+    fn name(&self) -> &'static str {
+        "macos-keychain"
+    }
+
+    fn set_password(&self, password: String) -> Result<()> {
         let keychain = SecKeychain::default()?;
         keychain.set_generic_password(self.service, self.username, password.as_bytes())?;
 
         Ok(())
     }
 
-    pub fn get_password(&self) -> Result<Option<String>> {
+    fn get_password(&self) -> Result<Option<String>> {
         let keychain = SecKeychain::default()?;
         let result = find_generic_password(Some(&[keychain]), self.service, self.username);
         let secret = match result {
@@ -33,7 +48,15 @@ impl<'a> Keyring<'a> {
                 let secret = String::from_utf8(password.to_vec())?;
                 Some(secret)
             }
-            Err(_) => None,
+            Err(_err) => {
+                event!(
+                    debug,
+                    service = self.service,
+                    "no generic password: {}",
+                    _err
+                );
+                None
+            }
         };
 
         Ok(secret)
@@ -0,0 +1,219 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::backend::{Backend, Entry};
+use crate::options::PROFILE_ATTRIBUTE;
+use crate::purge::{PurgeOptions, PurgedItem};
+use crate::search::{FoundItem, SearchScope};
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// (application, profile, service, username) to the password and when it was set
+type Key = (String, Option<String>, String, String);
+type Store = BTreeMap<Key, (String, u64)>;
+
+// shared by every mock in the process, so a secret set through one client is seen by the others
+static STORE: Mutex<Store> = Mutex::new(BTreeMap::new());
+
+/// Keeps secrets in memory, for tests.
+///
+/// All mocks of a process share one store, which lives until the process exits or
+/// [`MockKeyring::clear`] is called. Profiles are kept apart like the Secret Service does. Select
+/// it for code you don't control with `KEYRING_BACKEND=mock` or
+/// [`force_backend`](crate::force_backend).
+pub struct MockKeyring<'a> {
+    username: &'a str,
+    service: &'a str,
+    application: &'a str,
+    profile: Option<String>,
+}
+
+impl<'a> MockKeyring<'a> {
+    /// Returns a mock backend
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username to store secrets under
+    /// * `service` - A unique identifier within your application
+    /// * `application` - The name of your application
+    pub fn new(username: &'a str, service: &'a str, application: &'a str) -> Self {
+        Self {
+            username,
+            service,
+            application,
+            profile: None,
+        }
+    }
+
+    /// Keeps the secrets of `profile` apart from those of other profiles, see
+    /// [`Options::profile`](crate::Options::profile)
+    pub fn with_profile(mut self, profile: &str) -> Self {
+        self.profile = Some(profile.to_string());
+        self
+    }
+
+    /// Removes every secret of every mock
+    pub fn clear() {
+        if let Ok(mut store) = STORE.lock() {
+            store.clear();
+        }
+    }
+
+    fn store() -> Result<MutexGuard<'static, Store>> {
+        STORE
+            .lock()
+            .map_err(|_| anyhow!("a thread panicked while using the mock keyring"))
+    }
+
+    fn key(&self) -> Key {
+        (
+            self.application.to_string(),
+            self.profile.clone(),
+            self.service.to_string(),
+            self.username.to_string(),
+        )
+    }
+}
+
+impl Backend for MockKeyring<'_> {
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+    fn set_password(&self, password: String) -> Result<()> {
+        let modified = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        Self::store()?.insert(self.key(), (password, modified));
+
+        Ok(())
+    }
+
+    fn get_password(&self) -> Result<Option<String>> {
+        Ok(Self::store()?
+            .get(&self.key())
+            .map(|(password, _)| password.clone()))
+    }
+
+    fn delete_password(&self) -> Result<bool> {
+        Ok(Self::store()?.remove(&self.key()).is_some())
+    }
+
+    fn list(&self) -> Result<Vec<Entry>> {
+        Ok(Self::store()?
+            .keys()
+            .filter(|(application, profile, _, _)| {
+                application == self.application && *profile == self.profile
+            })
+            .map(|(_, _, service, username)| Entry {
+                service: service.clone(),
+                username: username.clone(),
+            })
+            .collect())
+    }
+
+    fn profiles(&self) -> Result<Vec<String>> {
+        let profiles: BTreeSet<String> = Self::store()?
+            .keys()
+            .filter(|(application, _, _, _)| application == self.application)
+            .filter_map(|(_, profile, _, _)| profile.clone())
+            .collect();
+
+        Ok(profiles.into_iter().collect())
+    }
+
+    /// Searches the `application`, `profile`, `service` and `username` of every mock's secrets
+    fn search(
+        &self,
+        attributes: &HashMap<&str, &str>,
+        _scope: SearchScope,
+    ) -> Result<Vec<FoundItem<'_>>> {
+        Ok(Self::store()?
+            .iter()
+            .filter_map(|(key, (_, modified))| {
+                let item = attributes_of(key);
+                let matches = attributes
+                    .iter()
+                    .all(|(name, value)| item.get(*name).map(String::as_str) == Some(*value));
+                if !matches {
+                    return None;
+                }
+
+                let (_, _, service, username) = key;
+                let label = format!("{}: {}", service, username);
+                let key = key.clone();
+                Some(FoundItem::new(
+                    label,
+                    item,
+                    *modified,
+                    *modified,
+                    false,
+                    move || {
+                        Self::store()?
+                            .get(&key)
+                            .map(|(password, _)| password.clone())
+                            .ok_or_else(|| anyhow!("the secret was removed"))
+                    },
+                ))
+            })
+            .collect())
+    }
+
+    /// Removes the application's secrets
+    fn purge(&self, options: &PurgeOptions) -> Result<Vec<PurgedItem>> {
+        let mut store = Self::store()?;
+        let keys: Vec<Key> = store
+            .keys()
+            .filter(|(application, profile, service, _)| {
+                application == self.application
+                    && options.service.as_ref().is_none_or(|only| only == service)
+                    && options
+                        .profile
+                        .as_ref()
+                        .is_none_or(|only| profile.as_ref() == Some(only))
+            })
+            .cloned()
+            .collect();
+
+        let mut purged = Vec::new();
+        for key in keys {
+            if !options.dry_run {
+                store.remove(&key);
+            }
+            purged.push(PurgedItem {
+                backend: self.name(),
+                label: format!("{}: {}", key.2, key.3),
+                attributes: attributes_of(&key),
+            });
+        }
+
+        Ok(purged)
+    }
+
+    fn modified(&self) -> Result<Option<u64>> {
+        Ok(Self::store()?
+            .get(&self.key())
+            .map(|(_, modified)| *modified))
+    }
+}
+
+/// The attributes a secret would have in the Secret Service
+fn attributes_of(key: &Key) -> HashMap<String, String> {
+    let (application, profile, service, username) = key;
+    let mut attributes: HashMap<String, String> = vec![
+        ("application", application),
+        ("service", service),
+        ("username", username),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.to_string(), value.clone()))
+    .collect();
+    if let Some(profile) = profile {
+        attributes.insert(PROFILE_ATTRIBUTE.to_string(), profile.clone());
+    }
+
+    attributes
+}
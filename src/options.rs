@@ -0,0 +1,376 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::error::Error;
+use crate::purge::PurgeOptions;
+use crate::schema::{AttributeType, Schema};
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+/// The attribute holding [`Options::profile`]
+pub(crate) const PROFILE_ATTRIBUTE: &str = "profile";
+
+/// The `application` python-keyring stores its items with
+const PYTHON_KEYRING_APPLICATION: &str = "Python keyring library";
+
+/// The `application` the `keyring` crate stores its items with
+const KEYRING_CRATE_APPLICATION: &str = "rust-keyring";
+
+/// Settings for a [`KeyringClient`](crate::KeyringClient).
+///
+/// Start from `Options::default()` and override what you need.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Reopen the connection and session once when the keyring daemon restarted or dropped our
+    /// session, then retry the call if it only reads, calls that change the keyring fail instead
+    /// because the keyring may have done what they asked
+    pub reconnect: bool,
+    /// How long to wait for the keyring to answer a call, `None` waits forever
+    pub call_timeout: Option<Duration>,
+    /// How long to wait for the user to answer a prompt, `None` waits forever
+    pub prompt_timeout: Option<Duration>,
+    /// How calls that are safe to repeat, like searches and reads, are retried after transient
+    /// failures, calls that timed out aren't retried
+    pub retry: RetryPolicy,
+    /// The label of new items, `{application}`, `{service}` and `{username}` are replaced with
+    /// the client's
+    pub label: String,
+    /// The collection items are stored in, an alias like `default` or `session`, or an object path
+    /// like `/org/freedesktop/secrets/collection/login`
+    pub collection: String,
+    /// Attributes stored with every item and matched when looking items up, `schema` has to
+    /// declare them
+    pub attributes: BTreeMap<String, String>,
+    /// Keeps a separate set of secrets, like `staging` or `prod`, in the `profile` attribute
+    ///
+    /// Clients without a profile only see items that have none. The Secret Service, the Secret
+    /// portal, the `file` and `mock` backends and the environment variable overlay keep profiles
+    /// apart, the other backends fail with [`Error::Unsupported`](crate::Error::Unsupported) when
+    /// one is set.
+    pub profile: Option<String>,
+    /// Which attributes identify an item
+    pub layout: AttributeLayout,
+    /// The schema items are stored with, which the Secret Service and the Secret portal check
+    /// their attributes against
+    pub schema: Schema,
+    /// Whether the keyring may prompt the user, e.g. to unlock a collection
+    pub prompt: PromptPolicy,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            reconnect: true,
+            // the reply timeout libdbus uses
+            call_timeout: Some(Duration::from_secs(25)),
+            prompt_timeout: None,
+            retry: RetryPolicy::default(),
+            label: "Password for {service}: {username}".to_string(),
+            collection: "default".to_string(),
+            attributes: BTreeMap::new(),
+            profile: None,
+            layout: AttributeLayout::Keyring,
+            schema: Schema::default(),
+            prompt: PromptPolicy::Allow,
+        }
+    }
+}
+
+impl Options {
+    /// Stores and finds items the way python-keyring does, so it and `secret-tool` share them
+    ///
+    /// Sets the [`AttributeLayout::PythonKeyring`] layout, python-keyring's label and the
+    /// `org.freedesktop.Secret.Generic` schema. Call it before adding your own attributes, it
+    /// replaces the schema.
+    pub fn python_keyring(self) -> Self {
+        self.generic(
+            AttributeLayout::PythonKeyring,
+            "Password for '{username}' on '{service}'",
+        )
+    }
+
+    /// Stores and finds items the way the `keyring` crate does, so it and this crate share them
+    ///
+    /// Sets the [`AttributeLayout::KeyringCrate`] layout, the `keyring` crate's label and the
+    /// `org.freedesktop.Secret.Generic` schema. Call it before adding your own attributes, it
+    /// replaces the schema.
+    pub fn keyring_crate(self) -> Self {
+        self.generic(
+            AttributeLayout::KeyringCrate,
+            "keyring-rs for service '{service}', user '{username}'",
+        )
+    }
+
+    fn generic(mut self, layout: AttributeLayout, label: &str) -> Self {
+        self.layout = layout;
+        self.label = label.to_string();
+        self.schema = Schema::new("org.freedesktop.Secret.Generic")
+            .attribute("application", AttributeType::String)
+            .attribute("service", AttributeType::String)
+            .attribute("username", AttributeType::String)
+            .attribute("target", AttributeType::String)
+            .attribute(PROFILE_ATTRIBUTE, AttributeType::String)
+            // `secret-tool` stores items without a schema
+            .dont_match_name();
+        self
+    }
+
+    /// The attributes an entry's items are found by, in the configured layout
+    pub(crate) fn lookup_attributes<'s>(
+        &'s self,
+        application: &'s str,
+        service: &'s str,
+        username: &'s str,
+    ) -> HashMap<&'s str, &'s str> {
+        let mut attributes = self.item_attributes();
+        match self.layout {
+            AttributeLayout::Keyring => {
+                attributes.insert("application", application);
+                attributes.insert("service", service);
+            }
+            AttributeLayout::PythonKeyring | AttributeLayout::KeyringCrate => {
+                attributes.insert("service", service);
+                attributes.insert("username", username);
+            }
+        }
+
+        attributes
+    }
+
+    /// The attributes an entry's items are stored with, in the configured layout
+    pub(crate) fn stored_attributes<'s>(
+        &'s self,
+        application: &'s str,
+        service: &'s str,
+        username: &'s str,
+    ) -> HashMap<&'s str, &'s str> {
+        let mut attributes = self.lookup_attributes(application, service, username);
+        match self.layout {
+            AttributeLayout::Keyring => {}
+            AttributeLayout::PythonKeyring => {
+                attributes.insert("application", PYTHON_KEYRING_APPLICATION);
+            }
+            AttributeLayout::KeyringCrate => {
+                attributes.insert("application", KEYRING_CRATE_APPLICATION);
+            }
+        }
+
+        attributes
+    }
+
+    /// The attributes of the client's items a purge removes, in the configured layout
+    ///
+    /// In the shared layouts every application's items have the same `application`, so a purge
+    /// has to name the service to only remove the client's items.
+    pub(crate) fn purge_attributes<'s>(
+        &'s self,
+        application: &'s str,
+        purge: &'s PurgeOptions,
+    ) -> Result<HashMap<&'s str, &'s str>> {
+        let mut attributes: HashMap<&str, &str> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+        let application = match (self.layout, &purge.service) {
+            (AttributeLayout::Keyring, _) => application,
+            (_, None) => {
+                return Err(anyhow!(
+                    "the {:?} layout shares its items with other applications, purge a service",
+                    self.layout
+                ))
+            }
+            (AttributeLayout::PythonKeyring, Some(_)) => PYTHON_KEYRING_APPLICATION,
+            (AttributeLayout::KeyringCrate, Some(_)) => KEYRING_CRATE_APPLICATION,
+        };
+        attributes.insert("application", application);
+        if let Some(service) = &purge.service {
+            attributes.insert("service", service);
+        }
+        if let Some(profile) = &purge.profile {
+            attributes.insert(PROFILE_ATTRIBUTE, profile);
+        }
+
+        Ok(attributes)
+    }
+
+    /// The label for an item, filled in from the `label` template
+    pub(crate) fn item_label(&self, application: &str, service: &str, username: &str) -> String {
+        self.label
+            .replace("{application}", application)
+            .replace("{service}", service)
+            .replace("{username}", username)
+    }
+
+    /// The configured attributes and the profile, for backends that store items by attributes
+    pub(crate) fn item_attributes(&self) -> HashMap<&str, &str> {
+        let mut attributes: HashMap<&str, &str> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+        if let Some(profile) = &self.profile {
+            attributes.insert(PROFILE_ATTRIBUTE, profile);
+        }
+
+        attributes
+    }
+
+    /// Whether an item with `attributes` belongs to the client's profile
+    ///
+    /// Searching for the profile's attributes already finds the items of a profile, this tells
+    /// apart the items of other profiles when the client has none.
+    pub(crate) fn in_profile(&self, attributes: &HashMap<String, String>) -> bool {
+        attributes.get(PROFILE_ATTRIBUTE) == self.profile.as_ref()
+    }
+
+    /// Fails when a profile is set, for backends that can't keep the secrets of profiles apart
+    pub(crate) fn no_profile(&self, backend: &'static str) -> Result<()> {
+        match self.profile {
+            Some(_) => Err(Error::unsupported(backend, "profiles").into()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Which attributes identify the items of an entry in the Secret Service.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AttributeLayout {
+    /// `application` and `service`
+    Keyring,
+    /// `service` and `username`, like python-keyring and `secret-tool lookup service <service>
+    /// username <username>`, see [`Options::python_keyring`]
+    ///
+    /// Items are stored with python-keyring's `application`, so either tool replaces the other's
+    /// item instead of adding one. A profile or extra attributes make the items differ again.
+    PythonKeyring,
+    /// `service` and `username`, like the `keyring` crate, see [`Options::keyring_crate`] and
+    /// the [`compat`](crate::compat) module
+    KeyringCrate,
+}
+
+/// Whether a keyring may show prompts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PromptPolicy {
+    /// Show prompts and wait for the user to answer them
+    Allow,
+    /// Fail with [`Error::PromptRequired`](crate::Error::PromptRequired) instead of prompting,
+    /// e.g. for services nobody is sitting in front of
+    Deny,
+}
+
+/// Retries with exponential backoff.
+///
+/// Calls are retried when the bus is over its limits, or while the Secret Service is restarting
+/// and its name has no owner. Calls that timed out, including `NoReply`, aren't retried.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// How often a call is made in total, `1` disables retrying
+    pub attempts: u32,
+    /// How long to wait before the first retry, doubling on every retry after
+    pub backoff: Duration,
+    /// The longest to wait between two attempts
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether the Secret Service would match an item with `item` for `attributes`
+    fn matches(attributes: &HashMap<&str, &str>, item: &[(&str, &str)]) -> bool {
+        let item: HashMap<&str, &str> = item.iter().cloned().collect();
+
+        attributes
+            .iter()
+            .all(|(name, value)| item.get(name) == Some(value))
+    }
+
+    #[test]
+    fn purges_the_applications_items() {
+        let options = Options::default();
+        let purge = PurgeOptions::default();
+        let attributes = options.purge_attributes("my-app", &purge).unwrap();
+
+        assert!(matches(
+            &attributes,
+            &[("application", "my-app"), ("service", "example.com")]
+        ));
+        assert!(!matches(
+            &attributes,
+            &[("application", "other-app"), ("service", "example.com")]
+        ));
+    }
+
+    #[test]
+    fn purges_a_service_in_shared_layouts() {
+        for options in [
+            Options::default().python_keyring(),
+            Options::default().keyring_crate(),
+        ] {
+            let err = options
+                .purge_attributes("my-app", &PurgeOptions::default())
+                .unwrap_err();
+            assert!(err.to_string().contains("purge a service"), "{}", err);
+        }
+
+        let options = Options::default().python_keyring();
+        let purge = PurgeOptions {
+            service: Some("my-service".to_string()),
+            ..PurgeOptions::default()
+        };
+        let attributes = options.purge_attributes("my-app", &purge).unwrap();
+
+        let item = |service| {
+            [
+                ("application", PYTHON_KEYRING_APPLICATION),
+                ("service", service),
+                ("username", "alice"),
+            ]
+        };
+        assert!(matches(&attributes, &item("my-service")));
+        // stored by another application through python-keyring
+        assert!(!matches(&attributes, &item("other-service")));
+    }
+
+    #[test]
+    fn purges_the_configured_attributes() {
+        let mut options = Options::default().keyring_crate();
+        options
+            .attributes
+            .insert("target".to_string(), "work".to_string());
+        let purge = PurgeOptions {
+            service: Some("example.com".to_string()),
+            ..PurgeOptions::default()
+        };
+        let attributes = options.purge_attributes("my-app", &purge).unwrap();
+
+        let item = |target| {
+            [
+                ("application", KEYRING_CRATE_APPLICATION),
+                ("service", "example.com"),
+                ("username", "alice"),
+                ("target", target),
+            ]
+        };
+        assert!(matches(&attributes, &item("work")));
+        assert!(!matches(&attributes, &item("home")));
+    }
+}
@@ -0,0 +1,534 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::backend::{Backend, Entry};
+use crate::file::{create_private_dir, private_options};
+use anyhow::{anyhow, Context, Result};
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::UNIX_EPOCH;
+
+const STORE_VARIABLE: &str = "PASSWORD_STORE_DIR";
+const GPG_ID: &str = ".gpg-id";
+const EXTENSION: &str = "gpg";
+
+/// Stores secrets in a [pass](https://www.passwordstore.org/) password store.
+///
+/// An entry is the GPG-encrypted file `<service>/<username>.gpg` in `$PASSWORD_STORE_DIR`, or
+/// `~/.password-store`. Its first line is the password and the lines after it are metadata, which
+/// is kept when the password changes. Files are encrypted for the recipients in the nearest
+/// `.gpg-id`, using the `gpg` binary.
+pub struct PassKeyring<'a> {
+    username: &'a str,
+    service: &'a str,
+    store: PathBuf,
+    gpg: String,
+}
+
+/// A decrypted password store entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PassEntry {
+    pub password: String,
+    /// The lines after the password, e.g. `login: tmuntaner` or `url: https://example.com`
+    pub metadata: Vec<String>,
+}
+
+impl<'a> PassKeyring<'a> {
+    /// Returns a backend for the user's password store
+    ///
+    /// Fails when the store wasn't set up with `pass init`.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username to store secrets under
+    /// * `service` - A unique identifier within your application, may contain `/` for subfolders
+    pub fn new(username: &'a str, service: &'a str) -> Result<Self> {
+        let store = match std::env::var_os(STORE_VARIABLE) {
+            Some(store) => PathBuf::from(store),
+            None => dirs::home_dir()
+                .ok_or_else(|| anyhow!("no home directory"))?
+                .join(".password-store"),
+        };
+        if !store.join(GPG_ID).is_file() {
+            return Err(anyhow!("{:?} is not an initialized password store", store));
+        }
+
+        Ok(Self::with_store(username, service, store))
+    }
+
+    /// Returns a backend for the password store at `store`
+    pub fn with_store<P: Into<PathBuf>>(username: &'a str, service: &'a str, store: P) -> Self {
+        Self {
+            username,
+            service,
+            store: store.into(),
+            gpg: String::from("gpg"),
+        }
+    }
+
+    /// Runs `gpg` instead of the `gpg` binary on the `PATH`
+    pub fn with_gpg(mut self, gpg: &str) -> Self {
+        self.gpg = gpg.to_string();
+        self
+    }
+
+    /// Returns the decrypted entry, with its metadata
+    pub fn entry(&self) -> Result<Option<PassEntry>> {
+        let path = self.path()?;
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let plaintext = self.gpg(&[OsStr::new("--decrypt"), path.as_os_str()], None)?;
+        let plaintext =
+            String::from_utf8(plaintext).with_context(|| format!("{:?} isn't text", path))?;
+        let mut lines = plaintext.lines();
+
+        Ok(Some(PassEntry {
+            password: lines.next().unwrap_or_default().to_string(),
+            metadata: lines.map(String::from).collect(),
+        }))
+    }
+
+    fn path(&self) -> Result<PathBuf> {
+        let service = Path::new(self.service);
+        let valid_service = service
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if self.service.is_empty() || !valid_service {
+            return Err(anyhow!("{:?} is not a valid pass folder", self.service));
+        }
+        if self.username.is_empty() || self.username.contains('/') || self.username.starts_with('.')
+        {
+            return Err(anyhow!(
+                "{:?} is not a valid pass entry name",
+                self.username
+            ));
+        }
+
+        Ok(self
+            .store
+            .join(service)
+            .join(format!("{}.{}", self.username, EXTENSION)))
+    }
+
+    /// Returns the recipients from the `.gpg-id` closest to `path`, like `pass` does
+    fn recipients(&self, path: &Path) -> Result<Vec<String>> {
+        let gpg_id = path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(&self.store))
+            .map(|dir| dir.join(GPG_ID))
+            .find(|gpg_id| gpg_id.is_file())
+            .ok_or_else(|| anyhow!("no {} in {:?}", GPG_ID, self.store))?;
+
+        let recipients: Vec<String> = fs::read_to_string(&gpg_id)
+            .with_context(|| format!("failed to read {:?}", gpg_id))?
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default().trim())
+            .filter(|line| !line.is_empty())
+            .map(String::from)
+            .collect();
+        if recipients.is_empty() {
+            return Err(anyhow!("{:?} lists no recipients", gpg_id));
+        }
+
+        Ok(recipients)
+    }
+
+    fn write(&self, entry: &PassEntry) -> Result<()> {
+        let path = self.path()?;
+        let dir = path.parent().unwrap_or(&self.store);
+        create_private_dir(dir)?;
+
+        let mut args = vec![
+            OsStr::new("--encrypt"),
+            OsStr::new("--compress-algo=none"),
+            OsStr::new("--no-encrypt-to"),
+        ];
+        let recipients = self.recipients(&path)?;
+        for recipient in &recipients {
+            args.push(OsStr::new("--recipient"));
+            args.push(OsStr::new(recipient));
+        }
+
+        let mut plaintext = entry.password.clone();
+        for line in &entry.metadata {
+            plaintext.push('\n');
+            plaintext.push_str(line);
+        }
+        plaintext.push('\n');
+        let ciphertext = self.gpg(&args, Some(plaintext.as_bytes()))?;
+
+        let tmp_path = dir.join(format!(".{}.{}.tmp", self.username, std::process::id()));
+        let mut tmp = private_options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        tmp.write_all(&ciphertext)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &path).with_context(|| format!("failed to replace {:?}", path))
+    }
+
+    /// Runs gpg non-interactively and returns what it wrote to stdout
+    fn gpg(&self, args: &[&OsStr], input: Option<&[u8]>) -> Result<Vec<u8>> {
+        let mut child = Command::new(&self.gpg)
+            .args(["--quiet", "--batch", "--yes", "--output", "-"])
+            .args(args)
+            .stdin(if input.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("failed to run {}", self.gpg))?;
+
+        // write from another thread, gpg stops reading once its output fills the pipe
+        let stdin = child.stdin.take();
+        let output = std::thread::scope(|scope| {
+            let writer = stdin
+                .zip(input)
+                .map(|(mut stdin, input)| scope.spawn(move || stdin.write_all(input)));
+            let output = child.wait_with_output();
+            if let Some(writer) = writer {
+                writer
+                    .join()
+                    .map_err(|_| anyhow!("writing to {} panicked", self.gpg))??;
+            }
+
+            Ok::<_, anyhow::Error>(output?)
+        })?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "{} failed: {}",
+                self.gpg,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        Ok(output.stdout)
+    }
+}
+
+impl Backend for PassKeyring<'_> {
+    fn name(&self) -> &'static str {
+        "pass"
+    }
+
+    fn set_password(&self, password: String) -> Result<()> {
+        let metadata = match self.entry()? {
+            Some(entry) => entry.metadata,
+            None => Vec::new(),
+        };
+
+        self.write(&PassEntry { password, metadata })
+    }
+
+    fn get_password(&self) -> Result<Option<String>> {
+        Ok(self.entry()?.map(|entry| entry.password))
+    }
+
+    fn delete_password(&self) -> Result<bool> {
+        let path = self.path()?;
+        match fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err).context(format!("failed to remove {:?}", path)),
+        }
+
+        // like `pass rm`, remove folders left empty
+        for dir in path.ancestors().skip(1) {
+            if dir == self.store || !dir.starts_with(&self.store) || fs::remove_dir(dir).is_err() {
+                break;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Lists every entry in a folder of the store
+    fn list(&self) -> Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        let mut dirs = vec![self.store.clone()];
+
+        while let Some(dir) = dirs.pop() {
+            for file in fs::read_dir(&dir).with_context(|| format!("failed to read {:?}", dir))? {
+                let path = file?.path();
+                let hidden = path
+                    .file_name()
+                    .and_then(OsStr::to_str)
+                    .is_none_or(|name| name.starts_with('.'));
+                if hidden {
+                    continue;
+                }
+                if path.is_dir() {
+                    dirs.push(path);
+                    continue;
+                }
+                if path.extension() != Some(OsStr::new(EXTENSION)) || dir == self.store {
+                    continue;
+                }
+
+                let service = dir.strip_prefix(&self.store)?.to_str();
+                let username = path.file_stem().and_then(OsStr::to_str);
+                if let (Some(service), Some(username)) = (service, username) {
+                    entries.push(Entry {
+                        service: service.to_string(),
+                        username: username.to_string(),
+                    });
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn modified(&self) -> Result<Option<u64>> {
+        let path = self.path()?;
+
+        match fs::metadata(&path) {
+            Ok(metadata) => Ok(Some(
+                metadata.modified()?.duration_since(UNIX_EPOCH)?.as_secs(),
+            )),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).context(format!("failed to read {:?}", path)),
+        }
+    }
+}
+
+impl PassEntry {
+    /// Returns the value of a `name: value` metadata line, ignoring the case of `name`
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.metadata.iter().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            if key.trim().eq_ignore_ascii_case(name) {
+                Some(value.trim())
+            } else {
+                None
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// A throwaway GPG home, and a `gpg` wrapper using it
+    struct GpgHome {
+        dir: TempDir,
+        gpg: String,
+    }
+
+    impl GpgHome {
+        /// Returns `None` when gpg isn't installed
+        fn new() -> Option<Self> {
+            Command::new("gpg").arg("--version").output().ok()?;
+
+            let dir = tempfile::tempdir().unwrap();
+            let home = dir.path().join("gnupg");
+            create_private_dir(&home).unwrap();
+            let gpg = dir.path().join("gpg");
+            fs::write(
+                &gpg,
+                format!("#!/bin/sh\nexec gpg --homedir {:?} \"$@\"\n", home),
+            )
+            .unwrap();
+            #[cfg(unix)]
+            fs::set_permissions(&gpg, std::os::unix::fs::PermissionsExt::from_mode(0o700)).unwrap();
+
+            Some(Self {
+                gpg: gpg.to_str().unwrap().to_string(),
+                dir,
+            })
+        }
+
+        /// Generates a key without a passphrase and returns its fingerprint
+        fn key(&self, email: &str) -> String {
+            let run = |args: &[&str]| {
+                let output = Command::new(&self.gpg).args(args).output().unwrap();
+                assert!(output.status.success(), "{:?}", output);
+                String::from_utf8(output.stdout).unwrap()
+            };
+            run(&[
+                "--batch",
+                "--passphrase",
+                "",
+                "--quick-gen-key",
+                email,
+                "default",
+                "default",
+                "never",
+            ]);
+
+            run(&["--batch", "--with-colons", "--list-keys", email])
+                .lines()
+                .find_map(|line| line.strip_prefix("fpr:"))
+                .map(|line| line.trim_matches(':').to_string())
+                .unwrap()
+        }
+
+        /// The key ids an encrypted file is encrypted for
+        fn recipients(&self, path: &Path) -> String {
+            let output = Command::new(&self.gpg)
+                .args(["--batch", "--list-packets", "--list-only"])
+                .arg(path)
+                .output()
+                .unwrap();
+
+            String::from_utf8_lossy(&output.stdout).into_owned()
+        }
+
+        fn store(&self) -> PathBuf {
+            self.dir.path().join("store")
+        }
+
+        fn keyring<'a>(&self, username: &'a str, service: &'a str) -> PassKeyring<'a> {
+            PassKeyring::with_store(username, service, self.store()).with_gpg(&self.gpg)
+        }
+    }
+
+    impl Drop for GpgHome {
+        fn drop(&mut self) {
+            Command::new("gpgconf")
+                .env("GNUPGHOME", self.dir.path().join("gnupg"))
+                .args(["--kill", "gpg-agent"])
+                .status()
+                .ok();
+        }
+    }
+
+    /// Sets up a store encrypted for a new key, returns `None` when gpg isn't installed
+    fn store() -> Option<GpgHome> {
+        let home = GpgHome::new()?;
+        let key = home.key("store@example.com");
+        create_private_dir(&home.store()).unwrap();
+        fs::write(home.store().join(GPG_ID), format!("{}\n", key)).unwrap();
+
+        Some(home)
+    }
+
+    #[test]
+    fn round_trip() {
+        let home = match store() {
+            Some(home) => home,
+            None => return,
+        };
+        let keyring = home.keyring("alice", "example.com");
+
+        assert_eq!(keyring.get_password().unwrap(), None);
+        keyring.set_password("secret".to_string()).unwrap();
+        assert_eq!(keyring.get_password().unwrap().as_deref(), Some("secret"));
+        assert_eq!(
+            keyring.list().unwrap(),
+            vec![Entry {
+                service: "example.com".to_string(),
+                username: "alice".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn large_secrets_do_not_block() {
+        let home = match store() {
+            Some(home) => home,
+            None => return,
+        };
+        let keyring = home.keyring("alice", "example.com");
+        // more than a pipe buffer of output
+        let secret = "x".repeat(1 << 20);
+
+        keyring.set_password(secret.clone()).unwrap();
+        assert_eq!(keyring.get_password().unwrap(), Some(secret));
+    }
+
+    #[test]
+    fn keeps_metadata() {
+        let home = match store() {
+            Some(home) => home,
+            None => return,
+        };
+        let keyring = home.keyring("alice", "example.com");
+        keyring
+            .write(&PassEntry {
+                password: "old".to_string(),
+                metadata: vec![
+                    "login: alice".to_string(),
+                    "url: https://example.com".to_string(),
+                ],
+            })
+            .unwrap();
+
+        keyring.set_password("new".to_string()).unwrap();
+        let entry = keyring.entry().unwrap().unwrap();
+        assert_eq!(entry.password, "new");
+        assert_eq!(entry.field("URL"), Some("https://example.com"));
+        assert_eq!(entry.metadata.len(), 2);
+    }
+
+    #[test]
+    fn nearest_gpg_id() {
+        let home = match store() {
+            Some(home) => home,
+            None => return,
+        };
+        let team_key = home.key("team@example.com");
+        let team = home.store().join("team");
+        create_private_dir(&team).unwrap();
+        fs::write(team.join(GPG_ID), format!("{} # the team\n", team_key)).unwrap();
+
+        let keyring = home.keyring("alice", "team/example.com");
+        keyring.set_password("secret".to_string()).unwrap();
+
+        let recipients = home.recipients(&keyring.path().unwrap());
+        let store_key = fs::read_to_string(home.store().join(GPG_ID)).unwrap();
+        // the packets name the encryption subkeys, so compare by the key listings
+        let subkey = |key: &str| {
+            let output = Command::new(&home.gpg)
+                .args(["--batch", "--with-colons", "--list-keys", key.trim()])
+                .output()
+                .unwrap();
+            String::from_utf8(output.stdout)
+                .unwrap()
+                .lines()
+                .filter(|line| line.starts_with("sub:"))
+                .filter_map(|line| line.split(':').nth(4).map(String::from))
+                .next()
+                .unwrap()
+        };
+        assert!(recipients.contains(&subkey(&team_key)), "{}", recipients);
+        assert!(!recipients.contains(&subkey(&store_key)), "{}", recipients);
+        assert_eq!(keyring.get_password().unwrap().as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn delete_prunes_empty_folders() {
+        let home = match store() {
+            Some(home) => home,
+            None => return,
+        };
+        let alice = home.keyring("alice", "work/example.com");
+        let bob = home.keyring("bob", "work/other.com");
+        alice.set_password("secret".to_string()).unwrap();
+        bob.set_password("secret".to_string()).unwrap();
+
+        assert!(alice.delete_password().unwrap());
+        assert!(!alice.delete_password().unwrap());
+        assert!(!home.store().join("work/example.com").exists());
+        assert!(home.store().join("work").is_dir());
+
+        assert!(bob.delete_password().unwrap());
+        assert!(!home.store().join("work").exists());
+        assert!(home.store().join(GPG_ID).is_file());
+    }
+}
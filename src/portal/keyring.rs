@@ -0,0 +1,522 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::file::{create_private_dir, lock_file, private_options};
+use aes::cipher::block_padding::Pkcs7;
+use aes::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit};
+use aes::Aes256;
+use anyhow::{anyhow, Context, Result};
+use byteorder::LE;
+use hmac::{Hmac, Mac};
+use openssl::rand::rand_bytes;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use zvariant::EncodingContext;
+use zvariant_derive::Type;
+
+type Aes256CbcEnc = cbc::Encryptor<Aes256>;
+type Aes256CbcDec = cbc::Decryptor<Aes256>;
+type HmacSha256 = Hmac<Sha256>;
+
+const HEADER: &[u8; 16] = b"GnomeKeyring\n\r\0\n";
+const MAJOR_VERSION: u8 = 1;
+const MINOR_VERSION: u8 = 0;
+const SALT_SIZE: usize = 32;
+const ITERATION_COUNT: u32 = 100_000;
+// a corrupted or hostile file mustn't make the key derivation run for too long
+const MAX_ITERATION_COUNT: u32 = 1_000_000;
+const IV_SIZE: usize = 16;
+const MAC_SIZE: usize = 32;
+
+/// A keyring file in libsecret's format for sandboxed apps.
+///
+/// After the header, the file is a little endian GVariant of type `(uayutua(a{say}ay))`. Each item
+/// is an `(a{ss}sttay)` GVariant encrypted with AES-256-CBC, followed by its IV and an
+/// HMAC-SHA256 of both, and is found through the HMACs of its attribute values. The key is derived
+/// from the portal's master secret with PBKDF2-SHA256.
+///
+/// The file is locked from when it's opened until it's dropped, through a lock file next to it, so
+/// processes sharing it don't overwrite each other's changes.
+///
+/// <https://gitlab.gnome.org/GNOME/libsecret/-/blob/master/libsecret/secret-file-collection.c>
+pub struct KeyringFile {
+    path: PathBuf,
+    keyring: Keyring,
+    key: Vec<u8>,
+    _lock: File,
+}
+
+#[derive(Deserialize, Serialize, Type)]
+struct Keyring {
+    salt_size: u32,
+    salt: Vec<u8>,
+    iteration_count: u32,
+    modified_time: u64,
+    usage_count: u32,
+    items: Vec<EncryptedItem>,
+}
+
+#[derive(Deserialize, Serialize, Type)]
+struct EncryptedItem {
+    // sorted, so the same items are always written the same way
+    hashed_attributes: BTreeMap<String, Vec<u8>>,
+    blob: Vec<u8>,
+}
+
+/// A decrypted item.
+#[derive(Deserialize, Serialize, Type)]
+pub struct Item {
+    pub attributes: HashMap<String, String>,
+    pub label: String,
+    pub created: u64,
+    pub modified: u64,
+    pub secret: Vec<u8>,
+}
+
+impl KeyringFile {
+    /// Locks and reads the keyring at `path`, or starts an empty one if there is no file yet
+    ///
+    /// `derive` returns the key for a salt and an iteration count, so callers can reuse it.
+    pub fn open<F>(path: &Path, derive: F) -> Result<Self>
+    where
+        F: FnOnce(&[u8], u32) -> Result<Vec<u8>>,
+    {
+        let lock = lock_file(path)?;
+        lock.lock()?;
+
+        let keyring = match fs::read(path) {
+            Ok(data) => Self::parse(&data).with_context(|| format!("failed to read {:?}", path))?,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                let mut salt = vec![0u8; SALT_SIZE];
+                rand_bytes(&mut salt)?;
+
+                Keyring {
+                    salt_size: SALT_SIZE as u32,
+                    salt,
+                    iteration_count: ITERATION_COUNT,
+                    modified_time: now(),
+                    usage_count: 0,
+                    items: Vec::new(),
+                }
+            }
+            Err(err) => return Err(err).context(format!("failed to read {:?}", path)),
+        };
+        if keyring.iteration_count > MAX_ITERATION_COUNT {
+            return Err(anyhow!(
+                "{} PBKDF2 iterations are too many",
+                keyring.iteration_count
+            ))
+            .context(format!("failed to read {:?}", path));
+        }
+        let key = derive(&keyring.salt, keyring.iteration_count)?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            keyring,
+            key,
+            _lock: lock,
+        })
+    }
+
+    /// Derives the key for a keyring from the master secret
+    pub fn derive_key(secret: &[u8], salt: &[u8], iterations: u32) -> Result<Vec<u8>> {
+        // a single PBKDF2 block, the key is as long as the hash
+        let mac = HmacSha256::new_from_slice(secret)?;
+
+        let mut block = mac.clone();
+        block.update(salt);
+        block.update(&1u32.to_be_bytes());
+        let mut u = block.finalize().into_bytes();
+        let mut key = u;
+        for _ in 1..iterations {
+            let mut block = mac.clone();
+            block.update(&u);
+            u = block.finalize().into_bytes();
+            key.iter_mut().zip(u.iter()).for_each(|(k, u)| *k ^= u);
+        }
+
+        Ok(key.to_vec())
+    }
+
+    /// Returns the items that have all of `attributes`
+    pub fn search(&self, attributes: &HashMap<&str, &str>) -> Result<Vec<Item>> {
+        let hashed = self.hash_attributes(attributes)?;
+
+        self.keyring
+            .items
+            .iter()
+            .filter(|item| Self::matches(item, &hashed))
+            .map(|item| self.decrypt(item))
+            .collect()
+    }
+
+    /// Adds `item`, replacing the items with the same attributes
+    pub fn replace(&mut self, item: &Item) -> Result<()> {
+        let hashed = self.remove_item(item)?;
+
+        let encrypted = self.encrypt(item, hashed)?;
+        self.keyring.items.push(encrypted);
+
+        Ok(())
+    }
+
+    /// Removes the items with exactly the attributes of `item`, returns their hashed attributes
+    pub fn remove_item(&mut self, item: &Item) -> Result<BTreeMap<String, Vec<u8>>> {
+        let attributes = item
+            .attributes
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        let hashed = self.hash_attributes(&attributes)?;
+        self.keyring
+            .items
+            .retain(|item| item.hashed_attributes != hashed);
+
+        Ok(hashed)
+    }
+
+    /// Writes the keyring back to its file, replacing it atomically
+    pub fn write(&mut self) -> Result<()> {
+        self.keyring.modified_time = now();
+
+        let mut data = HEADER.to_vec();
+        data.push(MAJOR_VERSION);
+        data.push(MINOR_VERSION);
+        data.extend_from_slice(&zvariant::to_bytes(context(), &self.keyring)?);
+
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        create_private_dir(dir)?;
+        let tmp_path = dir.join(format!(".keyring.{}.tmp", std::process::id()));
+        let mut tmp = private_options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        tmp.write_all(&data)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("failed to replace {:?}", self.path))?;
+        File::open(dir)?.sync_all()?;
+
+        Ok(())
+    }
+
+    fn parse(data: &[u8]) -> Result<Keyring> {
+        if data.len() < HEADER.len() + 2 || &data[..HEADER.len()] != HEADER {
+            return Err(anyhow!("not a keyring file"));
+        }
+        let (major, minor) = (data[HEADER.len()], data[HEADER.len() + 1]);
+        if (major, minor) != (MAJOR_VERSION, MINOR_VERSION) {
+            return Err(anyhow!("unsupported version {}.{}", major, minor));
+        }
+
+        let keyring: Keyring = zvariant::from_slice(&data[HEADER.len() + 2..], context())?;
+        if keyring.salt.len() != keyring.salt_size as usize {
+            return Err(anyhow!("corrupted salt"));
+        }
+
+        Ok(keyring)
+    }
+
+    fn matches(item: &EncryptedItem, hashed: &BTreeMap<String, Vec<u8>>) -> bool {
+        hashed
+            .iter()
+            .all(|(name, mac)| item.hashed_attributes.get(name) == Some(mac))
+    }
+
+    fn hash_attributes(
+        &self,
+        attributes: &HashMap<&str, &str>,
+    ) -> Result<BTreeMap<String, Vec<u8>>> {
+        attributes
+            .iter()
+            .map(|(name, value)| Ok((name.to_string(), self.mac(value.as_bytes())?)))
+            .collect()
+    }
+
+    fn mac(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut mac = HmacSha256::new_from_slice(&self.key)?;
+        mac.update(data);
+
+        Ok(mac.finalize().into_bytes().to_vec())
+    }
+
+    fn encrypt(
+        &self,
+        item: &Item,
+        hashed_attributes: BTreeMap<String, Vec<u8>>,
+    ) -> Result<EncryptedItem> {
+        let plaintext = zvariant::to_bytes(context(), item)?;
+        let mut iv = [0u8; IV_SIZE];
+        rand_bytes(&mut iv)?;
+
+        let mut blob = Aes256CbcEnc::new_from_slices(&self.key, &iv)?
+            .encrypt_padded_vec_mut::<Pkcs7>(&plaintext);
+        blob.extend_from_slice(&iv);
+        let mac = self.mac(&blob)?;
+        blob.extend_from_slice(&mac);
+
+        Ok(EncryptedItem {
+            hashed_attributes,
+            blob,
+        })
+    }
+
+    fn decrypt(&self, item: &EncryptedItem) -> Result<Item> {
+        if item.blob.len() < IV_SIZE + MAC_SIZE {
+            return Err(anyhow!("corrupted item"));
+        }
+        let (encrypted, mac) = item.blob.split_at(item.blob.len() - MAC_SIZE);
+
+        let mut verifier = HmacSha256::new_from_slice(&self.key)?;
+        verifier.update(encrypted);
+        verifier
+            .verify_slice(mac)
+            .map_err(|_| anyhow!("failed to decrypt an item, wrong master secret?"))?;
+
+        let (ciphertext, iv) = encrypted.split_at(encrypted.len() - IV_SIZE);
+        let plaintext = Aes256CbcDec::new_from_slices(&self.key, iv)?
+            .decrypt_padded_vec_mut::<Pkcs7>(ciphertext)
+            .map_err(|_| anyhow!("corrupted item"))?;
+
+        Ok(zvariant::from_slice(&plaintext, context())?)
+    }
+}
+
+fn context() -> EncodingContext<LE> {
+    EncodingContext::new_gvariant(0)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // written by tests/fixtures/portal_keyring.py with GLib's GVariant serializer, its key
+    // derivation and hashing are checked against published vectors below
+    const FIXTURE: &[u8] = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/portal.keyring"
+    ));
+    const FIXTURE_SECRET: &[u8] = b"fixture master secret";
+
+    fn open(path: &Path, secret: &[u8]) -> Result<KeyringFile> {
+        KeyringFile::open(path, |salt, iterations| {
+            KeyringFile::derive_key(secret, salt, iterations)
+        })
+    }
+
+    fn fixture() -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("default.keyring");
+        fs::write(&path, FIXTURE).unwrap();
+
+        (dir, path)
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn attributes<'a>(pairs: &[(&'a str, &'a str)]) -> HashMap<&'a str, &'a str> {
+        pairs.iter().copied().collect()
+    }
+
+    fn item(service: &str, secret: &str) -> Item {
+        Item {
+            attributes: [("application", "app"), ("service", service)]
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            label: format!("Password for {}", service),
+            created: 1,
+            modified: 2,
+            secret: secret.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn reads_libsecret_files() {
+        let (_dir, path) = fixture();
+        let keyring = open(&path, FIXTURE_SECRET).unwrap();
+
+        let items = keyring
+            .search(&attributes(&[
+                ("service", "example.com"),
+                ("username", "alice"),
+            ]))
+            .unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].label, "Password for example.com: alice");
+        assert_eq!(items[0].secret, b"hunter2");
+        assert_eq!(
+            (items[0].created, items[0].modified),
+            (1700000000, 1700000100)
+        );
+        assert_eq!(
+            items[0].attributes.get("xdg:schema").map(String::as_str),
+            Some("io.github.tmuntaner.Keyring")
+        );
+
+        let items = keyring
+            .search(&attributes(&[("service", "other")]))
+            .unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].secret, "pässword".as_bytes());
+        assert_eq!(keyring.search(&HashMap::new()).unwrap().len(), 2);
+        assert!(keyring
+            .search(&attributes(&[("service", "missing")]))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn writes_libsecret_layout() {
+        let (_dir, path) = fixture();
+        let keyring = open(&path, FIXTURE_SECRET).unwrap();
+
+        // everything but the header is the GVariant, which GLib serialized the same way
+        let data = zvariant::to_bytes(context(), &keyring.keyring).unwrap();
+        assert_eq!(data, &FIXTURE[HEADER.len() + 2..]);
+    }
+
+    #[test]
+    fn derives_keys_like_pbkdf2() {
+        // PBKDF2-HMAC-SHA256 vectors of RFC 7914, section 11, truncated to the key length
+        let key = KeyringFile::derive_key(b"passwd", b"salt", 1).unwrap();
+        assert_eq!(
+            hex(&key),
+            "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc"
+        );
+        let key = KeyringFile::derive_key(b"Password", b"NaCl", 80000).unwrap();
+        assert_eq!(
+            hex(&key),
+            "4ddcd8f60b98be21830cee5ef22701f9641a4418d04c0414aeff08876b34ab56"
+        );
+    }
+
+    #[test]
+    fn hashes_attributes_with_hmac() {
+        let dir = tempfile::tempdir().unwrap();
+        let keyring = KeyringFile::open(&dir.path().join("default.keyring"), |_, _| {
+            Ok(b"Jefe".to_vec())
+        })
+        .unwrap();
+
+        // HMAC-SHA256 test case 2 of RFC 4231
+        let hashed = keyring
+            .hash_attributes(&attributes(&[("name", "what do ya want for nothing?")]))
+            .unwrap();
+        assert_eq!(
+            hex(&hashed["name"]),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn iteration_limit() {
+        let (_dir, path) = fixture();
+        let mut keyring = KeyringFile::parse(FIXTURE).unwrap();
+        keyring.iteration_count = u32::MAX;
+        let mut data = FIXTURE[..HEADER.len() + 2].to_vec();
+        data.extend_from_slice(&zvariant::to_bytes(context(), &keyring).unwrap());
+        fs::write(&path, data).unwrap();
+
+        let err = KeyringFile::open(&path, |_, _| panic!("derived a key"))
+            .err()
+            .unwrap();
+        assert!(format!("{:#}", err).contains("too many"), "{:#}", err);
+    }
+
+    #[test]
+    fn wrong_master_secret() {
+        let (_dir, path) = fixture();
+        let keyring = open(&path, b"wrong").unwrap();
+
+        // the attributes don't hash to the stored values
+        assert!(keyring
+            .search(&attributes(&[("service", "other")]))
+            .unwrap()
+            .is_empty());
+        let err = keyring.search(&HashMap::new()).err().unwrap();
+        assert!(err.to_string().contains("wrong master secret"), "{:#}", err);
+    }
+
+    #[test]
+    fn round_trip() {
+        let (_dir, path) = fixture();
+        let mut keyring = open(&path, FIXTURE_SECRET).unwrap();
+        keyring.replace(&item("new.example.com", "secret")).unwrap();
+        keyring
+            .replace(&item("new.example.com", "changed"))
+            .unwrap();
+        keyring.write().unwrap();
+        drop(keyring);
+
+        let keyring = open(&path, FIXTURE_SECRET).unwrap();
+        let items = keyring
+            .search(&attributes(&[("service", "new.example.com")]))
+            .unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].secret, b"changed");
+        assert_eq!(items[0].label, "Password for new.example.com");
+        assert_eq!(keyring.search(&HashMap::new()).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn concurrent_writers_keep_each_others_items() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("default.keyring");
+        // derive the key once, PBKDF2 with the default iterations is slow in debug builds
+        let salt = std::sync::Arc::new(std::sync::Mutex::new(None::<(Vec<u8>, Vec<u8>)>));
+
+        let writers: Vec<_> = (0..4)
+            .map(|writer| {
+                let path = path.clone();
+                let salt = salt.clone();
+                std::thread::spawn(move || {
+                    for n in 0..5 {
+                        let mut keyring = KeyringFile::open(&path, |salt_bytes, iterations| {
+                            let mut cached = salt.lock().unwrap();
+                            if let Some((cached_salt, key)) = cached.as_ref() {
+                                if cached_salt == salt_bytes {
+                                    return Ok(key.clone());
+                                }
+                            }
+                            let key = KeyringFile::derive_key(b"secret", salt_bytes, iterations)?;
+                            cached.replace((salt_bytes.to_vec(), key.clone()));
+                            Ok(key)
+                        })
+                        .unwrap();
+                        let service = format!("{}-{}", writer, n);
+                        keyring.replace(&item(&service, "secret")).unwrap();
+                        keyring.write().unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let keyring = open(&path, b"secret").unwrap();
+        assert_eq!(keyring.search(&HashMap::new()).unwrap().len(), 20);
+    }
+}
@@ -0,0 +1,400 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::backend::{Backend, Entry};
+use crate::error::Error;
+use crate::options::{Options, PROFILE_ATTRIBUTE};
+use crate::purge::{PurgeOptions, PurgedItem};
+use crate::search::{FoundItem, SearchScope};
+use crate::secret_service::call::Caller;
+use anyhow::{anyhow, Context, Result};
+use futures_lite::StreamExt;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use zbus::blocking::Connection;
+use zvariant::{Fd, Value};
+
+mod keyring;
+// the proxies mirror the portal's interfaces, not everything in them is used
+#[allow(dead_code)]
+mod proxy;
+
+use keyring::{Item, KeyringFile};
+use proxy::{RequestProxy, SecretProxy};
+
+// exists in every Flatpak sandbox
+const FLATPAK_INFO: &str = "/.flatpak-info";
+
+// the `response` of a portal request the user cancelled
+const RESPONSE_CANCELLED: u32 = 1;
+
+/// Stores secrets for apps in a Flatpak sandbox, through the Secret portal.
+///
+/// The portal hands the app a master secret, which encrypts a keyring file in libsecret's format
+/// in the app's data directory, `~/.var/app/<app id>/data/keyrings/default.keyring`. Items have
+/// the same `application` and `service` attributes as with the Secret Service, plus `username`.
+///
+/// <https://flatpak.github.io/xdg-desktop-portal/docs/doc-org.freedesktop.portal.Secret.html>
+pub struct PortalKeyring<'a> {
+    username: &'a str,
+    service: &'a str,
+    application: &'a str,
+    options: Options,
+    path: PathBuf,
+    connection: Connection,
+    caller: Caller,
+    secret: RefCell<Option<Vec<u8>>>,
+    key: RefCell<Option<(Vec<u8>, Vec<u8>)>>,
+}
+
+impl<'a> PortalKeyring<'a> {
+    /// Returns a portal backend, failing outside of a Flatpak sandbox
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username to store secrets under
+    /// * `service` - A unique identifier within your application
+    /// * `application` - The name of your application
+    /// * `options` - Settings for the client, see [`Options`]
+    pub fn new(
+        username: &'a str,
+        service: &'a str,
+        application: &'a str,
+        options: Options,
+    ) -> Result<Self> {
+        if !Self::sandboxed() {
+            return Err(anyhow!("not running in a Flatpak sandbox"));
+        }
+        let data_dir = dirs::data_dir().ok_or_else(|| anyhow!("no data directory"))?;
+
+        Ok(Self {
+            username,
+            service,
+            application,
+            path: data_dir.join("keyrings").join("default.keyring"),
+            connection: Connection::session()?,
+            caller: Caller::new(&options),
+            options,
+            secret: RefCell::new(None),
+            key: RefCell::new(None),
+        })
+    }
+
+    /// Whether the process runs in a Flatpak sandbox
+    pub fn sandboxed() -> bool {
+        Path::new(FLATPAK_INFO).exists()
+    }
+
+    /// Stores items in the keyring file at `path` instead of the default one
+    pub fn with_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    fn attributes(&self) -> HashMap<&str, &str> {
+        let mut attributes = self.options.item_attributes();
+        attributes.insert("application", self.application);
+        attributes.insert("service", self.service);
+        attributes.insert("username", self.username);
+
+        attributes
+    }
+
+    /// The items of the entry, leaving out those of other profiles
+    fn items(&self, keyring: &KeyringFile) -> Result<Vec<Item>> {
+        let attributes = self.options.schema.lookup(self.attributes())?;
+
+        Ok(keyring
+            .search(&attributes)?
+            .into_iter()
+            .filter(|item| self.options.in_profile(&item.attributes))
+            .collect())
+    }
+
+    /// The items of the application in every profile
+    fn application_items(&self, keyring: &KeyringFile) -> Result<Vec<Item>> {
+        let mut attributes = HashMap::new();
+        attributes.insert("application", self.application);
+
+        keyring.search(&self.options.schema.lookup(attributes)?)
+    }
+
+    fn open(&self) -> Result<KeyringFile> {
+        // ask the portal before locking the file, other processes shouldn't wait for the user
+        self.master_secret()?;
+
+        KeyringFile::open(&self.path, |salt, iterations| self.key(salt, iterations))
+    }
+
+    /// Derives the keyring's key, reusing it while the salt doesn't change
+    fn key(&self, salt: &[u8], iterations: u32) -> Result<Vec<u8>> {
+        if let Some((cached_salt, key)) = self.key.borrow().as_ref() {
+            if cached_salt == salt {
+                return Ok(key.clone());
+            }
+        }
+
+        let key = KeyringFile::derive_key(&self.master_secret()?, salt, iterations)?;
+        self.key.replace(Some((salt.to_vec(), key.clone())));
+
+        Ok(key)
+    }
+
+    /// Returns the app's master secret, asking the portal for it the first time
+    fn master_secret(&self) -> Result<Vec<u8>> {
+        if let Some(secret) = self.secret.borrow().as_ref() {
+            return Ok(secret.clone());
+        }
+        span!("retrieve_secret");
+
+        let secret = self
+            .retrieve_secret()
+            .context("failed to retrieve the secret from the portal")?;
+        if secret.is_empty() {
+            return Err(anyhow!("the portal returned an empty secret"));
+        }
+        self.secret.replace(Some(secret.clone()));
+
+        Ok(secret)
+    }
+
+    /// Runs the portal's request/response flow, the secret is written to a pipe we pass along
+    fn retrieve_secret(&self) -> Result<Vec<u8>> {
+        let token = format!("keyring_rs_{}", std::process::id());
+        let sender = self
+            .connection
+            .unique_name()
+            .ok_or_else(|| anyhow!("the connection has no unique name"))?
+            .trim_start_matches(':')
+            .replace('.', "_");
+        let request_path = format!(
+            "/org/freedesktop/portal/desktop/request/{}/{}",
+            sender, token
+        );
+
+        let request: RequestProxy = self.caller.call(
+            RequestProxy::builder(self.connection.inner())
+                .path(request_path)?
+                .build(),
+        )?;
+        // subscribe before asking, so we can't miss the response
+        let mut responses = self.caller.call(request.receive_response())?;
+
+        let portal: SecretProxy = self
+            .caller
+            .call(SecretProxy::new(self.connection.inner()))?;
+        let (mut reader, writer) = std::io::pipe()?;
+        let mut options: HashMap<&str, Value<'_>> = HashMap::new();
+        options.insert("handle_token", token.as_str().into());
+        let handle = self
+            .caller
+            .call(portal.retrieve_secret(Fd::from(&writer), options))?;
+        drop(writer);
+        // portals older than the `handle_token` option pick their own path
+        if handle.as_str() != request.path().as_str() {
+            return Err(anyhow!("unexpected request {}", handle.as_str()));
+        }
+        event!(
+            debug,
+            request = handle.as_str(),
+            "requested the master secret"
+        );
+
+        let response = self.caller.wait_for_prompt(async {
+            responses
+                .next()
+                .await
+                .ok_or_else(|| zbus::Error::Failure("request went away".to_string()))
+        });
+        let response = match response {
+            Ok(response) => response,
+            Err(err) => {
+                let _ = self.caller.call(request.close());
+                return Err(err);
+            }
+        };
+        match response.args()?.response {
+            0 => {}
+            RESPONSE_CANCELLED => return Err(Error::PromptDismissed.into()),
+            response => return Err(anyhow!("the request failed ({})", response)),
+        }
+
+        let mut secret = Vec::new();
+        reader.read_to_end(&mut secret)?;
+
+        Ok(secret)
+    }
+}
+
+impl Backend for PortalKeyring<'_> {
+    fn name(&self) -> &'static str {
+        "flatpak-portal"
+    }
+
+    fn set_password(&self, password: String) -> Result<()> {
+        let mut keyring = self.open()?;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let stale = self.items(&keyring)?;
+        let created = stale.first().map_or(now, |item| item.created);
+        // copies stored without `xdg:schema` have other attributes, so `replace` would keep them
+        for item in &stale {
+            keyring.remove_item(item)?;
+        }
+        let item = Item {
+            attributes: self
+                .options
+                .schema
+                .stored(self.attributes())?
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            label: self
+                .options
+                .item_label(self.application, self.service, self.username),
+            created,
+            modified: now,
+            secret: password.into_bytes(),
+        };
+        keyring.replace(&item)?;
+
+        keyring.write()
+    }
+
+    fn get_password(&self) -> Result<Option<String>> {
+        let keyring = self.open()?;
+
+        match self.items(&keyring)?.into_iter().next() {
+            Some(item) => Ok(Some(String::from_utf8(item.secret)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn delete_password(&self) -> Result<bool> {
+        let mut keyring = self.open()?;
+        let items = self.items(&keyring)?;
+        if items.is_empty() {
+            return Ok(false);
+        }
+        for item in &items {
+            keyring.remove_item(item)?;
+        }
+        keyring.write()?;
+
+        Ok(true)
+    }
+
+    fn list(&self) -> Result<Vec<Entry>> {
+        let keyring = self.open()?;
+
+        Ok(self
+            .application_items(&keyring)?
+            .into_iter()
+            .filter(|item| self.options.in_profile(&item.attributes))
+            .filter_map(|mut item| {
+                Some(Entry {
+                    service: item.attributes.remove("service")?,
+                    username: item.attributes.remove("username")?,
+                })
+            })
+            .collect())
+    }
+
+    fn modified(&self) -> Result<Option<u64>> {
+        let keyring = self.open()?;
+
+        Ok(self.items(&keyring)?.first().map(|item| item.modified))
+    }
+
+    fn relabel(&self, label: &str) -> Result<bool> {
+        let mut keyring = self.open()?;
+        let items = self.items(&keyring)?;
+        if items.is_empty() {
+            return Ok(false);
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        for item in items {
+            keyring.replace(&Item {
+                label: label.to_string(),
+                modified: now,
+                ..item
+            })?;
+        }
+        keyring.write()?;
+
+        Ok(true)
+    }
+
+    fn purge(&self, options: &PurgeOptions) -> Result<Vec<PurgedItem>> {
+        let mut keyring = self.open()?;
+        let mut attributes = HashMap::new();
+        attributes.insert("application", self.application);
+        if let Some(service) = &options.service {
+            attributes.insert("service", service.as_str());
+        }
+        if let Some(profile) = &options.profile {
+            attributes.insert(PROFILE_ATTRIBUTE, profile.as_str());
+        }
+
+        let items = keyring.search(&self.options.schema.lookup(attributes)?)?;
+        if !options.dry_run && !items.is_empty() {
+            for item in &items {
+                keyring.remove_item(item)?;
+            }
+            keyring.write()?;
+        }
+
+        Ok(items
+            .into_iter()
+            .map(|item| PurgedItem {
+                backend: self.name(),
+                label: item.label,
+                attributes: item.attributes,
+            })
+            .collect())
+    }
+
+    /// Searches the keyring file, which is the only collection
+    fn search(
+        &self,
+        attributes: &HashMap<&str, &str>,
+        _scope: SearchScope,
+    ) -> Result<Vec<FoundItem<'_>>> {
+        let keyring = self.open()?;
+
+        Ok(keyring
+            .search(attributes)?
+            .into_iter()
+            .map(|item| {
+                let secret = item.secret;
+                FoundItem::new(
+                    item.label,
+                    item.attributes,
+                    item.created,
+                    item.modified,
+                    false,
+                    move || Ok(String::from_utf8(secret.clone())?),
+                )
+            })
+            .collect())
+    }
+
+    fn profiles(&self) -> Result<Vec<String>> {
+        let keyring = self.open()?;
+
+        let profiles: BTreeSet<String> = self
+            .application_items(&keyring)?
+            .into_iter()
+            .filter_map(|mut item| item.attributes.remove(PROFILE_ATTRIBUTE))
+            .collect();
+
+        Ok(profiles.into_iter().collect())
+    }
+}
@@ -0,0 +1,39 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::HashMap;
+use zbus::dbus_proxy;
+use zvariant::{Fd, OwnedObjectPath, OwnedValue, Value};
+
+/// https://flatpak.github.io/xdg-desktop-portal/docs/doc-org.freedesktop.portal.Secret.html
+#[dbus_proxy(
+    interface = "org.freedesktop.portal.Secret",
+    default_service = "org.freedesktop.portal.Desktop",
+    default_path = "/org/freedesktop/portal/desktop"
+)]
+trait Secret {
+    fn retrieve_secret(
+        &self,
+        fd: Fd,
+        options: HashMap<&str, Value<'_>>,
+    ) -> zbus::Result<OwnedObjectPath>;
+
+    #[dbus_proxy(property, name = "version")]
+    fn version(&self) -> zbus::fdo::Result<u32>;
+}
+
+/// https://flatpak.github.io/xdg-desktop-portal/docs/doc-org.freedesktop.portal.Request.html
+#[dbus_proxy(
+    interface = "org.freedesktop.portal.Request",
+    default_service = "org.freedesktop.portal.Desktop",
+    default_path = "/org/freedesktop/portal/desktop"
+)]
+trait Request {
+    fn close(&self) -> zbus::Result<()>;
+
+    #[dbus_proxy(signal)]
+    fn response(&self, response: u32, results: HashMap<String, OwnedValue>) -> zbus::Result<()>;
+}
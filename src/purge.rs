@@ -0,0 +1,31 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::HashMap;
+
+/// Which items [`KeyringClient::purge`](crate::KeyringClient::purge) removes.
+///
+/// By default every item of the application, in every profile and collection. The Secret
+/// Service's shared layouts need a `service`, see
+/// [`KeyringClient::purge`](crate::KeyringClient::purge).
+#[derive(Clone, Debug, Default)]
+pub struct PurgeOptions {
+    /// Only remove the items of this service
+    pub service: Option<String>,
+    /// Only remove the items of this profile, see [`Options::profile`](crate::Options::profile)
+    pub profile: Option<String>,
+    /// Report the items that would be removed without removing them
+    pub dry_run: bool,
+}
+
+/// An item a purge removed, or would have removed in a dry run.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PurgedItem {
+    /// The backend the item was in, e.g. `secret-service`
+    pub backend: &'static str,
+    pub label: String,
+    pub attributes: HashMap<String, String>,
+}
@@ -0,0 +1,464 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::backend::Backend;
+use crate::options::Options;
+use crate::{CredentialNames, SystemdCredentials};
+use crate::{EnvKeyring, FileKeyring, KeePassKeyring, MockKeyring, PassKeyring};
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, OnceLock, RwLock};
+
+#[cfg(target_os = "linux")]
+use crate::{KWallet, KernelKeyring, KeyringTarget, PortalKeyring};
+
+const FILE_PASSPHRASE_VARIABLE: &str = "KEYRING_FILE_PASSPHRASE";
+const KEEPASS_DATABASE_VARIABLE: &str = "KEYRING_KEEPASS_DATABASE";
+const KEEPASS_PASSWORD_VARIABLE: &str = "KEYRING_KEEPASS_PASSWORD";
+const KEEPASS_KEY_FILE_VARIABLE: &str = "KEYRING_KEEPASS_KEY_FILE";
+const KEEPASS_MAPPING_VARIABLE: &str = "KEYRING_KEEPASS_MAPPING";
+const CREDENTIAL_NAME_VARIABLE: &str = "KEYRING_CREDENTIAL_NAME";
+#[cfg(target_os = "linux")]
+const KEYUTILS_KEYRING_VARIABLE: &str = "KEYRING_KEYUTILS_KEYRING";
+
+type Factory =
+    Arc<dyn for<'a> Fn(&BackendTarget<'a>) -> Result<Box<dyn Backend + 'a>> + Send + Sync>;
+
+/// What a registered backend is created for.
+#[derive(Clone, Debug)]
+pub struct BackendTarget<'a> {
+    pub username: &'a str,
+    pub service: &'a str,
+    pub application: &'a str,
+    pub options: Options,
+}
+
+#[derive(Default)]
+struct Registry {
+    factories: BTreeMap<String, Factory>,
+    forced: Option<String>,
+}
+
+static REGISTRY: OnceLock<RwLock<Registry>> = OnceLock::new();
+
+/// Makes a backend selectable by `name`, replacing a backend registered under that name
+///
+/// The built-in backends are `secret-service`, `flatpak-portal`, `kwallet` and `keyutils` on Linux,
+/// `macos-keychain` on macOS, `wincred` on Windows, and `file`, `keepass`, `pass`,
+/// `systemd-credentials`, `env`, `mock` and, with the `vault` feature, `vault` everywhere.
+///
+/// The `file` backend's passphrase is `$KEYRING_FILE_PASSPHRASE`. The `keepass` backend opens
+/// `$KEYRING_KEEPASS_DATABASE` with `$KEYRING_KEEPASS_PASSWORD`, `$KEYRING_KEEPASS_KEY_FILE` or
+/// both, and maps services by `$KEYRING_KEEPASS_MAPPING`, `title` or `group`. The `keyutils`
+/// backend uses the kernel keyring named by `$KEYRING_KEYUTILS_KEYRING`, `session`, `user`,
+/// `process` or `persistent`, the user keyring by default. The `systemd-credentials` backend names
+/// credentials by the template in `$KEYRING_CREDENTIAL_NAME`, see [`CredentialNames`].
+///
+/// # Arguments
+///
+/// * `name` - The name to select the backend by, e.g. in `KEYRING_BACKEND`
+/// * `factory` - Creates the backend for a client
+pub fn register_backend<F>(name: &str, factory: F)
+where
+    F: for<'a> Fn(&BackendTarget<'a>) -> Result<Box<dyn Backend + 'a>> + Send + Sync + 'static,
+{
+    if let Ok(mut registry) = registry().write() {
+        registry
+            .factories
+            .insert(name.to_string(), Arc::new(factory));
+    }
+}
+
+/// The names of the registered backends
+pub fn backend_names() -> Vec<String> {
+    registry()
+        .read()
+        .map(|registry| registry.factories.keys().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// Makes every client created after this use the backend `name`, e.g. `mock` in tests, or the
+/// usual selection again with `None`
+///
+/// It takes precedence over `KEYRING_BACKEND` and the configuration file.
+pub fn force_backend(name: Option<&str>) {
+    if let Ok(mut registry) = registry().write() {
+        registry.forced = name.map(String::from);
+    }
+}
+
+/// The backend set with [`force_backend`]
+pub(crate) fn forced_backend() -> Option<String> {
+    registry()
+        .read()
+        .ok()
+        .and_then(|registry| registry.forced.clone())
+}
+
+/// Creates the backend registered as `name`
+pub(crate) fn create<'a>(name: &str, target: &BackendTarget<'a>) -> Result<Box<dyn Backend + 'a>> {
+    // don't hold the lock while the backend is created, it may create clients itself
+    let factory = registry()
+        .read()
+        .map_err(|_| anyhow!("a thread panicked while registering a backend"))?
+        .factories
+        .get(name)
+        .cloned();
+    let factory = factory.ok_or_else(|| {
+        anyhow!(
+            "unknown keyring backend {:?}, known are {}",
+            name,
+            backend_names().join(", ")
+        )
+    })?;
+    event!(debug, backend = name, "creating a registered backend");
+
+    factory(target)
+}
+
+/// Keeps tests that force a backend or set the variables backends are selected and created by
+/// from running at the same time
+#[cfg(test)]
+pub(crate) fn lock_selection() -> std::sync::MutexGuard<'static, ()> {
+    static SELECTION: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    SELECTION.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+fn registry() -> &'static RwLock<Registry> {
+    REGISTRY.get_or_init(|| {
+        let mut registry = Registry::default();
+        for (name, factory) in builtin() {
+            registry.factories.insert(name.to_string(), factory);
+        }
+
+        RwLock::new(registry)
+    })
+}
+
+fn builtin() -> Vec<(&'static str, Factory)> {
+    let mut factories: Vec<(&'static str, Factory)> = vec![
+        ("file", Arc::new(file)),
+        ("keepass", Arc::new(keepass)),
+        ("pass", Arc::new(pass)),
+        ("systemd-credentials", Arc::new(systemd_credentials)),
+        ("env", Arc::new(env)),
+        ("mock", Arc::new(mock)),
+    ];
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+    factories.push((platform_name(), Arc::new(platform)));
+    #[cfg(target_os = "linux")]
+    factories.extend(vec![
+        ("flatpak-portal", Arc::new(portal) as Factory),
+        ("kwallet", Arc::new(kwallet)),
+        ("keyutils", Arc::new(keyutils)),
+    ]);
+    #[cfg(feature = "vault")]
+    factories.push(("vault", Arc::new(vault)));
+
+    factories
+}
+
+#[cfg(target_os = "linux")]
+fn platform_name() -> &'static str {
+    "secret-service"
+}
+
+#[cfg(target_os = "macos")]
+fn platform_name() -> &'static str {
+    "macos-keychain"
+}
+
+#[cfg(target_os = "windows")]
+fn platform_name() -> &'static str {
+    "wincred"
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+fn platform<'a>(target: &BackendTarget<'a>) -> Result<Box<dyn Backend + 'a>> {
+    Ok(Box::new(crate::Keyring::new(
+        target.username,
+        target.service,
+        target.application,
+        target.options.clone(),
+    )?))
+}
+
+#[cfg(target_os = "linux")]
+fn portal<'a>(target: &BackendTarget<'a>) -> Result<Box<dyn Backend + 'a>> {
+    Ok(Box::new(PortalKeyring::new(
+        target.username,
+        target.service,
+        target.application,
+        target.options.clone(),
+    )?))
+}
+
+#[cfg(target_os = "linux")]
+fn kwallet<'a>(target: &BackendTarget<'a>) -> Result<Box<dyn Backend + 'a>> {
+    Ok(Box::new(KWallet::new(
+        target.username,
+        target.service,
+        target.application,
+        target.options.clone(),
+    )?))
+}
+
+#[cfg(target_os = "linux")]
+fn keyutils<'a>(target: &BackendTarget<'a>) -> Result<Box<dyn Backend + 'a>> {
+    target.options.no_profile("keyutils")?;
+    let keyring = match variable(KEYUTILS_KEYRING_VARIABLE) {
+        Some(keyring) => keyring.parse()?,
+        None => KeyringTarget::User,
+    };
+
+    Ok(Box::new(KernelKeyring::new(
+        target.username,
+        target.service,
+        target.application,
+        keyring,
+    )?))
+}
+
+#[cfg(feature = "vault")]
+fn vault<'a>(target: &BackendTarget<'a>) -> Result<Box<dyn Backend + 'a>> {
+    Ok(Box::new(crate::VaultKeyring::from_env(
+        target.username,
+        target.service,
+        target.application,
+        target.options.clone(),
+    )?))
+}
+
+fn file<'a>(target: &BackendTarget<'a>) -> Result<Box<dyn Backend + 'a>> {
+    let passphrase = std::env::var(FILE_PASSPHRASE_VARIABLE)
+        .map_err(|_| anyhow!("the file backend needs {}", FILE_PASSPHRASE_VARIABLE))?;
+
+    let keyring = FileKeyring::new(
+        target.username,
+        target.service,
+        target.application,
+        &passphrase,
+    )?;
+
+    Ok(match &target.options.profile {
+        Some(profile) => Box::new(keyring.with_profile(profile)),
+        None => Box::new(keyring),
+    })
+}
+
+fn keepass<'a>(target: &BackendTarget<'a>) -> Result<Box<dyn Backend + 'a>> {
+    target.options.no_profile("keepass")?;
+    let database = variable(KEEPASS_DATABASE_VARIABLE)
+        .ok_or_else(|| anyhow!("the keepass backend needs {}", KEEPASS_DATABASE_VARIABLE))?;
+    let password = variable(KEEPASS_PASSWORD_VARIABLE);
+    let key_file = variable(KEEPASS_KEY_FILE_VARIABLE);
+
+    let keyring = KeePassKeyring::new(
+        target.username,
+        target.service,
+        database,
+        password.as_deref(),
+        key_file.as_deref().map(Path::new),
+    )?;
+
+    Ok(match variable(KEEPASS_MAPPING_VARIABLE) {
+        Some(mapping) => Box::new(keyring.with_mapping(mapping.parse()?)),
+        None => Box::new(keyring),
+    })
+}
+
+fn pass<'a>(target: &BackendTarget<'a>) -> Result<Box<dyn Backend + 'a>> {
+    target.options.no_profile("pass")?;
+
+    Ok(Box::new(PassKeyring::new(target.username, target.service)?))
+}
+
+fn systemd_credentials<'a>(target: &BackendTarget<'a>) -> Result<Box<dyn Backend + 'a>> {
+    target.options.no_profile("systemd-credentials")?;
+    let names = match variable(CREDENTIAL_NAME_VARIABLE) {
+        Some(template) => CredentialNames::new(&template),
+        None => CredentialNames::default(),
+    };
+
+    Ok(Box::new(SystemdCredentials::new(
+        target.username,
+        target.service,
+        target.application,
+        names,
+    )?))
+}
+
+fn env<'a>(target: &BackendTarget<'a>) -> Result<Box<dyn Backend + 'a>> {
+    let keyring = EnvKeyring::new(target.username, target.service);
+
+    Ok(match &target.options.profile {
+        Some(profile) => Box::new(keyring.with_profile(profile)),
+        None => Box::new(keyring),
+    })
+}
+
+fn mock<'a>(target: &BackendTarget<'a>) -> Result<Box<dyn Backend + 'a>> {
+    let keyring = MockKeyring::new(target.username, target.service, target.application);
+
+    Ok(match &target.options.profile {
+        Some(profile) => Box::new(keyring.with_profile(profile)),
+        None => Box::new(keyring),
+    })
+}
+
+/// The value of an environment variable, unless it's unset or empty
+fn variable(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KeyringClient, BACKEND_VARIABLE};
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+    fn client(name: &str) -> Result<KeyringClient<'static>> {
+        KeyringClient::with_backend_name(
+            name,
+            "alice",
+            "registry",
+            "registry-tests",
+            Options::default(),
+        )
+    }
+
+    /// Creates a client the usual way, with `KEYRING_BACKEND` set to `backend`
+    fn select(backend: Option<&str>, configured: Option<&str>) -> Result<KeyringClient<'static>> {
+        if let Some(backend) = backend {
+            std::env::set_var(BACKEND_VARIABLE, backend);
+        }
+        let client = KeyringClient::select(
+            configured.map(String::from),
+            "alice",
+            "registry",
+            "registry-tests",
+            Options::default(),
+        );
+        std::env::remove_var(BACKEND_VARIABLE);
+
+        client
+    }
+
+    #[test]
+    fn registers_backends() {
+        register_backend("registry-test", |target| {
+            Ok(Box::new(MockKeyring::new(
+                target.username,
+                "registered",
+                target.application,
+            )))
+        });
+
+        assert!(backend_names().contains(&"registry-test".to_string()));
+        assert!(backend_names().contains(&"keepass".to_string()));
+        assert_eq!(client("registry-test").unwrap().backend_name(), "mock");
+    }
+
+    #[test]
+    fn unknown_backends_list_the_known_ones() {
+        let err = client("no-such-backend").err().unwrap().to_string();
+
+        assert!(
+            err.starts_with("unknown keyring backend \"no-such-backend\", known are "),
+            "{}",
+            err
+        );
+        assert!(err.contains("mock"), "{}", err);
+    }
+
+    #[test]
+    fn environment_selects_the_backend() {
+        let _selection = lock_selection();
+
+        assert_eq!(select(Some("mock"), None).unwrap().backend_name(), "mock");
+        assert_eq!(
+            select(Some("mock"), Some("env")).unwrap().backend_name(),
+            "mock"
+        );
+        assert_eq!(select(None, Some("mock")).unwrap().backend_name(), "mock");
+    }
+
+    #[test]
+    fn forced_backends_come_first() {
+        let _selection = lock_selection();
+
+        force_backend(Some("mock"));
+        let forced = forced_backend();
+        let client = select(Some("no-such-backend"), Some("no-such-backend"));
+        force_backend(None);
+
+        assert_eq!(forced.as_deref(), Some("mock"));
+        assert_eq!(client.unwrap().backend_name(), "mock");
+        assert_eq!(forced_backend(), None);
+    }
+
+    #[test]
+    fn opens_the_configured_keepass_database() {
+        let _selection = lock_selection();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keepass.kdbx");
+        std::fs::copy(Path::new(FIXTURES).join("keepass.kdbx"), &path).unwrap();
+
+        let missing = client("keepass").err().unwrap();
+        std::env::set_var(KEEPASS_DATABASE_VARIABLE, &path);
+        std::env::set_var(KEEPASS_PASSWORD_VARIABLE, "fixture password");
+        let opened = client("keepass").and_then(|client| client.get_password());
+        std::env::set_var(KEEPASS_MAPPING_VARIABLE, "folder");
+        let mapping = client("keepass").err().unwrap();
+        for name in [
+            KEEPASS_DATABASE_VARIABLE,
+            KEEPASS_PASSWORD_VARIABLE,
+            KEEPASS_MAPPING_VARIABLE,
+        ] {
+            std::env::remove_var(name);
+        }
+
+        assert_eq!(
+            missing.to_string(),
+            "the keepass backend needs KEYRING_KEEPASS_DATABASE"
+        );
+        assert_eq!(opened.unwrap(), None);
+        assert_eq!(mapping.to_string(), "unknown KeePass mapping \"folder\"");
+    }
+
+    #[test]
+    fn names_systemd_credentials() {
+        let _selection = lock_selection();
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("registry.alice"), "default").unwrap();
+        std::fs::write(dir.path().join("registry-tests-alice"), "templated").unwrap();
+
+        std::env::set_var("CREDENTIALS_DIRECTORY", dir.path());
+        let default = client("systemd-credentials").and_then(|client| client.get_password());
+        std::env::set_var(CREDENTIAL_NAME_VARIABLE, "{application}-{username}");
+        let templated = client("systemd-credentials").and_then(|client| client.get_password());
+        std::env::remove_var(CREDENTIAL_NAME_VARIABLE);
+        std::env::remove_var("CREDENTIALS_DIRECTORY");
+
+        assert_eq!(default.unwrap().as_deref(), Some("default"));
+        assert_eq!(templated.unwrap().as_deref(), Some("templated"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn selects_the_kernel_keyring() {
+        let _selection = lock_selection();
+
+        std::env::set_var(KEYUTILS_KEYRING_VARIABLE, "thread");
+        let err = client("keyutils").err().unwrap();
+        std::env::remove_var(KEYUTILS_KEYRING_VARIABLE);
+
+        assert_eq!(err.to_string(), "unknown kernel keyring \"thread\"");
+    }
+}
@@ -0,0 +1,250 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::options::PROFILE_ATTRIBUTE;
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, HashMap};
+
+/// The attribute libsecret stores the schema name in
+pub(crate) const SCHEMA_ATTRIBUTE: &str = "xdg:schema";
+
+/// The type of a schema attribute's value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AttributeType {
+    String,
+    /// A 32 bit signed integer, written in decimal
+    Integer,
+    /// `true` or `false`
+    Boolean,
+}
+
+/// Declares the attributes of a kind of item, like libsecret's `SecretSchema`.
+///
+/// Items are stored with the schema's name in the `xdg:schema` attribute, which GNOME tools like
+/// Seahorse and libsecret-based apps use to group and find them. Attributes are checked against
+/// the schema whenever items are stored or searched.
+///
+/// <https://gnome.pages.gitlab.gnome.org/libsecret/struct.Schema.html>
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Schema {
+    name: String,
+    attributes: BTreeMap<String, AttributeType>,
+    match_name: bool,
+}
+
+impl Schema {
+    /// Returns a schema without attributes
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - A dotted name, like `org.example.App.Password`
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            attributes: BTreeMap::new(),
+            match_name: true,
+        }
+    }
+
+    /// Declares an attribute
+    pub fn attribute(mut self, name: &str, kind: AttributeType) -> Self {
+        self.attributes.insert(name.to_string(), kind);
+        self
+    }
+
+    /// Leaves `xdg:schema` out of searches, so items stored without it are found too
+    ///
+    /// The same as libsecret's `SECRET_SCHEMA_DONT_MATCH_NAME`.
+    pub fn dont_match_name(mut self) -> Self {
+        self.match_name = false;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The type of the attribute `name`, if the schema declares it
+    pub fn attribute_type(&self, name: &str) -> Option<AttributeType> {
+        self.attributes.get(name).copied()
+    }
+
+    /// Checks that the schema declares every attribute and that the values have the declared types
+    ///
+    /// `xdg:schema` is always rejected, the schema sets it itself.
+    pub fn validate(&self, attributes: &HashMap<&str, &str>) -> Result<()> {
+        for (&name, &value) in attributes {
+            if name == SCHEMA_ATTRIBUTE {
+                return Err(anyhow!(
+                    "{} is set by the schema {}, it can't be given",
+                    SCHEMA_ATTRIBUTE,
+                    self.name
+                ));
+            }
+            let kind = self
+                .attribute_type(name)
+                .ok_or_else(|| anyhow!("the schema {} has no attribute {:?}", self.name, name))?;
+            let expected = match kind {
+                AttributeType::String => continue,
+                AttributeType::Integer if value.parse::<i32>().is_ok() => continue,
+                AttributeType::Boolean if value == "true" || value == "false" => continue,
+                AttributeType::Integer => "an integer",
+                AttributeType::Boolean => "true or false",
+            };
+
+            return Err(anyhow!(
+                "the attribute {:?} of the schema {} must be {}, not {:?}",
+                name,
+                self.name,
+                expected,
+                value
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Validates the attributes of an item to store and adds the schema name
+    pub(crate) fn stored<'a>(
+        &'a self,
+        mut attributes: HashMap<&'a str, &'a str>,
+    ) -> Result<HashMap<&'a str, &'a str>> {
+        self.validate(&attributes)?;
+        attributes.insert(SCHEMA_ATTRIBUTE, &self.name);
+
+        Ok(attributes)
+    }
+
+    /// Validates the attributes of a search and adds the schema name, unless it's not matched
+    pub(crate) fn lookup<'a>(
+        &'a self,
+        mut attributes: HashMap<&'a str, &'a str>,
+    ) -> Result<HashMap<&'a str, &'a str>> {
+        self.validate(&attributes)?;
+        if self.match_name {
+            attributes.insert(SCHEMA_ATTRIBUTE, &self.name);
+        }
+
+        Ok(attributes)
+    }
+}
+
+/// The schema of the items this crate stores
+///
+/// Its name isn't matched, items stored before it existed have no `xdg:schema`.
+impl Default for Schema {
+    fn default() -> Self {
+        Self::new("io.github.tmuntaner.Keyring")
+            .attribute("application", AttributeType::String)
+            .attribute("service", AttributeType::String)
+            .attribute("username", AttributeType::String)
+            .attribute(PROFILE_ATTRIBUTE, AttributeType::String)
+            .dont_match_name()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema() -> Schema {
+        Schema::new("org.example.App.Password")
+            .attribute("service", AttributeType::String)
+            .attribute("port", AttributeType::Integer)
+            .attribute("shared", AttributeType::Boolean)
+    }
+
+    fn attributes<'a>(pairs: &[(&'a str, &'a str)]) -> HashMap<&'a str, &'a str> {
+        pairs.iter().copied().collect()
+    }
+
+    #[test]
+    fn accepts_declared_attributes() {
+        let valid = attributes(&[
+            ("service", "example.com"),
+            ("port", "-8080"),
+            ("shared", "false"),
+        ]);
+
+        schema().validate(&valid).unwrap();
+        schema().validate(&HashMap::new()).unwrap();
+    }
+
+    #[test]
+    fn rejects_unknown_attributes() {
+        let err = schema()
+            .validate(&attributes(&[("username", "alice")]))
+            .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "the schema org.example.App.Password has no attribute \"username\""
+        );
+    }
+
+    #[test]
+    fn rejects_mistyped_values() {
+        let cases = [
+            ("port", "eighty", "an integer"),
+            ("port", "2147483648", "an integer"),
+            ("port", "", "an integer"),
+            ("shared", "yes", "true or false"),
+            ("shared", "True", "true or false"),
+        ];
+        for (name, value, expected) in cases {
+            let err = schema()
+                .validate(&attributes(&[(name, value)]))
+                .unwrap_err();
+
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "the attribute {:?} of the schema org.example.App.Password must be {}, not {:?}",
+                    name, expected, value
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_setting_the_schema_name() {
+        let injected = attributes(&[("service", "example.com"), (SCHEMA_ATTRIBUTE, "org.other")]);
+
+        assert!(schema().stored(injected.clone()).is_err());
+        assert!(schema().lookup(injected.clone()).is_err());
+
+        // also when a schema declares it, which would let a search with an unmatched name pick
+        // another schema's items
+        let declared = schema()
+            .attribute(SCHEMA_ATTRIBUTE, AttributeType::String)
+            .dont_match_name();
+        let err = declared.lookup(injected).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "xdg:schema is set by the schema org.example.App.Password, it can't be given"
+        );
+    }
+
+    #[test]
+    fn adds_the_schema_name() {
+        let schema = schema();
+        let stored = schema
+            .stored(attributes(&[("service", "example.com")]))
+            .unwrap();
+        assert_eq!(stored[SCHEMA_ATTRIBUTE], "org.example.App.Password");
+
+        let lookup = schema
+            .lookup(attributes(&[("service", "example.com")]))
+            .unwrap();
+        assert_eq!(lookup[SCHEMA_ATTRIBUTE], "org.example.App.Password");
+
+        let unmatched = schema.clone().dont_match_name();
+        let lookup = unmatched
+            .lookup(attributes(&[("service", "example.com")]))
+            .unwrap();
+        assert!(!lookup.contains_key(SCHEMA_ATTRIBUTE));
+    }
+}
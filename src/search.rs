@@ -0,0 +1,77 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Where [`KeyringClient::search`](crate::KeyringClient::search) looks for items.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SearchScope {
+    /// The client's collection, see [`Options::collection`](crate::Options::collection)
+    Collection,
+    /// Every collection of the keyring, including locked ones
+    AllCollections,
+}
+
+/// An item found by a search.
+///
+/// The metadata is read during the search, the secret only when [`FoundItem::secret`] is called.
+pub struct FoundItem<'a> {
+    pub label: String,
+    pub attributes: HashMap<String, String>,
+    /// When the item was created, in seconds since the unix epoch
+    pub created: u64,
+    /// When the item was last modified, in seconds since the unix epoch
+    pub modified: u64,
+    /// Whether the item was locked when it was found
+    ///
+    /// Keyrings may hide the metadata of locked items, and reading the secret asks the user to
+    /// unlock it.
+    pub locked: bool,
+    secret: Box<dyn Fn() -> Result<String> + 'a>,
+}
+
+impl<'a> FoundItem<'a> {
+    /// Returns a found item, `secret` reads its secret
+    pub(crate) fn new<F>(
+        label: String,
+        attributes: HashMap<String, String>,
+        created: u64,
+        modified: u64,
+        locked: bool,
+        secret: F,
+    ) -> Self
+    where
+        F: Fn() -> Result<String> + 'a,
+    {
+        Self {
+            label,
+            attributes,
+            created,
+            modified,
+            locked,
+            secret: Box::new(secret),
+        }
+    }
+
+    /// Reads the item's secret, unlocking the item first if needed
+    pub fn secret(&self) -> Result<String> {
+        (self.secret)()
+    }
+}
+
+impl fmt::Debug for FoundItem<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FoundItem")
+            .field("label", &self.label)
+            .field("attributes", &self.attributes)
+            .field("created", &self.created)
+            .field("modified", &self.modified)
+            .field("locked", &self.locked)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,106 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::error::Error;
+use crate::options::{Options, PromptPolicy, RetryPolicy};
+use anyhow::Result;
+use async_io::Timer;
+use std::future::Future;
+use std::time::Duration;
+
+/// Runs D-Bus calls with the client's timeout and retry settings.
+///
+/// zbus' blocking proxies wait for a reply forever, so calls go through the async proxies and race
+/// against a timer instead.
+#[derive(Clone, Debug)]
+pub struct Caller {
+    timeout: Option<Duration>,
+    prompt_timeout: Option<Duration>,
+    prompt: PromptPolicy,
+    retry: RetryPolicy,
+}
+
+impl Caller {
+    pub fn new(options: &Options) -> Self {
+        Self {
+            timeout: options.call_timeout,
+            prompt_timeout: options.prompt_timeout,
+            prompt: options.prompt,
+            retry: options.retry.clone(),
+        }
+    }
+
+    /// Whether prompts may be shown, see [`PromptPolicy`]
+    pub fn may_prompt(&self) -> bool {
+        self.prompt == PromptPolicy::Allow
+    }
+
+    /// Runs a call once, for calls that aren't safe to repeat
+    pub fn call<T, E, F>(&self, future: F) -> Result<T>
+    where
+        E: std::error::Error + Send + Sync + 'static,
+        F: Future<Output = std::result::Result<T, E>>,
+    {
+        Self::with_timeout(self.timeout, future)
+    }
+
+    /// Runs an idempotent call, retrying it when it fails with a transient error
+    ///
+    /// A call that timed out isn't retried, so a hung daemon blocks for at most one timeout.
+    pub fn call_idempotent<T, E, F, Fut>(&self, f: F) -> Result<T>
+    where
+        E: std::error::Error + Send + Sync + 'static,
+        F: Fn() -> Fut,
+        Fut: Future<Output = std::result::Result<T, E>>,
+    {
+        let mut backoff = self.retry.backoff;
+        let mut attempt = 1;
+
+        loop {
+            match self.call(f()) {
+                Err(err) if attempt < self.retry.attempts && Error::transient(&err) => {
+                    event!(
+                        debug,
+                        attempt,
+                        ?backoff,
+                        "retrying transient failure: {:#}",
+                        err
+                    );
+                    std::thread::sleep(backoff);
+                    backoff = std::cmp::min(backoff * 2, self.retry.max_backoff);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Waits for a future that depends on the user, like a prompt being answered
+    pub fn wait_for_prompt<T, E, F>(&self, future: F) -> Result<T>
+    where
+        E: std::error::Error + Send + Sync + 'static,
+        F: Future<Output = std::result::Result<T, E>>,
+    {
+        Self::with_timeout(self.prompt_timeout, future)
+    }
+
+    fn with_timeout<T, E, F>(timeout: Option<Duration>, future: F) -> Result<T>
+    where
+        E: std::error::Error + Send + Sync + 'static,
+        F: Future<Output = std::result::Result<T, E>>,
+    {
+        let future = async { future.await.map_err(anyhow::Error::new) };
+
+        match timeout {
+            Some(timeout) => async_io::block_on(futures_lite::future::or(future, async {
+                Timer::after(timeout).await;
+                event!(warn, ?timeout, "keyring call timed out");
+                Err(Error::Timeout(timeout).into())
+            })),
+            None => async_io::block_on(future),
+        }
+    }
+}
@@ -4,12 +4,16 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+use crate::schema::Schema;
+use crate::secret_service::call::Caller;
 use crate::secret_service::item::Item;
+use crate::secret_service::prompt;
 use crate::secret_service::proxy::secrets::Secret;
-use crate::secret_service::proxy::secrets_collection::CollectionProxyBlocking;
+use crate::secret_service::proxy::secrets_collection::CollectionProxy;
 use crate::secret_service::session::SERVICE_NAME;
 use anyhow::Result;
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use zbus::blocking::Connection;
 use zvariant::{Dict, OwnedObjectPath, Value};
 
@@ -17,10 +21,11 @@ pub const ITEM_LABEL: &str = "org.freedesktop.Secret.Item.Label";
 pub const ITEM_ATTRIBUTES: &str = "org.freedesktop.Secret.Item.Attributes";
 
 pub struct Collection<'a> {
-    proxy: CollectionProxyBlocking<'a>,
+    proxy: CollectionProxy<'a>,
     connection: Connection,
     session_path: OwnedObjectPath,
     aes_key: Vec<u8>,
+    caller: Caller,
 }
 
 impl Collection<'_> {
@@ -29,31 +34,33 @@ impl Collection<'_> {
         session_path: OwnedObjectPath,
         aes_key: Vec<u8>,
         path: String,
+        caller: Caller,
     ) -> Result<Collection<'a>> {
-        let proxy = CollectionProxyBlocking::builder(&connection)
-            .destination(SERVICE_NAME.to_string())?
-            .path(path)?
-            .build()?;
+        let proxy = caller.call(
+            CollectionProxy::builder(connection.inner())
+                .destination(SERVICE_NAME.to_string())?
+                .path(path)?
+                .build(),
+        )?;
 
         Ok(Collection {
             proxy,
             connection,
             aes_key,
             session_path,
+            caller,
         })
     }
 
+    /// Stores a secret, replacing the item with the same attributes, and returns the item's path
     pub fn create_item(
         &self,
         secret: String,
         label: String,
+        schema: &Schema,
         attributes: HashMap<&str, &str>,
-    ) -> Result<()> {
-        let mut properties: HashMap<&str, Value> = HashMap::new();
-        let attributes: Dict = attributes.into();
-
-        properties.insert(ITEM_LABEL, label.into());
-        properties.insert(ITEM_ATTRIBUTES, attributes.into());
+    ) -> Result<OwnedObjectPath> {
+        let attributes = schema.stored(attributes)?;
         let secret = Secret::new(
             self.session_path.clone(),
             self.aes_key.clone(),
@@ -61,21 +68,93 @@ impl Collection<'_> {
             String::from("text/plain"),
         )?;
 
-        let _created_item = self.proxy.create_item(properties, secret, true)?;
+        self.store(secret, label, attributes)
+    }
+
+    /// Copies an item into the collection, keeping its label, attributes and secret as they are
+    ///
+    /// The attributes aren't checked against a schema, the item may be stored by another
+    /// application. The secret is passed on still encrypted for the session, so secrets that
+    /// aren't text keep their bytes and content type.
+    pub fn copy_item(&self, item: &Item<'_>) -> Result<OwnedObjectPath> {
+        let secret = item.secret()?;
+        let attributes = item.attributes()?;
+
+        self.store(
+            secret,
+            item.label()?,
+            attributes
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str()))
+                .collect(),
+        )
+    }
+
+    fn store(
+        &self,
+        secret: Secret,
+        label: String,
+        attributes: HashMap<&str, &str>,
+    ) -> Result<OwnedObjectPath> {
+        span!("create_item", collection = self.proxy.path().as_str(), %label, ?attributes);
+
+        let mut properties: HashMap<&str, Value> = HashMap::new();
+        let attributes: Dict = attributes.into();
+
+        properties.insert(ITEM_LABEL, label.into());
+        properties.insert(ITEM_ATTRIBUTES, attributes.into());
+
+        let created_item = self
+            .caller
+            .call(self.proxy.create_item(properties, secret, true))?;
+        // a locked collection has to be unlocked by the user before the item is created
+        let result = prompt::complete(&self.connection, &self.caller, created_item.prompt())?;
+        let item = match result {
+            // after a prompt, the item is the prompt's result
+            Some(path) if created_item.item().as_str() == "/" => OwnedObjectPath::try_from(path)?,
+            _ => created_item.item().clone(),
+        };
+        event!(debug, item = item.as_str(), "created item");
 
-        Ok(())
+        Ok(item)
+    }
+
+    pub fn search_paths(
+        &self,
+        schema: &Schema,
+        attributes: HashMap<&str, &str>,
+    ) -> Result<Vec<OwnedObjectPath>> {
+        self.search_items(schema.lookup(attributes)?)
+    }
+
+    /// Finds the items with all of `attributes`, which aren't checked against a schema
+    pub fn search_items(&self, attributes: HashMap<&str, &str>) -> Result<Vec<OwnedObjectPath>> {
+        span!(
+            "search_items",
+            collection = self.proxy.path().as_str(),
+            ?attributes
+        );
+
+        let paths = self
+            .caller
+            .call_idempotent(|| self.proxy.search_items(attributes.clone()))?;
+        event!(debug, matches = paths.len(), items = ?paths, "searched items");
+
+        Ok(paths)
+    }
+
+    pub fn item(&self, path: &OwnedObjectPath) -> Result<Item<'_>> {
+        let path = path.as_str().to_string();
+
+        Item::new(
+            self.connection.clone(),
+            self.session_path.clone(),
+            path,
+            self.caller.clone(),
+        )
     }
 
-    pub fn search(&self, attributes: HashMap<&str, &str>) -> Result<Vec<Secret>> {
-        let item_paths = self.proxy.search_items(attributes)?;
-
-        item_paths
-            .into_iter()
-            .map(|item| {
-                let path = item.as_str().to_string();
-                Item::new(self.connection.clone(), self.session_path.clone(), path)
-            })
-            .map(|item| item?.secret())
-            .collect()
+    pub fn path(&self) -> OwnedObjectPath {
+        self.proxy.path().clone().into()
     }
 }
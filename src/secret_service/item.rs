@@ -4,16 +4,21 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+use crate::secret_service::call::Caller;
+use crate::secret_service::prompt;
 use crate::secret_service::proxy::secrets::Secret;
-use crate::secret_service::proxy::secrets_item::ItemProxyBlocking;
+use crate::secret_service::proxy::secrets_item::ItemProxy;
 use crate::secret_service::session::SERVICE_NAME;
-use anyhow::{anyhow, Result};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
 use zbus::blocking::Connection;
 use zvariant::OwnedObjectPath;
 
 pub struct Item<'a> {
-    proxy: ItemProxyBlocking<'a>,
+    proxy: ItemProxy<'a>,
+    connection: Connection,
     session_path: OwnedObjectPath,
+    caller: Caller,
 }
 
 impl Item<'_> {
@@ -21,21 +26,66 @@ impl Item<'_> {
         connection: Connection,
         session_path: OwnedObjectPath,
         path: String,
+        caller: Caller,
     ) -> Result<Item<'a>> {
-        let proxy = ItemProxyBlocking::builder(&connection)
-            .destination(SERVICE_NAME.to_string())?
-            .path(path)?
-            .build()?;
+        let proxy = caller.call(
+            ItemProxy::builder(connection.inner())
+                .destination(SERVICE_NAME.to_string())?
+                .path(path)?
+                .build(),
+        )?;
 
         Ok(Item {
             proxy,
+            connection,
             session_path,
+            caller,
         })
     }
 
     pub fn secret(&self) -> Result<Secret> {
-        self.proxy
-            .get_secret(&self.session_path)
-            .map_err(|_| anyhow!("failed to get secret"))
+        event!(debug, item = self.proxy.path().as_str(), "getting secret");
+
+        self.caller
+            .call_idempotent(|| self.proxy.get_secret(&self.session_path))
+            .context("failed to get secret")
+    }
+
+    pub fn attributes(&self) -> Result<HashMap<String, String>> {
+        self.caller.call_idempotent(|| self.proxy.attributes())
+    }
+
+    pub fn modified(&self) -> Result<u64> {
+        self.caller.call_idempotent(|| self.proxy.modified())
+    }
+
+    pub fn created(&self) -> Result<u64> {
+        self.caller.call_idempotent(|| self.proxy.created())
+    }
+
+    pub fn label(&self) -> Result<String> {
+        self.caller.call_idempotent(|| self.proxy.label())
+    }
+
+    pub fn locked(&self) -> Result<bool> {
+        self.caller.call_idempotent(|| self.proxy.locked())
+    }
+
+    pub fn set_label(&self, label: &str) -> Result<()> {
+        event!(debug, item = self.proxy.path().as_str(), %label, "relabeling item");
+
+        self.caller.call(self.proxy.set_label(label))?;
+
+        Ok(())
+    }
+
+    /// Deletes the item, prompting to unlock it if needed
+    pub fn delete(&self) -> Result<()> {
+        event!(debug, item = self.proxy.path().as_str(), "deleting item");
+
+        let prompt = self.caller.call(self.proxy.delete())?;
+        prompt::complete(&self.connection, &self.caller, &prompt)?;
+
+        Ok(())
     }
 }
@@ -4,7 +4,12 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+pub mod call;
 pub mod collection;
-mod item;
+pub mod item;
+mod prompt;
+// the proxies mirror the whole specification, not everything in it is used yet
+#[allow(dead_code)]
 mod proxy;
 pub mod session;
+pub mod watch;
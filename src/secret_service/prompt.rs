@@ -0,0 +1,72 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::error::Error;
+use crate::secret_service::call::Caller;
+use crate::secret_service::proxy::secrets_prompt::PromptProxy;
+use crate::secret_service::session::SERVICE_NAME;
+use anyhow::Result;
+use futures_lite::StreamExt;
+use zbus::blocking::Connection;
+use zvariant::{ObjectPath, OwnedValue};
+
+/// Shows the prompt at `path` and waits for the user to answer it.
+///
+/// Returns the prompt's result, or `None` if `path` is `/`, which the service uses when no prompt
+/// is needed. Fails with [`Error::PromptRequired`] without showing it when the client's
+/// [`PromptPolicy`](crate::PromptPolicy) denies prompts.
+///
+/// <https://specifications.freedesktop.org/secret-service/latest/ch09.html>
+pub fn complete(
+    connection: &Connection,
+    caller: &Caller,
+    path: &ObjectPath<'_>,
+) -> Result<Option<OwnedValue>> {
+    if path.as_str() == "/" {
+        return Ok(None);
+    }
+    span!("prompt", prompt = path.as_str());
+    if !caller.may_prompt() {
+        event!(debug, "prompting isn't allowed");
+        return Err(Error::PromptRequired.into());
+    }
+
+    let proxy: PromptProxy = caller.call(
+        PromptProxy::builder(connection.inner())
+            .destination(SERVICE_NAME)?
+            .path(path.to_owned())?
+            .build(),
+    )?;
+
+    // subscribe before prompting, so we can't miss the signal
+    let mut completed = caller.call(proxy.receive_completed())?;
+    caller.call(proxy.prompt(""))?;
+
+    let signal = caller.wait_for_prompt(async {
+        completed
+            .next()
+            .await
+            .ok_or_else(|| zbus::Error::Failure("prompt went away".to_string()))
+    });
+    let signal = match signal {
+        Ok(signal) => signal,
+        Err(err) => {
+            // don't leave the dialog open when we stopped waiting for it
+            event!(debug, "stopped waiting for the prompt, dismissing it");
+            let _ = caller.call(proxy.dismiss());
+            return Err(err);
+        }
+    };
+
+    let args = signal.args()?;
+    if args.dismissed {
+        event!(debug, "prompt dismissed");
+        return Err(Error::PromptDismissed.into());
+    }
+    event!(debug, "prompt completed");
+
+    Ok(Some(args.result.into()))
+}
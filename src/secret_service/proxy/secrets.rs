@@ -52,6 +52,15 @@ pub trait Secrets {
 
     fn set_alias(&self, name: &str, collection: ObjectPath<'_>) -> zbus::Result<()>;
 
+    #[dbus_proxy(signal)]
+    fn collection_created(&self, collection: ObjectPath<'_>) -> zbus::Result<()>;
+
+    #[dbus_proxy(signal)]
+    fn collection_deleted(&self, collection: ObjectPath<'_>) -> zbus::Result<()>;
+
+    #[dbus_proxy(signal)]
+    fn collection_changed(&self, collection: ObjectPath<'_>) -> zbus::Result<()>;
+
     #[dbus_proxy(property)]
     fn collections(&self) -> zbus::fdo::Result<Vec<ObjectPath<'_>>>;
 }
@@ -88,6 +97,16 @@ pub struct SearchItemsResult {
     locked: Vec<OwnedObjectPath>,
 }
 
+impl SearchItemsResult {
+    pub fn unlocked(&self) -> &[OwnedObjectPath] {
+        &self.unlocked
+    }
+
+    pub fn locked(&self) -> &[OwnedObjectPath] {
+        &self.locked
+    }
+}
+
 #[derive(Deserialize, Serialize, Type)]
 pub struct LockResult {
     object_paths: Vec<OwnedObjectPath>,
@@ -100,6 +119,12 @@ pub struct UnlockResult {
     prompt: OwnedObjectPath,
 }
 
+impl UnlockResult {
+    pub fn prompt(&self) -> &OwnedObjectPath {
+        &self.prompt
+    }
+}
+
 /// https://specifications.freedesktop.org/secret-service/latest/ch14.html
 #[derive(Deserialize, Serialize, Type)]
 pub struct Secret {
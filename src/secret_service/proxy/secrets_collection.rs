@@ -29,6 +29,15 @@ trait Collection {
         replace: bool,
     ) -> zbus::Result<CreateItemResult>;
 
+    #[dbus_proxy(signal)]
+    fn item_created(&self, item: ObjectPath<'_>) -> zbus::Result<()>;
+
+    #[dbus_proxy(signal)]
+    fn item_deleted(&self, item: ObjectPath<'_>) -> zbus::Result<()>;
+
+    #[dbus_proxy(signal)]
+    fn item_changed(&self, item: ObjectPath<'_>) -> zbus::Result<()>;
+
     #[dbus_proxy(property)]
     fn items(&self) -> zbus::fdo::Result<Vec<ObjectPath<'_>>>;
 
@@ -53,3 +62,13 @@ pub struct CreateItemResult {
     item: OwnedObjectPath,
     prompt: OwnedObjectPath,
 }
+
+impl CreateItemResult {
+    pub fn item(&self) -> &OwnedObjectPath {
+        &self.item
+    }
+
+    pub fn prompt(&self) -> &OwnedObjectPath {
+        &self.prompt
+    }
+}
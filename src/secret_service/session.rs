@@ -4,19 +4,28 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use crate::secret_service::proxy::secrets::{OpenSessionResult, SecretsProxyBlocking};
+use crate::secret_service::call::Caller;
+use crate::secret_service::prompt;
+use crate::secret_service::proxy::secrets::{OpenSessionResult, SecretsProxy};
 use anyhow::Result;
 use hkdf::Hkdf;
 use openssl::bn::BigNum;
 use sha2::Sha256;
 use std::borrow::Borrow;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use zbus::blocking::Connection;
 use zvariant::OwnedObjectPath;
 
 pub const SERVICE_NAME: &str = "org.freedesktop.secrets";
 
 pub struct Session<'a> {
-    secrets: SecretsProxyBlocking<'a>,
+    inner: RefCell<SessionInner<'a>>,
+    caller: Caller,
+}
+
+struct SessionInner<'a> {
+    secrets: SecretsProxy<'a>,
     connection: Connection,
     session_path: OwnedObjectPath,
     aes_key: Vec<u8>,
@@ -24,8 +33,29 @@ pub struct Session<'a> {
 
 pub const DH_ALGORITHM: &str = "dh-ietf1024-sha256-aes128-cbc-pkcs7";
 
-impl Session<'_> {
-    pub fn new() -> Result<Self> {
+impl<'a> Session<'a> {
+    pub fn new(caller: Caller) -> Result<Self> {
+        let inner = Self::open(&caller)?;
+
+        Ok(Self {
+            inner: RefCell::new(inner),
+            caller,
+        })
+    }
+
+    /// Opens a new connection and session, replacing the current ones
+    ///
+    /// Used when the daemon restarted or closed our session on its side.
+    pub fn reconnect(&self) -> Result<()> {
+        let inner = Self::open(&self.caller)?;
+        self.inner.replace(inner);
+
+        Ok(())
+    }
+
+    fn open(caller: &Caller) -> Result<SessionInner<'a>> {
+        span!("open_session", algorithm = DH_ALGORITHM);
+
         let generator = BigNum::from_u32(2u32)?;
         let p = BigNum::get_rfc2409_prime_1024()?;
         let dh = openssl::dh::Dh::from_pqg(p, None, generator)?;
@@ -33,9 +63,9 @@ impl Session<'_> {
         let public_key = key.public_key();
 
         let connection = zbus::blocking::Connection::session()?;
-        let secrets: SecretsProxyBlocking = SecretsProxyBlocking::new(&connection)?;
-        let session: OpenSessionResult =
-            secrets.open_session(DH_ALGORITHM, public_key.to_vec().as_slice().into())?;
+        let secrets: SecretsProxy = caller.call(SecretsProxy::new(connection.inner()))?;
+        let session: OpenSessionResult = caller
+            .call(secrets.open_session(DH_ALGORITHM, public_key.to_vec().as_slice().into()))?;
 
         let server_public_key = session.server_public_key()?;
         let common_key = key.compute_key(server_public_key.borrow())?;
@@ -48,8 +78,9 @@ impl Session<'_> {
         hk.expand(&info, &mut okm).expect("hkdf should not fail");
 
         let aes_key = okm.to_vec();
+        event!(debug, session = session.result().as_str(), "opened session");
 
-        Ok(Self {
+        Ok(SessionInner {
             secrets,
             connection,
             aes_key,
@@ -57,19 +88,70 @@ impl Session<'_> {
         })
     }
 
-    pub fn secrets_proxy(&self) -> &SecretsProxyBlocking {
-        &self.secrets
+    /// Returns the path of the collection the alias `name` points to
+    pub fn read_alias(&self, name: &str) -> Result<OwnedObjectPath> {
+        let secrets = self.inner.borrow().secrets.clone();
+
+        let path = self.caller.call_idempotent(|| secrets.read_alias(name))?;
+        event!(
+            debug,
+            alias = name,
+            collection = path.as_str(),
+            "resolved alias"
+        );
+
+        Ok(path)
+    }
+
+    /// Searches every collection, returns the unlocked and the locked items
+    pub fn search_items(
+        &self,
+        attributes: HashMap<&str, &str>,
+    ) -> Result<(Vec<OwnedObjectPath>, Vec<OwnedObjectPath>)> {
+        span!("search_all_items", ?attributes);
+        let secrets = self.inner.borrow().secrets.clone();
+
+        let result = self
+            .caller
+            .call_idempotent(|| secrets.search_items(attributes.clone()))?;
+        event!(
+            debug,
+            unlocked = result.unlocked().len(),
+            locked = result.locked().len(),
+            "searched all collections"
+        );
+
+        Ok((result.unlocked().to_vec(), result.locked().to_vec()))
+    }
+
+    /// Unlocks items or collections, prompting the user if the service asks to
+    pub fn unlock(&self, paths: &[OwnedObjectPath]) -> Result<()> {
+        let (secrets, connection) = {
+            let inner = self.inner.borrow();
+            (inner.secrets.clone(), inner.connection.clone())
+        };
+        event!(debug, objects = ?paths, "unlocking");
+
+        let objects = paths.iter().map(|path| &**path).collect();
+        let result = self.caller.call(secrets.unlock(objects))?;
+        prompt::complete(&connection, &self.caller, result.prompt())?;
+
+        Ok(())
+    }
+
+    pub fn caller(&self) -> Caller {
+        self.caller.clone()
     }
 
     pub fn aes_key(&self) -> Vec<u8> {
-        self.aes_key.clone()
+        self.inner.borrow().aes_key.clone()
     }
 
     pub fn connection(&self) -> Connection {
-        self.connection.clone()
+        self.inner.borrow().connection.clone()
     }
 
     pub fn session_path(&self) -> OwnedObjectPath {
-        self.session_path.clone()
+        self.inner.borrow().session_path.clone()
     }
 }
@@ -0,0 +1,432 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::secret_service::call::Caller;
+use crate::secret_service::item::Item;
+use crate::secret_service::proxy::secrets::{
+    CollectionChanged, CollectionCreated, CollectionDeleted,
+};
+use crate::secret_service::proxy::secrets_collection::{
+    CollectionProxy, ItemChanged, ItemCreated, ItemDeleted,
+};
+use crate::secret_service::session::SERVICE_NAME;
+use anyhow::Result;
+use async_io::Async;
+use futures_lite::{future, StreamExt};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use zbus::blocking::Connection;
+use zbus::fdo::{DBusProxy, NameOwnerChanged};
+use zbus::names::{BusName, OwnedUniqueName};
+use zbus::{Message, MessageStream, MessageType, OwnedMatchRule};
+use zvariant::OwnedObjectPath;
+
+const SERVICE_PATH: &str = "/org/freedesktop/secrets";
+const BUS_NAME: &str = "org.freedesktop.DBus";
+
+/// The kind of change reported for a stored secret.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeKind {
+    Created,
+    Changed,
+    Deleted,
+}
+
+/// A change to a secret matching the client's attributes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChangeEvent {
+    kind: ChangeKind,
+    path: String,
+}
+
+impl ChangeEvent {
+    pub fn kind(&self) -> ChangeKind {
+        self.kind
+    }
+
+    /// The D-Bus object path of the changed item
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+/// A blocking iterator over changes to the items of a collection.
+///
+/// Listens for the `ItemCreated`, `ItemChanged` and `ItemDeleted` signals of the collection and the
+/// `CollectionCreated`, `CollectionChanged` and `CollectionDeleted` signals of the service, and
+/// only yields events for items whose attributes match the ones the watcher was created with.
+/// When the collection itself is created or changed, e.g. unlocked, its items are searched again
+/// and the differences are reported as item events.
+///
+/// Only signals sent by the current owner of `org.freedesktop.secrets` are handled, other peers
+/// can send signals to the watcher directly. The owner is looked up when the watcher is created
+/// and followed through `NameOwnerChanged`.
+///
+/// An error for one signal, e.g. for an item that was deleted before its attributes were read,
+/// doesn't end the iterator.
+///
+/// <https://specifications.freedesktop.org/secret-service/latest/ch07.html>
+pub struct Watcher {
+    messages: MessageStream,
+    owner_changes: MessageStream,
+    owner: Option<OwnedUniqueName>,
+    stop: Option<Async<UnixStream>>,
+    connection: Connection,
+    session_path: OwnedObjectPath,
+    collection_path: OwnedObjectPath,
+    attributes: HashMap<String, String>,
+    known: HashSet<OwnedObjectPath>,
+    pending: VecDeque<ChangeEvent>,
+    caller: Caller,
+}
+
+impl Watcher {
+    pub fn new(
+        connection: Connection,
+        session_path: OwnedObjectPath,
+        collection_path: OwnedObjectPath,
+        attributes: HashMap<String, String>,
+        known: Vec<OwnedObjectPath>,
+        caller: Caller,
+    ) -> Result<Self> {
+        // the bus only routes broadcasts by the rule's sender, see `Watcher::from_owner`
+        let rule: OwnedMatchRule = zbus::MatchRule::builder()
+            .msg_type(MessageType::Signal)
+            .sender(SERVICE_NAME)?
+            .path_namespace(SERVICE_PATH)?
+            .build()
+            .into();
+        let messages = async_io::block_on(MessageStream::for_match_rule(
+            rule,
+            connection.inner(),
+            None,
+        ))?;
+
+        // subscribed before the owner is looked up, so no change in between is missed
+        let rule: OwnedMatchRule = zbus::MatchRule::builder()
+            .msg_type(MessageType::Signal)
+            .sender(BUS_NAME)?
+            .interface(BUS_NAME)?
+            .member("NameOwnerChanged")?
+            .arg(0, SERVICE_NAME)?
+            .build()
+            .into();
+        let owner_changes = async_io::block_on(MessageStream::for_match_rule(
+            rule,
+            connection.inner(),
+            None,
+        ))?;
+        let bus = caller.call(DBusProxy::new(connection.inner()))?;
+        let name = BusName::from_static_str(SERVICE_NAME)?;
+        let owner = caller.call_idempotent(|| bus.get_name_owner(name.clone()))?;
+
+        Ok(Self {
+            messages,
+            owner_changes,
+            owner: Some(owner),
+            stop: None,
+            connection,
+            session_path,
+            collection_path,
+            attributes,
+            known: known.into_iter().collect(),
+            pending: VecDeque::new(),
+            caller,
+        })
+    }
+
+    /// Returns a guard that ends the iterator when it's dropped, even while it waits for a signal
+    pub(crate) fn stop_guard(&mut self) -> Result<StopGuard> {
+        let (reader, writer) = UnixStream::pair()?;
+        self.stop = Some(Async::new(reader)?);
+
+        Ok(StopGuard { _writer: writer })
+    }
+
+    /// Waits for the next signal, or returns `None` once the stream ends or the watcher is stopped
+    fn receive(&mut self) -> Option<zbus::Result<Arc<Message>>> {
+        // zbus queues a message before later ones, so preferring owner changes handles them in
+        // the order the bus sent them
+        let mut messages = (&mut self.owner_changes).or(&mut self.messages);
+        let next = async { messages.next().await };
+
+        match &self.stop {
+            // closing the other end makes the socket readable
+            Some(stop) => async_io::block_on(future::or(next, async {
+                stop.readable().await.ok();
+                None
+            })),
+            None => async_io::block_on(next),
+        }
+    }
+
+    /// Whether `message` was sent by `owner`
+    fn from_owner(message: &Message, owner: Option<&str>) -> Result<bool> {
+        let header = message.header()?;
+        let sender = header.sender()?.map(|sender| sender.as_str());
+
+        Ok(owner.is_some() && sender == owner)
+    }
+
+    fn handle(&mut self, message: Arc<Message>) -> Result<()> {
+        if let Some(signal) = NameOwnerChanged::from_message(message.clone()) {
+            if Self::from_owner(&message, Some(BUS_NAME))? {
+                let args = signal.args()?;
+                if args.name() == SERVICE_NAME {
+                    let owner = args
+                        .new_owner()
+                        .as_ref()
+                        .map(|owner| owner.to_owned().into());
+                    event!(debug, ?owner, "secret service owner changed");
+                    self.owner = owner;
+                }
+            }
+
+            return Ok(());
+        }
+        // zbus doesn't match a well-known sender itself, so signals sent to us directly by any
+        // peer arrive here too
+        if !Self::from_owner(&message, self.owner.as_deref().map(|owner| owner.as_str()))? {
+            event!(debug, "ignoring a signal from another peer");
+            return Ok(());
+        }
+
+        if let Some(signal) = CollectionDeleted::from_message(message.clone()) {
+            if signal.args()?.collection() == &*self.collection_path {
+                for path in self.known.drain() {
+                    self.pending.push_back(ChangeEvent {
+                        kind: ChangeKind::Deleted,
+                        path: path.as_str().to_string(),
+                    });
+                }
+            }
+
+            return Ok(());
+        }
+        if let Some(signal) = CollectionCreated::from_message(message.clone()) {
+            if signal.args()?.collection() == &*self.collection_path {
+                self.rescan()?;
+            }
+
+            return Ok(());
+        }
+        if let Some(signal) = CollectionChanged::from_message(message.clone()) {
+            if signal.args()?.collection() == &*self.collection_path {
+                self.rescan()?;
+            }
+
+            return Ok(());
+        }
+
+        if message.path().as_ref() != Some(&*self.collection_path) {
+            return Ok(());
+        }
+
+        if let Some(signal) = ItemCreated::from_message(message.clone()) {
+            let path: OwnedObjectPath = signal.args()?.item().clone().into();
+            if self.matches(&path)? {
+                self.known.insert(path.clone());
+                self.push(ChangeKind::Created, path);
+            }
+        } else if let Some(signal) = ItemChanged::from_message(message.clone()) {
+            let path: OwnedObjectPath = signal.args()?.item().clone().into();
+            if self.matches(&path)? {
+                self.known.insert(path.clone());
+                self.push(ChangeKind::Changed, path);
+            } else if self.known.remove(&path) {
+                // the item's attributes no longer match ours, so it's gone as far as we're concerned
+                self.push(ChangeKind::Deleted, path);
+            }
+        } else if let Some(signal) = ItemDeleted::from_message(message) {
+            let path: OwnedObjectPath = signal.args()?.item().clone().into();
+            if self.known.remove(&path) {
+                self.push(ChangeKind::Deleted, path);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn matches(&self, path: &OwnedObjectPath) -> Result<bool> {
+        let item = Item::new(
+            self.connection.clone(),
+            self.session_path.clone(),
+            path.as_str().to_string(),
+            self.caller.clone(),
+        )?;
+        let attributes = item.attributes()?;
+
+        Ok(self
+            .attributes
+            .iter()
+            .all(|(key, value)| attributes.get(key) == Some(value)))
+    }
+
+    /// Searches the collection again and reports the items that appeared or disappeared
+    fn rescan(&mut self) -> Result<()> {
+        let proxy = self.caller.call(
+            CollectionProxy::builder(self.connection.inner())
+                .destination(SERVICE_NAME.to_string())?
+                .path(self.collection_path.clone())?
+                .build(),
+        )?;
+        let attributes: HashMap<&str, &str> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+        let found: HashSet<OwnedObjectPath> = self
+            .caller
+            .call_idempotent(|| proxy.search_items(attributes.clone()))?
+            .into_iter()
+            .collect();
+
+        let created: Vec<OwnedObjectPath> = found.difference(&self.known).cloned().collect();
+        let deleted: Vec<OwnedObjectPath> = self.known.difference(&found).cloned().collect();
+        for path in created {
+            self.push(ChangeKind::Created, path);
+        }
+        for path in deleted {
+            self.push(ChangeKind::Deleted, path);
+        }
+        self.known = found;
+
+        Ok(())
+    }
+
+    fn push(&mut self, kind: ChangeKind, path: OwnedObjectPath) {
+        event!(debug, ?kind, item = path.as_str(), "item changed");
+        self.pending.push_back(ChangeEvent {
+            kind,
+            path: path.as_str().to_string(),
+        });
+    }
+}
+
+impl Iterator for Watcher {
+    type Item = Result<ChangeEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(Ok(event));
+            }
+
+            let message = match self.receive()? {
+                Ok(message) => message,
+                Err(err) => return Some(Err(err.into())),
+            };
+            if let Err(err) = self.handle(message) {
+                return Some(Err(err));
+            }
+        }
+    }
+}
+
+/// Stops the [`Watcher`] it was taken from when dropped, which removes its match rule.
+pub(crate) struct StopGuard {
+    _writer: UnixStream,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::Options;
+    use crate::test_bus::PrivateBus;
+    use std::convert::TryFrom;
+    use zvariant::ObjectPath;
+
+    const COLLECTION: &str = "/org/freedesktop/secrets/collection/login";
+
+    fn item(n: usize) -> String {
+        format!("{}/{}", COLLECTION, n)
+    }
+
+    fn watch(connection: Connection, known: &[String]) -> Watcher {
+        Watcher::new(
+            connection,
+            OwnedObjectPath::try_from("/").unwrap(),
+            OwnedObjectPath::try_from(COLLECTION).unwrap(),
+            HashMap::new(),
+            known
+                .iter()
+                .map(|path| OwnedObjectPath::try_from(path.as_str()).unwrap())
+                .collect(),
+            Caller::new(&Options::default()),
+        )
+        .unwrap()
+    }
+
+    /// Sends `ItemDeleted` to the watcher only, like a peer forging it would
+    fn item_deleted(from: &Connection, to: &Connection, item: &str) {
+        let to = to.unique_name().unwrap().to_string();
+        from.emit_signal(
+            Some(to.as_str()),
+            COLLECTION,
+            "org.freedesktop.Secret.Collection",
+            "ItemDeleted",
+            &ObjectPath::try_from(item).unwrap(),
+        )
+        .unwrap();
+        // the bus routed the signal once it answers
+        zbus::blocking::fdo::DBusProxy::new(from)
+            .unwrap()
+            .get_id()
+            .unwrap();
+    }
+
+    #[test]
+    fn ignores_signals_from_other_peers() {
+        let bus = match PrivateBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+        let service = bus.connect().name(SERVICE_NAME).unwrap().build().unwrap();
+        let peer = bus.connect().build().unwrap();
+        let connection = bus.connect().build().unwrap();
+        let mut watcher = watch(connection.clone(), &[item(1), item(2)]);
+
+        item_deleted(&peer, &connection, &item(1));
+        item_deleted(&service, &connection, &item(2));
+
+        let event = watcher.next().unwrap().unwrap();
+        assert_eq!(
+            (event.kind(), event.path()),
+            (ChangeKind::Deleted, &*item(2))
+        );
+        assert_eq!(watcher.known.len(), 1);
+    }
+
+    #[test]
+    fn follows_the_owner() {
+        let bus = match PrivateBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+        let old = bus.connect().name(SERVICE_NAME).unwrap().build().unwrap();
+        let connection = bus.connect().build().unwrap();
+        let mut watcher = watch(connection.clone(), &[item(1), item(2)]);
+
+        // the service restarted
+        old.release_name(SERVICE_NAME).unwrap();
+        let new = bus.connect().name(SERVICE_NAME).unwrap().build().unwrap();
+
+        item_deleted(&old, &connection, &item(1));
+        item_deleted(&new, &connection, &item(2));
+
+        let event = watcher.next().unwrap().unwrap();
+        assert_eq!(
+            (event.kind(), event.path()),
+            (ChangeKind::Deleted, &*item(2))
+        );
+        assert_eq!(watcher.known.len(), 1);
+        assert_eq!(
+            watcher.owner.as_ref().map(|owner| owner.as_str()),
+            new.unique_name().map(|name| name.as_str())
+        );
+    }
+}
@@ -0,0 +1,253 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::backend::Backend;
+use crate::error::Error;
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+const CREDENTIALS_DIRECTORY: &str = "CREDENTIALS_DIRECTORY";
+
+/// Reads secrets that systemd passed to the service with `LoadCredential=` or
+/// `LoadCredentialEncrypted=`.
+///
+/// The credentials are files in `$CREDENTIALS_DIRECTORY`, named by a [`CredentialNames`] mapping.
+/// The backend is read-only, so creating it fails outside of a unit with credentials, which lets a
+/// [`ChainKeyring`](crate::ChainKeyring) fall back to a desktop keyring.
+///
+/// <https://systemd.io/CREDENTIALS/>
+pub struct SystemdCredentials<'a> {
+    username: &'a str,
+    service: &'a str,
+    application: &'a str,
+    directory: PathBuf,
+    names: CredentialNames,
+}
+
+/// Maps a service and username to a credential name.
+///
+/// Explicit names win, everything else is named by a template where `{application}`, `{service}`
+/// and `{username}` are replaced. The default template is `{service}.{username}`.
+#[derive(Clone, Debug)]
+pub struct CredentialNames {
+    template: String,
+    names: HashMap<(String, String), String>,
+}
+
+impl<'a> SystemdCredentials<'a> {
+    /// Returns a backend reading from `$CREDENTIALS_DIRECTORY`
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username the secret is stored under
+    /// * `service` - A unique identifier within your application
+    /// * `application` - The name of your application
+    /// * `names` - How entries map to credential names
+    pub fn new(
+        username: &'a str,
+        service: &'a str,
+        application: &'a str,
+        names: CredentialNames,
+    ) -> Result<Self> {
+        let directory = std::env::var_os(CREDENTIALS_DIRECTORY)
+            .ok_or_else(|| anyhow!("${} is not set", CREDENTIALS_DIRECTORY))?;
+
+        Ok(Self::with_directory(
+            username,
+            service,
+            application,
+            directory,
+            names,
+        ))
+    }
+
+    /// Returns a backend reading the credentials in `directory`, e.g. for credentials a service
+    /// passes on to its child processes
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The username the secret is stored under
+    /// * `service` - A unique identifier within your application
+    /// * `application` - The name of your application
+    /// * `directory` - The directory with the credentials
+    /// * `names` - How entries map to credential names
+    pub fn with_directory<P: Into<PathBuf>>(
+        username: &'a str,
+        service: &'a str,
+        application: &'a str,
+        directory: P,
+        names: CredentialNames,
+    ) -> Self {
+        Self {
+            username,
+            service,
+            application,
+            directory: directory.into(),
+            names,
+        }
+    }
+
+    fn path(&self) -> Result<PathBuf> {
+        let name = self
+            .names
+            .resolve(self.application, self.service, self.username);
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(anyhow!("{:?} is not a valid credential name", name));
+        }
+
+        Ok(self.directory.join(name))
+    }
+}
+
+impl Backend for SystemdCredentials<'_> {
+    fn name(&self) -> &'static str {
+        "systemd-credentials"
+    }
+
+    fn set_password(&self, _password: String) -> Result<()> {
+        Err(Error::unsupported(self.name(), "storing secrets").into())
+    }
+
+    /// Returns the credential's contents, without a single trailing newline
+    fn get_password(&self) -> Result<Option<String>> {
+        let path = self.path()?;
+
+        let mut secret = match fs::read_to_string(&path) {
+            Ok(secret) => secret,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).context(format!("failed to read {:?}", path)),
+        };
+        if secret.ends_with('\n') {
+            secret.pop();
+        }
+
+        Ok(Some(secret))
+    }
+
+    fn modified(&self) -> Result<Option<u64>> {
+        let path = self.path()?;
+
+        match fs::metadata(&path) {
+            Ok(metadata) => Ok(Some(
+                metadata.modified()?.duration_since(UNIX_EPOCH)?.as_secs(),
+            )),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).context(format!("failed to read {:?}", path)),
+        }
+    }
+}
+
+impl CredentialNames {
+    /// Returns a mapping naming credentials by `template`
+    pub fn new(template: &str) -> Self {
+        Self {
+            template: template.to_string(),
+            names: HashMap::new(),
+        }
+    }
+
+    /// Maps a service and username to an explicit credential name
+    pub fn insert(&mut self, service: &str, username: &str, name: &str) {
+        self.names.insert(
+            (service.to_string(), username.to_string()),
+            name.to_string(),
+        );
+    }
+
+    /// Returns the credential name for an entry
+    pub fn resolve(&self, application: &str, service: &str, username: &str) -> String {
+        match self.names.get(&(service.to_string(), username.to_string())) {
+            Some(name) => name.clone(),
+            None => self
+                .template
+                .replace("{application}", application)
+                .replace("{service}", service)
+                .replace("{username}", username),
+        }
+    }
+}
+
+impl Default for CredentialNames {
+    fn default() -> Self {
+        Self::new("{service}.{username}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials<'a>(dir: &tempfile::TempDir, names: CredentialNames) -> SystemdCredentials<'a> {
+        SystemdCredentials::with_directory("alice", "example.com", "app", dir.path(), names)
+    }
+
+    #[test]
+    fn reads_credentials() {
+        let dir = tempfile::tempdir().unwrap();
+        let keyring = credentials(&dir, CredentialNames::default());
+        assert_eq!(keyring.get_password().unwrap(), None);
+        assert_eq!(keyring.modified().unwrap(), None);
+
+        fs::write(dir.path().join("example.com.alice"), "secret\n\n").unwrap();
+        // only a single trailing newline is dropped
+        assert_eq!(keyring.get_password().unwrap().as_deref(), Some("secret\n"));
+        assert!(keyring.modified().unwrap().is_some());
+    }
+
+    #[test]
+    fn names_credentials() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut names = CredentialNames::new("{application}-{service}-{username}");
+        names.insert("other.com", "alice", "explicit");
+        fs::write(dir.path().join("app-example.com-alice"), "templated").unwrap();
+        fs::write(dir.path().join("explicit"), "explicit").unwrap();
+
+        let templated = credentials(&dir, names.clone());
+        let explicit =
+            SystemdCredentials::with_directory("alice", "other.com", "app", dir.path(), names);
+        assert_eq!(
+            templated.get_password().unwrap().as_deref(),
+            Some("templated")
+        );
+        assert_eq!(
+            explicit.get_password().unwrap().as_deref(),
+            Some("explicit")
+        );
+    }
+
+    #[test]
+    fn rejects_names_outside_the_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        for template in ["", "..", "../{service}", "{service}/{username}"] {
+            let keyring = credentials(&dir, CredentialNames::new(template));
+
+            let err = keyring.get_password().unwrap_err();
+            assert!(
+                err.to_string().ends_with("is not a valid credential name"),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn is_read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let keyring = credentials(&dir, CredentialNames::default());
+
+        let err = keyring.set_password("secret".to_string()).unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(Error::Unsupported { .. })),
+            "{:#}",
+            err
+        );
+        assert!(keyring.delete_password().is_err());
+    }
+}
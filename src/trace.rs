@@ -0,0 +1,54 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Instrumentation that compiles to nothing without the `tracing` feature.
+//!
+//! Never pass secret values to these macros, only attributes, labels and object paths.
+
+/// Emits a `tracing` event at the given level, e.g. `event!(debug, path = %path, "found item")`
+#[cfg(feature = "tracing")]
+macro_rules! event {
+    ($level:ident, $($arg:tt)*) => {
+        tracing::$level!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! event {
+    ($level:ident, $($arg:tt)*) => {};
+}
+
+/// Reports something users should see even without the `tracing` feature, like a reconnect,
+/// e.g. `report!(warn, "reconnecting")`
+///
+/// With the feature it's a `tracing` event, which `tracing` passes on to `log` when there is no
+/// subscriber, so it's reported once either way.
+#[cfg(feature = "tracing")]
+macro_rules! report {
+    ($level:ident, $($arg:tt)*) => {
+        tracing::$level!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! report {
+    ($level:ident, $($arg:tt)*) => {
+        log::$level!($($arg)*)
+    };
+}
+
+/// Enters a debug span until the end of the enclosing scope, e.g. `span!("open_session");`
+#[cfg(feature = "tracing")]
+macro_rules! span {
+    ($($arg:tt)*) => {
+        let _span = tracing::debug_span!($($arg)*).entered();
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! span {
+    ($($arg:tt)*) => {};
+}
@@ -0,0 +1,903 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use crate::backend::{Backend, Entry};
+use crate::options::Options;
+use crate::KeyringClient;
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use ureq::{Agent, AgentBuilder, Request};
+
+const ADDRESS_VARIABLE: &str = "VAULT_ADDR";
+const TOKEN_VARIABLE: &str = "VAULT_TOKEN";
+const NAMESPACE_VARIABLE: &str = "VAULT_NAMESPACE";
+const DEFAULT_ADDRESS: &str = "https://127.0.0.1:8200";
+const DEFAULT_MOUNT: &str = "secret";
+
+// the keyring entry holding a token is the Vault address under this service
+const TOKEN_SERVICE: &str = "vault-token";
+
+// how often a write is retried when another client changed the secret in between
+const CAS_ATTEMPTS: u32 = 3;
+
+/// Stores secrets in a [HashiCorp Vault](https://developer.hashicorp.com/vault/docs/secrets/kv/kv-v2)
+/// KV version 2 mount, for secrets shared by a team.
+///
+/// The service is the secret's path in the mount, below an optional prefix, and the username a key
+/// in it, so all usernames of a service share one secret. Every change writes a new version of
+/// the secret with check-and-set, so concurrent writers don't overwrite each other, and earlier
+/// versions stay readable with [`VaultKeyring::get_version`].
+pub struct VaultKeyring<'a> {
+    username: &'a str,
+    service: &'a str,
+    address: String,
+    token: String,
+    namespace: Option<String>,
+    mount: String,
+    prefix: String,
+    agent: Agent,
+}
+
+/// A version of a secret.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VaultVersion {
+    pub version: u64,
+    /// Seconds since the unix epoch
+    pub created: u64,
+    /// Whether the version was deleted, it can still be undeleted
+    pub deleted: bool,
+    /// Whether the version was destroyed for good
+    pub destroyed: bool,
+}
+
+#[derive(Deserialize)]
+struct Response<T> {
+    data: T,
+}
+
+#[derive(Deserialize)]
+struct Secret {
+    data: Option<Map<String, Value>>,
+    metadata: VersionMetadata,
+}
+
+#[derive(Deserialize)]
+struct VersionMetadata {
+    version: u64,
+    created_time: String,
+}
+
+#[derive(Deserialize)]
+struct Metadata {
+    versions: HashMap<String, VersionInfo>,
+}
+
+#[derive(Deserialize)]
+struct VersionInfo {
+    created_time: String,
+    #[serde(default)]
+    deletion_time: String,
+    #[serde(default)]
+    destroyed: bool,
+}
+
+#[derive(Deserialize)]
+struct Keys {
+    keys: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Errors {
+    #[serde(default)]
+    errors: Vec<String>,
+}
+
+impl<'a> VaultKeyring<'a> {
+    /// Returns a backend for the `secret` mount of the Vault server at `address`
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The key to store secrets under
+    /// * `service` - The secret's path, may contain `/`
+    /// * `address` - The server, e.g. `https://vault.example.com:8200`
+    /// * `token` - The token to authenticate with
+    /// * `options` - Settings for the client, only `call_timeout` is used and profiles aren't
+    ///   supported
+    pub fn new(
+        username: &'a str,
+        service: &'a str,
+        address: &str,
+        token: &str,
+        options: Options,
+    ) -> Result<Self> {
+        options.no_profile("vault")?;
+        let mut agent = AgentBuilder::new();
+        if let Some(timeout) = options.call_timeout {
+            agent = agent.timeout(timeout);
+        }
+
+        Ok(Self {
+            username,
+            service,
+            address: address.trim_end_matches('/').to_string(),
+            token: token.to_string(),
+            namespace: None,
+            mount: String::from(DEFAULT_MOUNT),
+            prefix: String::new(),
+            agent: agent.build(),
+        })
+    }
+
+    /// Returns a backend configured like the `vault` CLI
+    ///
+    /// The server is `$VAULT_ADDR` and the namespace `$VAULT_NAMESPACE`. The token is
+    /// `$VAULT_TOKEN`, or else the password the OS keyring stores for the username `$VAULT_ADDR`
+    /// and the service `vault-token` in `application`. The token is always read from the OS
+    /// keyring, not the backend selected by `KEYRING_BACKEND` or
+    /// [`force_backend`](crate::force_backend), which may be this one.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` - The key to store secrets under
+    /// * `service` - The secret's path, may contain `/`
+    /// * `application` - The name of your application, for finding the token in the keyring
+    /// * `options` - Settings for the client, see [`Options`]
+    pub fn from_env(
+        username: &'a str,
+        service: &'a str,
+        application: &str,
+        options: Options,
+    ) -> Result<Self> {
+        options.no_profile("vault")?;
+        let address =
+            std::env::var(ADDRESS_VARIABLE).unwrap_or_else(|_| String::from(DEFAULT_ADDRESS));
+        let token = match std::env::var(TOKEN_VARIABLE) {
+            Ok(token) => token,
+            Err(_) => {
+                KeyringClient::platform(&address, TOKEN_SERVICE, application, options.clone())?
+                    .get_password()
+                    .context("failed to read the Vault token from the keyring")?
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "no Vault token, set {} or store one in the keyring",
+                            TOKEN_VARIABLE
+                        )
+                    })?
+            }
+        };
+
+        let vault = Self::new(username, service, &address, &token, options)?;
+        Ok(match std::env::var(NAMESPACE_VARIABLE) {
+            Ok(namespace) if !namespace.is_empty() => vault.with_namespace(&namespace),
+            _ => vault,
+        })
+    }
+
+    /// Uses the KV mount at `mount` instead of `secret`
+    pub fn with_mount(mut self, mount: &str) -> Self {
+        self.mount = mount.trim_matches('/').to_string();
+        self
+    }
+
+    /// Stores secrets below `prefix` in the mount, e.g. `teams/payments`
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.trim_matches('/').to_string();
+        self
+    }
+
+    /// Sends requests to the Vault Enterprise namespace `namespace`
+    pub fn with_namespace(mut self, namespace: &str) -> Self {
+        self.namespace = Some(namespace.to_string());
+        self
+    }
+
+    /// Returns the versions of the service's secret, oldest first
+    pub fn versions(&self) -> Result<Vec<VaultVersion>> {
+        let (status, body) = self.send(self.request("GET", "metadata", &self.path()), None)?;
+        if status == 404 {
+            return Ok(Vec::new());
+        }
+        let metadata: Response<Metadata> = serde_json::from_str(&body)?;
+
+        let mut versions = metadata
+            .data
+            .versions
+            .into_iter()
+            .map(|(version, info)| {
+                Ok(VaultVersion {
+                    version: version.parse()?,
+                    created: parse_time(&info.created_time).unwrap_or_default(),
+                    deleted: !info.deletion_time.is_empty(),
+                    destroyed: info.destroyed,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        versions.sort_by_key(|version| version.version);
+
+        Ok(versions)
+    }
+
+    /// Returns the password as it was in `version` of the secret
+    pub fn get_version(&self, version: u64) -> Result<Option<String>> {
+        match self.read(Some(version))? {
+            Some(secret) => self.password(&secret),
+            None => Ok(None),
+        }
+    }
+
+    /// The secret's path in the mount
+    fn path(&self) -> String {
+        self.service_path(self.service)
+    }
+
+    fn service_path(&self, service: &str) -> String {
+        if self.prefix.is_empty() {
+            service.to_string()
+        } else {
+            format!("{}/{}", self.prefix, service)
+        }
+    }
+
+    /// Returns a request for the KV endpoint `kind`, e.g. `data` or `metadata`, of `path`
+    fn request(&self, method: &str, kind: &str, path: &str) -> Request {
+        let path = path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(encode_segment)
+            .collect::<Vec<_>>()
+            .join("/");
+        let url = format!("{}/v1/{}/{}/{}", self.address, self.mount, kind, path);
+
+        let request = self
+            .agent
+            .request(method, &url)
+            .set("X-Vault-Token", &self.token)
+            .set("X-Vault-Request", "true");
+        match &self.namespace {
+            Some(namespace) => request.set("X-Vault-Namespace", namespace),
+            None => request,
+        }
+    }
+
+    /// Sends a request and returns the status and body, failing on errors other than 400 and 404
+    fn send(&self, request: Request, body: Option<Value>) -> Result<(u16, String)> {
+        let result = match body {
+            Some(body) => request.send_json(body),
+            None => request.call(),
+        };
+
+        let response = match result {
+            Ok(response) => response,
+            Err(ureq::Error::Status(_, response)) => response,
+            Err(err) => return Err(err).context("failed to reach Vault"),
+        };
+        let status = response.status();
+        let body = response.into_string()?;
+        event!(debug, status, "vault request");
+
+        match status {
+            200..=299 | 400 | 404 => Ok((status, body)),
+            403 => Err(anyhow!("Vault denied access, is the token valid?")),
+            _ => Err(anyhow!(
+                "Vault failed with {}: {}",
+                status,
+                errors(&body).join(", ")
+            )),
+        }
+    }
+
+    /// Reads the latest or a specific version of the secret
+    ///
+    /// A deleted version has no data, but is returned for its metadata.
+    fn read(&self, version: Option<u64>) -> Result<Option<Secret>> {
+        self.read_path(&self.path(), version)
+    }
+
+    fn read_path(&self, path: &str, version: Option<u64>) -> Result<Option<Secret>> {
+        let mut request = self.request("GET", "data", path);
+        if let Some(version) = version {
+            request = request.query("version", &version.to_string());
+        }
+
+        let (status, body) = self.send(request, None)?;
+        match status {
+            // Vault answers 404 for deleted versions, but still sends their metadata
+            404 => Ok(serde_json::from_str::<Response<Secret>>(&body)
+                .ok()
+                .map(|secret| secret.data)),
+            400 => Err(anyhow!("Vault failed: {}", errors(&body).join(", "))),
+            _ => Ok(Some(serde_json::from_str::<Response<Secret>>(&body)?.data)),
+        }
+    }
+
+    /// Writes a new version of the secret, unless its current version isn't `version`
+    ///
+    /// Returns the new version, or `None` when someone else wrote the secret first.
+    fn write(&self, data: Map<String, Value>, version: u64) -> Result<Option<u64>> {
+        let body = json!({
+            "options": { "cas": version },
+            "data": data,
+        });
+        let (status, body) = self.send(self.request("POST", "data", &self.path()), Some(body))?;
+
+        match status {
+            400 if errors(&body)
+                .iter()
+                .any(|error| error.contains("check-and-set")) =>
+            {
+                Ok(None)
+            }
+            400 | 404 => Err(anyhow!(
+                "Vault failed to write the secret: {}",
+                errors(&body).join(", ")
+            )),
+            _ => {
+                let metadata: Response<VersionMetadata> = serde_json::from_str(&body)?;
+                event!(debug, version = metadata.data.version, "wrote the secret");
+
+                Ok(Some(metadata.data.version))
+            }
+        }
+    }
+
+    /// Changes the secret with check-and-set, retrying when another client changed it first
+    ///
+    /// `change` returns false when there is nothing to write.
+    fn update<F>(&self, mut change: F) -> Result<Option<(u64, Map<String, Value>)>>
+    where
+        F: FnMut(&mut Map<String, Value>) -> Result<bool>,
+    {
+        for _ in 0..CAS_ATTEMPTS {
+            let (version, mut data) = match self.read(None)? {
+                Some(secret) => (secret.metadata.version, secret.data.unwrap_or_default()),
+                None => (0, Map::new()),
+            };
+            if !change(&mut data)? {
+                return Ok(None);
+            }
+
+            if let Some(version) = self.write(data.clone(), version)? {
+                return Ok(Some((version, data)));
+            }
+        }
+
+        Err(anyhow!(
+            "the secret {:?} kept changing while writing it",
+            self.path()
+        ))
+    }
+
+    fn password(&self, secret: &Secret) -> Result<Option<String>> {
+        match secret
+            .data
+            .as_ref()
+            .and_then(|data| data.get(self.username))
+        {
+            Some(Value::String(password)) => Ok(Some(password.clone())),
+            Some(_) => Err(anyhow!("the value of {:?} isn't a string", self.username)),
+            None => Ok(None),
+        }
+    }
+
+    /// Adds the keys of the secrets below `path` to `entries`
+    fn collect(&self, path: &str, entries: &mut Vec<Entry>) -> Result<()> {
+        let (status, body) = self.send(self.request("LIST", "metadata", path), None)?;
+        if status == 404 {
+            return Ok(());
+        }
+        let keys: Response<Keys> = serde_json::from_str(&body)?;
+
+        for key in keys.data.keys {
+            let child = if path.is_empty() {
+                key.clone()
+            } else {
+                format!("{}/{}", path, key)
+            };
+            if key.ends_with('/') {
+                self.collect(child.trim_end_matches('/'), entries)?;
+                continue;
+            }
+
+            let data = match self.read_path(&child, None)? {
+                Some(secret) => secret.data.unwrap_or_default(),
+                None => continue,
+            };
+            let service = match child.strip_prefix(&self.prefix) {
+                Some(service) if !self.prefix.is_empty() => service.trim_start_matches('/'),
+                _ => child.as_str(),
+            };
+            entries.extend(data.keys().map(|username| Entry {
+                service: service.to_string(),
+                username: username.clone(),
+            }));
+        }
+
+        Ok(())
+    }
+}
+
+impl Backend for VaultKeyring<'_> {
+    fn name(&self) -> &'static str {
+        "vault"
+    }
+
+    fn set_password(&self, password: String) -> Result<()> {
+        self.update(|data| {
+            data.insert(self.username.to_string(), Value::String(password.clone()));
+            Ok(true)
+        })?;
+
+        Ok(())
+    }
+
+    fn get_password(&self) -> Result<Option<String>> {
+        match self.read(None)? {
+            Some(secret) => self.password(&secret),
+            None => Ok(None),
+        }
+    }
+
+    /// Writes a version without the username, and deletes that version when the secret is left
+    /// empty, so it can still be undeleted
+    fn delete_password(&self) -> Result<bool> {
+        let (version, data) = match self.update(|data| Ok(data.remove(self.username).is_some()))? {
+            Some(written) => written,
+            None => return Ok(false),
+        };
+
+        if data.is_empty() {
+            let body = json!({ "versions": [version] });
+            let (status, body) =
+                self.send(self.request("POST", "delete", &self.path()), Some(body))?;
+            if status == 400 {
+                return Err(anyhow!(
+                    "Vault failed to delete the secret: {}",
+                    errors(&body).join(", ")
+                ));
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Lists the keys of every secret below the prefix
+    fn list(&self) -> Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        self.collect(&self.prefix, &mut entries)?;
+
+        Ok(entries)
+    }
+
+    fn modified(&self) -> Result<Option<u64>> {
+        match self.read(None)? {
+            Some(secret) if self.password(&secret)?.is_some() => {
+                Ok(parse_time(&secret.metadata.created_time))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// The messages of a Vault error response
+fn errors(body: &str) -> Vec<String> {
+    serde_json::from_str::<Errors>(body)
+        .map(|errors| errors.errors)
+        .unwrap_or_default()
+}
+
+/// Percent-encodes a path segment
+fn encode_segment(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Parses the UTC RFC 3339 times Vault returns, e.g. `2018-03-22T02:24:06.945319214Z`, into
+/// seconds since the unix epoch
+fn parse_time(time: &str) -> Option<u64> {
+    let (date, time) = time.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    let mut time = time.get(..8)?.splitn(3, ':').map(str::parse::<i64>);
+    let (hour, minute, second) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+
+    // days from civil, <https://howardhinnant.github.io/date_algorithms.html#days_from_civil>
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    (days * 86_400 + hour * 3_600 + minute * 60 + second)
+        .try_into()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+
+    const TOKEN: &str = "test-token";
+
+    /// A KV version 2 mount at `secret`, served over HTTP on localhost.
+    struct MockVault {
+        address: String,
+        state: Arc<Mutex<State>>,
+    }
+
+    #[derive(Default)]
+    struct State {
+        secrets: BTreeMap<String, Vec<MockVersion>>,
+        // how many of the next writes race with another client's write
+        conflicts: u32,
+    }
+
+    struct MockVersion {
+        data: Map<String, Value>,
+        deleted: bool,
+    }
+
+    impl MockVault {
+        fn start() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = format!("http://{}", listener.local_addr().unwrap());
+            let state = Arc::new(Mutex::new(State::default()));
+
+            let server_state = state.clone();
+            std::thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let state = server_state.clone();
+                    std::thread::spawn(move || serve(stream, &state));
+                }
+            });
+
+            Self { address, state }
+        }
+
+        fn keyring<'a>(&self, username: &'a str, service: &'a str) -> VaultKeyring<'a> {
+            VaultKeyring::new(username, service, &self.address, TOKEN, Options::default()).unwrap()
+        }
+    }
+
+    /// Answers the requests of a connection until the client closes it
+    fn serve(stream: TcpStream, state: &Mutex<State>) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut stream = stream;
+        loop {
+            let mut request_line = String::new();
+            if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+                return;
+            }
+            let mut parts = request_line.split_whitespace();
+            let method = parts.next().unwrap_or_default().to_string();
+            let target = parts.next().unwrap_or_default().to_string();
+
+            let mut length = 0;
+            let mut token = None;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                let (name, value) = line.split_once(':').unwrap();
+                match name.to_ascii_lowercase().as_str() {
+                    "content-length" => length = value.trim().parse().unwrap(),
+                    "x-vault-token" => token = Some(value.trim().to_string()),
+                    _ => {}
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+
+            let (status, body) = if token.as_deref() == Some(TOKEN) {
+                let body = serde_json::from_slice(&body).unwrap_or(Value::Null);
+                handle(&mut state.lock().unwrap(), &method, &target, body)
+            } else {
+                (403, json!({ "errors": ["permission denied"] }))
+            };
+            let body = if status == 204 {
+                String::new()
+            } else {
+                body.to_string()
+            };
+            write!(
+                stream,
+                "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )
+            .unwrap();
+        }
+    }
+
+    fn metadata(number: usize, version: &MockVersion) -> Value {
+        let created_time = format!("2023-11-14T22:13:{:02}.123456789Z", 20 + number);
+        json!({
+            "version": number,
+            "created_time": created_time,
+            "deletion_time": if version.deleted { created_time.as_str() } else { "" },
+            "destroyed": false,
+        })
+    }
+
+    fn handle(state: &mut State, method: &str, target: &str, body: Value) -> (u16, Value) {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let path = path.strip_prefix("/v1/secret/").unwrap();
+        let (kind, path) = path.split_once('/').unwrap_or((path, ""));
+        let not_found = (404, json!({ "errors": [] }));
+
+        match (method, kind) {
+            ("GET", "data") => {
+                let versions = match state.secrets.get(path) {
+                    Some(versions) => versions,
+                    None => return not_found,
+                };
+                let number = match query.strip_prefix("version=") {
+                    Some(number) => number.parse().unwrap(),
+                    None => versions.len(),
+                };
+                let version = match number.checked_sub(1).and_then(|index| versions.get(index)) {
+                    Some(version) => version,
+                    None => return not_found,
+                };
+                let metadata = metadata(number, version);
+                if version.deleted {
+                    (
+                        404,
+                        json!({ "data": { "data": null, "metadata": metadata } }),
+                    )
+                } else {
+                    let data = &version.data;
+                    (
+                        200,
+                        json!({ "data": { "data": data, "metadata": metadata } }),
+                    )
+                }
+            }
+            ("POST", "data") => {
+                let versions = state.secrets.entry(path.to_string()).or_default();
+                if state.conflicts > 0 {
+                    state.conflicts -= 1;
+                    let mut data = versions
+                        .last()
+                        .map(|version| version.data.clone())
+                        .unwrap_or_default();
+                    data.insert("other".to_string(), json!("changed"));
+                    versions.push(MockVersion {
+                        data,
+                        deleted: false,
+                    });
+                }
+                if body["options"]["cas"].as_u64() != Some(versions.len() as u64) {
+                    let error = "check-and-set parameter did not match the current version";
+                    return (400, json!({ "errors": [error] }));
+                }
+
+                versions.push(MockVersion {
+                    data: body["data"].as_object().unwrap().clone(),
+                    deleted: false,
+                });
+                let number = versions.len();
+                (
+                    200,
+                    json!({ "data": metadata(number, &versions[number - 1]) }),
+                )
+            }
+            ("POST", "delete") => {
+                let versions = state.secrets.get_mut(path).unwrap();
+                for number in body["versions"].as_array().unwrap() {
+                    versions[number.as_u64().unwrap() as usize - 1].deleted = true;
+                }
+                (204, Value::Null)
+            }
+            ("GET", "metadata") => {
+                let versions = match state.secrets.get(path) {
+                    Some(versions) => versions,
+                    None => return not_found,
+                };
+                let versions: Map<String, Value> = (1..)
+                    .zip(versions)
+                    .map(|(number, version)| (number.to_string(), metadata(number, version)))
+                    .collect();
+                (200, json!({ "data": { "versions": versions } }))
+            }
+            ("LIST", "metadata") => {
+                let prefix = if path.is_empty() {
+                    String::new()
+                } else {
+                    format!("{}/", path)
+                };
+                let mut keys: Vec<String> = state
+                    .secrets
+                    .keys()
+                    .filter_map(|key| key.strip_prefix(&prefix))
+                    .map(|rest| match rest.split_once('/') {
+                        Some((folder, _)) => format!("{}/", folder),
+                        None => rest.to_string(),
+                    })
+                    .collect();
+                keys.dedup();
+                if keys.is_empty() {
+                    return not_found;
+                }
+                (200, json!({ "data": { "keys": keys } }))
+            }
+            _ => (405, json!({ "errors": ["unsupported"] })),
+        }
+    }
+
+    fn entries(keyring: &VaultKeyring) -> Vec<(String, String)> {
+        let mut entries: Vec<(String, String)> = keyring
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.service, entry.username))
+            .collect();
+        entries.sort();
+
+        entries
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(service, username)| (service.to_string(), username.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let vault = MockVault::start();
+        let alice = vault.keyring("alice", "example.com");
+        let bob = vault.keyring("bob", "example.com");
+        let admin = vault.keyring("admin", "team/db");
+
+        assert_eq!(alice.get_password().unwrap(), None);
+        alice.set_password("hunter2".to_string()).unwrap();
+        bob.set_password("swordfish".to_string()).unwrap();
+        admin.set_password("pässword".to_string()).unwrap();
+        assert_eq!(alice.get_password().unwrap().as_deref(), Some("hunter2"));
+        assert_eq!(bob.get_password().unwrap().as_deref(), Some("swordfish"));
+        assert_eq!(admin.get_password().unwrap().as_deref(), Some("pässword"));
+        // the second version of the secret
+        assert_eq!(alice.modified().unwrap(), Some(1700000000 + 2));
+        assert_eq!(
+            entries(&alice),
+            pairs(&[
+                ("example.com", "alice"),
+                ("example.com", "bob"),
+                ("team/db", "admin"),
+            ])
+        );
+
+        assert!(alice.delete_password().unwrap());
+        assert!(!alice.delete_password().unwrap());
+        assert_eq!(alice.get_password().unwrap(), None);
+        assert_eq!(bob.get_password().unwrap().as_deref(), Some("swordfish"));
+        assert_eq!(
+            entries(&alice),
+            pairs(&[("example.com", "bob"), ("team/db", "admin")])
+        );
+
+        let prefixed = vault.keyring("admin", "db").with_prefix("team");
+        assert_eq!(
+            prefixed.get_password().unwrap().as_deref(),
+            Some("pässword")
+        );
+        assert_eq!(entries(&prefixed), pairs(&[("db", "admin")]));
+    }
+
+    #[test]
+    fn retries_check_and_set_conflicts() {
+        let vault = MockVault::start();
+        let alice = vault.keyring("alice", "example.com");
+        vault.state.lock().unwrap().conflicts = 1;
+
+        alice.set_password("hunter2".to_string()).unwrap();
+        assert_eq!(alice.get_password().unwrap().as_deref(), Some("hunter2"));
+        // the other client's change is kept
+        assert_eq!(
+            entries(&alice),
+            pairs(&[("example.com", "alice"), ("example.com", "other")])
+        );
+
+        vault.state.lock().unwrap().conflicts = CAS_ATTEMPTS;
+        let err = alice.set_password("changed".to_string()).err().unwrap();
+        assert!(err.to_string().contains("kept changing"), "{:#}", err);
+    }
+
+    #[test]
+    fn reads_deleted_versions() {
+        let vault = MockVault::start();
+        let alice = vault.keyring("alice", "example.com");
+        alice.set_password("first".to_string()).unwrap();
+        alice.set_password("second".to_string()).unwrap();
+        assert!(alice.delete_password().unwrap());
+
+        assert_eq!(alice.get_password().unwrap(), None);
+        assert_eq!(alice.modified().unwrap(), None);
+        assert_eq!(alice.get_version(1).unwrap().as_deref(), Some("first"));
+        assert_eq!(alice.get_version(2).unwrap().as_deref(), Some("second"));
+        assert_eq!(alice.get_version(3).unwrap(), None);
+        assert_eq!(alice.get_version(4).unwrap(), None);
+
+        let versions = alice.versions().unwrap();
+        let deleted: Vec<(u64, bool)> = versions
+            .iter()
+            .map(|version| (version.version, version.deleted))
+            .collect();
+        assert_eq!(deleted, vec![(1, false), (2, false), (3, true)]);
+        assert_eq!(versions[0].created, 1700000000 + 1);
+        assert!(vault
+            .keyring("alice", "missing")
+            .versions()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn wrong_token() {
+        let vault = MockVault::start();
+        let keyring = VaultKeyring::new(
+            "alice",
+            "example.com",
+            &vault.address,
+            "wrong",
+            Options::default(),
+        )
+        .unwrap();
+
+        let err = keyring.get_password().err().unwrap();
+        assert!(err.to_string().contains("denied"), "{:#}", err);
+    }
+
+    #[test]
+    fn profiles_are_unsupported() {
+        let options = Options {
+            profile: Some("staging".to_string()),
+            ..Options::default()
+        };
+        let err = VaultKeyring::new("alice", "example.com", "http://localhost", TOKEN, options)
+            .err()
+            .unwrap();
+        assert!(
+            matches!(err.downcast_ref(), Some(crate::Error::Unsupported { .. })),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn token_lookup_skips_the_registry() {
+        if std::env::var(TOKEN_VARIABLE).is_ok() {
+            return;
+        }
+
+        // this used to look the token up in a vault backend, which looked it up in another one
+        // until the stack overflowed. Whether the OS keyring has a token depends on the machine.
+        let _selection = crate::registry::lock_selection();
+        crate::force_backend(Some("vault"));
+        let _ = VaultKeyring::from_env("alice", "example.com", "app", Options::default());
+        crate::force_backend(None);
+    }
+}
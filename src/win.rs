@@ -4,6 +4,8 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+use crate::backend::Backend;
+use crate::options::Options;
 use crate::wincred::client::WincredClient;
 use anyhow::Result;
 
@@ -12,16 +14,31 @@ pub struct Keyring<'a> {
 }
 
 impl<'a> Keyring<'a> {
-    pub fn new(username: &'a str, service: &'a str, _application: &'a str) -> Result<Self> {
+    pub fn new(
+        username: &'a str,
+        service: &'a str,
+        _application: &'a str,
+        options: Options,
+    ) -> Result<Self> {
+        options.no_profile("wincred")?;
+
         let client: WincredClient = WincredClient::new(username, service)?;
         Ok(Self { client })
     }
+}
 
-    pub fn set_password(&self, password: String) -> Result<()> {
+impl Backend for Keyring<'_> {
+    fn name(&self) -> &'static str {
+        "wincred"
+    }
+
+    fn set_password(&self, password: String) -> Result<()> {
         self.client.set_password(password)
     }
 
-    pub fn get_password(&self) -> Result<Option<String>> {
+    fn get_password(&self) -> Result<Option<String>> {
+        span!("get_password");
+
         self.client.get_password()
     }
 }
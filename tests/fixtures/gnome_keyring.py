@@ -0,0 +1,111 @@
+#!/usr/bin/env python3
+# This Source Code Form is subject to the terms of the Mozilla Public
+# License, v. 2.0. If a copy of the MPL was not distributed with this
+# file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+"""Writes login.keyring, a keyring file in gnome-keyring's binary format.
+
+It follows gnome-keyring/pkcs11/secret-store/gkm-secret-binary.c: the key and IV come from
+egg_symkey_generate_simple with SHA-256 and the items are encrypted with AES-128-CBC after an MD5
+checksum. The password is `fixture password`.
+"""
+
+import hashlib
+import os
+import struct
+
+from cryptography.hazmat.primitives.ciphers import Cipher, algorithms, modes
+
+HEADER = b"GnomeKeyring\n\r\0\n"
+PASSWORD = b"fixture password"
+ITERATIONS = 1000
+SALT = bytes(range(8))
+
+# item types
+GENERIC_SECRET = 0
+NETWORK_PASSWORD = 1
+
+# attribute types
+STRING = 0
+UINT32 = 1
+
+# an access control list entry: allowed types, display name, path, reserved string and number
+ACL = [(7, "seahorse", "/usr/bin/seahorse")]
+
+
+def u32(value):
+    return struct.pack(">I", value)
+
+
+def time(value):
+    return u32(value >> 32) + u32(value & 0xffffffff)
+
+
+def string(value):
+    if value is None:
+        return u32(0xffffffff)
+    data = value.encode() if isinstance(value, str) else value
+    return u32(len(data)) + data
+
+
+def attributes(values, hashed):
+    data = u32(len(values))
+    for name, value in values:
+        data += string(name)
+        if isinstance(value, int):
+            data += u32(UINT32) + u32(value)
+        elif hashed:
+            data += u32(STRING) + string(hashlib.md5(value.encode()).hexdigest())
+        else:
+            data += u32(STRING) + string(value)
+    return data
+
+
+# (type, label, attributes, secret, created, modified, access control list)
+items = [
+    (GENERIC_SECRET, "Password for example.com: alice",
+     [("application", "app"), ("service", "example.com")],
+     b"hunter2", 1700000000, 1700000100, []),
+    (NETWORK_PASSWORD, "bob@example.org",
+     [("user", "bob"), ("server", "example.org"), ("protocol", "https"), ("port", 443)],
+     "pässword".encode(), 1700000200, 1700000200, ACL),
+    (GENERIC_SECRET, "Password for other.example.com: carol",
+     [("application", "app"), ("service", "other.example.com"),
+      ("xdg:schema", "io.github.tmuntaner.Keyring")],
+     b"swordfish", 1700000300, 1700000300, []),
+    (GENERIC_SECRET, "Password for example.com: dave",
+     [("application", "another-app"), ("service", "example.com")],
+     b"another", 1700000400, 1700000400, []),
+    (GENERIC_SECRET, "Not stored by an application",
+     [("application", "app"), ("service", "unlabelled")],
+     b"unlabelled", 1700000500, 1700000500, []),
+]
+
+hashed = u32(len(items))
+encrypted = b""
+for (index, (item_type, label, values, secret, created, modified, acl)) in enumerate(items):
+    hashed += u32(index + 1) + u32(item_type) + attributes(values, True)
+    encrypted += string(label) + string(secret) + time(created) + time(modified) + string(None)
+    encrypted += u32(0) * 4 + attributes(values, False) + u32(len(acl))
+    for (types, name, path) in acl:
+        encrypted += u32(types) + string(name) + string(path) + string(None) + u32(0)
+
+# padded with zeros, the checksum covers the padding
+encrypted += bytes(-len(encrypted) % 16)
+encrypted = hashlib.md5(encrypted).digest() + encrypted
+
+digest = hashlib.sha256(PASSWORD + SALT).digest()
+for _ in range(1, ITERATIONS):
+    digest = hashlib.sha256(digest).digest()
+encryptor = Cipher(algorithms.AES(digest[:16]), modes.CBC(digest[16:])).encryptor()
+encrypted = encryptor.update(encrypted) + encryptor.finalize()
+
+# version, AES and MD5
+data = HEADER + bytes([0, 0, 0, 0])
+data += string("Login") + time(1700000000) + time(1700000500)
+# flags, lock timeout and the key derivation
+data += u32(0) + u32(0) + u32(ITERATIONS) + SALT + u32(0) * 4
+data += hashed + u32(len(encrypted)) + encrypted
+
+with open(os.path.join(os.path.dirname(__file__), "login.keyring"), "wb") as f:
+    f.write(data)
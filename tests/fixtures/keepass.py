@@ -0,0 +1,190 @@
+#!/usr/bin/env python3
+# This Source Code Form is subject to the terms of the Mozilla Public
+# License, v. 2.0. If a copy of the MPL was not distributed with this
+# file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+"""Writes KDBX 4 databases with the layout KeePassXC gives new databases.
+
+* keepass.kdbx - AES-256, Argon2d and gzip, unlocked with the password `fixture password`
+* keepass-key-file.kdbx - ChaCha20 and Argon2id, unlocked with the key file keepass.keyx
+
+Both have an entry `example.com` for `alice`, a group `Servers` with an entry for `admin` and an
+entry for `alice` in the recycle bin. See https://keepass.info/help/kb/kdbx_4.html.
+"""
+
+import base64
+import ctypes
+import gzip
+import hashlib
+import hmac
+import os
+import struct
+
+from cryptography.hazmat.primitives import padding
+from cryptography.hazmat.primitives.ciphers import Cipher, algorithms, modes
+
+DIR = os.path.dirname(__file__)
+
+SIGNATURES = struct.pack("<III", 0x9AA2D903, 0xB54BFB67, 0x00040001)
+CIPHER_AES256 = bytes.fromhex("31c1f2e6bf714350be5805216afc5aff")
+CIPHER_CHACHA20 = bytes.fromhex("d6038a2b8b6f4cb5a524339a31dbb59a")
+KDF_ARGON2D = bytes.fromhex("ef636ddf8c29444b91f7a9a403e30a0c")
+KDF_ARGON2ID = bytes.fromhex("9e298b1956db4773b23dfc3ec6f0a1e6")
+ARGON2_TYPES = {KDF_ARGON2D: 0, KDF_ARGON2ID: 2}
+
+# small parameters, so tests are quick
+ARGON2_MEMORY = 1024 * 1024
+ARGON2_ITERATIONS = 2
+ARGON2_PARALLELISM = 2
+
+# 2023-11-14T22:13:20Z, base64 of little endian seconds since 0001-01-01
+TIME = base64.b64encode(struct.pack("<q", 1700000000 + 62135596800)).decode()
+
+ROOT_UUID = base64.b64encode(bytes([1] * 16)).decode()
+SERVERS_UUID = base64.b64encode(bytes([2] * 16)).decode()
+RECYCLE_BIN_UUID = base64.b64encode(bytes([3] * 16)).decode()
+
+libargon2 = ctypes.CDLL("libargon2.so.1")
+libargon2.argon2_hash.argtypes = [
+    ctypes.c_uint32, ctypes.c_uint32, ctypes.c_uint32, ctypes.c_char_p, ctypes.c_size_t,
+    ctypes.c_char_p, ctypes.c_size_t, ctypes.c_char_p, ctypes.c_size_t, ctypes.c_char_p,
+    ctypes.c_size_t, ctypes.c_int, ctypes.c_int]
+
+
+def times():
+    return ("<Times><LastModificationTime>{0}</LastModificationTime><CreationTime>{0}"
+            "</CreationTime><LastAccessTime>{0}</LastAccessTime><ExpiryTime>{0}</ExpiryTime>"
+            "<Expires>False</Expires><UsageCount>0</UsageCount><LocationChanged>{0}"
+            "</LocationChanged></Times>").format(TIME)
+
+
+def entry(uuid, title, username, password):
+    return ("<Entry><UUID>{}</UUID><IconID>0</IconID><ForegroundColor/><BackgroundColor/>"
+            "<OverrideURL/><Tags/>{}<String><Key>Notes</Key><Value/></String><String>"
+            "<Key>Password</Key><Value Protected=\"True\">{}</Value></String><String>"
+            "<Key>Title</Key><Value>{}</Value></String><String><Key>URL</Key><Value/></String>"
+            "<String><Key>UserName</Key><Value>{}</Value></String><AutoType><Enabled>True"
+            "</Enabled><DataTransferObfuscation>0</DataTransferObfuscation>"
+            "<DefaultSequence/></AutoType><History/></Entry>").format(
+        base64.b64encode(bytes([uuid] * 16)).decode(), times(), password, title, username)
+
+
+def group(uuid, name, icon, children):
+    return ("<Group><UUID>{}</UUID><Name>{}</Name><Notes/><IconID>{}</IconID>{}"
+            "<IsExpanded>True</IsExpanded><DefaultAutoTypeSequence/><EnableAutoType>null"
+            "</EnableAutoType><EnableSearching>null</EnableSearching><LastTopVisibleEntry>"
+            "AAAAAAAAAAAAAAAAAAAAAA==</LastTopVisibleEntry>{}</Group>").format(
+        uuid, name, icon, times(), "".join(children))
+
+
+DOCUMENT = ("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n<KeePassFile><Meta>"
+            "<Generator>KeePassXC</Generator><DatabaseName>Fixture</DatabaseName>"
+            "<DatabaseNameChanged>{0}</DatabaseNameChanged><MemoryProtection><ProtectTitle>False"
+            "</ProtectTitle><ProtectUserName>False</ProtectUserName><ProtectPassword>True"
+            "</ProtectPassword><ProtectURL>False</ProtectURL><ProtectNotes>False</ProtectNotes>"
+            "</MemoryProtection><RecycleBinEnabled>True</RecycleBinEnabled><RecycleBinUUID>{1}"
+            "</RecycleBinUUID><RecycleBinChanged>{0}</RecycleBinChanged><HistoryMaxItems>10"
+            "</HistoryMaxItems><HistoryMaxSize>6291456</HistoryMaxSize></Meta><Root>{2}"
+            "<DeletedObjects/></Root></KeePassFile>").format(TIME, RECYCLE_BIN_UUID, group(
+                ROOT_UUID, "Root", 48, [
+                    entry(4, "example.com", "alice", "hunter2"),
+                    group(SERVERS_UUID, "Servers", 48, [entry(5, "db", "admin", "pässword")]),
+                    group(RECYCLE_BIN_UUID, "Recycle Bin", 43,
+                          [entry(6, "example.com", "alice", "deleted")]),
+                ]))
+
+
+def field(field_id, value):
+    return bytes([field_id]) + struct.pack("<I", len(value)) + value
+
+
+def variant(value_type, name, value):
+    return bytes([value_type]) + struct.pack("<I", len(name)) + name + struct.pack(
+        "<I", len(value)) + value
+
+
+def kdf_parameters(uuid, salt):
+    return (struct.pack("<H", 0x100) + variant(0x42, b"$UUID", uuid)
+            + variant(0x05, b"I", struct.pack("<Q", ARGON2_ITERATIONS))
+            + variant(0x05, b"M", struct.pack("<Q", ARGON2_MEMORY))
+            + variant(0x04, b"P", struct.pack("<I", ARGON2_PARALLELISM))
+            + variant(0x42, b"S", salt) + variant(0x04, b"V", struct.pack("<I", 0x13)) + b"\0")
+
+
+def argon2(kdf, key, salt):
+    transformed = ctypes.create_string_buffer(32)
+    assert libargon2.argon2_hash(ARGON2_ITERATIONS, ARGON2_MEMORY // 1024, ARGON2_PARALLELISM,
+                                 key, len(key), salt, len(salt), transformed, 32, None, 0,
+                                 ARGON2_TYPES[kdf], 0x13) == 0
+    return transformed.raw
+
+
+def protect(document, stream_key):
+    """Encrypts the protected values with the ChaCha20 inner stream, in document order"""
+    digest = hashlib.sha512(stream_key).digest()
+    # a 32 bit block counter of 0 before the 12 byte nonce
+    stream = Cipher(algorithms.ChaCha20(digest[:32], bytes(4) + digest[32:44]), None).encryptor()
+    parts = document.split("<Value Protected=\"True\">")
+    for (index, part) in enumerate(parts[1:], 1):
+        value, rest = part.split("</Value>", 1)
+        value = base64.b64encode(stream.update(value.encode())).decode()
+        parts[index] = value + "</Value>" + rest
+    return "<Value Protected=\"True\">".join(parts).encode()
+
+
+def block_hmac(hmac_key, index, data):
+    key = hashlib.sha512(struct.pack("<Q", index) + hmac_key).digest()
+    return hmac.new(key, data, hashlib.sha256).digest()
+
+
+def write(name, composite_key, cipher, kdf, compressed, seed):
+    master_seed = bytes([seed] * 32)
+    salt = bytes([seed + 1] * 32)
+    iv = bytes([seed + 2] * (16 if cipher == CIPHER_AES256 else 12))
+    stream_key = bytes([seed + 3] * 64)
+
+    header = SIGNATURES + field(2, cipher) + field(3, struct.pack("<I", compressed))
+    header += field(4, master_seed) + field(7, iv) + field(11, kdf_parameters(kdf, salt))
+    header += field(0, b"\r\n\r\n")
+
+    payload = field(1, struct.pack("<I", 3)) + field(2, stream_key) + field(0, b"")
+    payload += protect(DOCUMENT, stream_key)
+    if compressed:
+        payload = gzip.compress(payload, mtime=0)
+
+    transformed = argon2(kdf, composite_key, salt)
+    key = hashlib.sha256(master_seed + transformed).digest()
+    if cipher == CIPHER_AES256:
+        padder = padding.PKCS7(128).padder()
+        payload = padder.update(payload) + padder.finalize()
+        encryptor = Cipher(algorithms.AES(key), modes.CBC(iv)).encryptor()
+    else:
+        encryptor = Cipher(algorithms.ChaCha20(key, bytes(4) + iv), None).encryptor()
+    payload = encryptor.update(payload) + encryptor.finalize()
+
+    hmac_key = hashlib.sha512(master_seed + transformed + b"\x01").digest()
+    data = header + hashlib.sha256(header).digest() + block_hmac(hmac_key, 2**64 - 1, header)
+    for (index, block) in enumerate([payload, b""]):
+        size = struct.pack("<I", len(block))
+        data += block_hmac(hmac_key, index, struct.pack("<Q", index) + size + block)
+        data += size + block
+
+    with open(os.path.join(DIR, name), "wb") as f:
+        f.write(data)
+
+
+# a version 2.0 key file, like KeePassXC creates
+KEY = bytes(range(32))
+KEY_FILE = ("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<KeyFile>\n    <Meta>\n"
+            "        <Version>2.0</Version>\n    </Meta>\n    <Key>\n"
+            "        <Data Hash=\"{}\">\n            {}\n        </Data>\n    </Key>\n"
+            "</KeyFile>\n").format(
+    hashlib.sha256(KEY).hexdigest()[:8].upper(),
+    " ".join(KEY.hex().upper()[i:i + 8] for i in range(0, 64, 8)))
+with open(os.path.join(DIR, "keepass.keyx"), "w") as f:
+    f.write(KEY_FILE)
+
+write("keepass.kdbx", hashlib.sha256(hashlib.sha256(b"fixture password").digest()).digest(),
+      CIPHER_AES256, KDF_ARGON2D, 1, 10)
+write("keepass-key-file.kdbx", hashlib.sha256(KEY).digest(), CIPHER_CHACHA20, KDF_ARGON2ID, 0,
+      20)
@@ -0,0 +1,84 @@
+#!/usr/bin/env python3
+# This Source Code Form is subject to the terms of the Mozilla Public
+# License, v. 2.0. If a copy of the MPL was not distributed with this
+# file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+"""Writes portal.keyring, a keyring file in libsecret's format.
+
+The GVariants are serialized by GLib, like libsecret does, and the items are encrypted the way
+libsecret/secret-file-collection.c encrypts them. The master secret is `fixture master secret`.
+"""
+
+import ctypes
+import hashlib
+import hmac
+import os
+
+from cryptography.hazmat.primitives.ciphers import Cipher, algorithms, modes
+
+glib = ctypes.CDLL("libglib-2.0.so.0")
+glib.g_variant_parse.restype = ctypes.c_void_p
+glib.g_variant_parse.argtypes = [ctypes.c_void_p, ctypes.c_char_p, ctypes.c_void_p,
+                                 ctypes.c_void_p, ctypes.c_void_p]
+glib.g_variant_type_new.restype = ctypes.c_void_p
+glib.g_variant_type_new.argtypes = [ctypes.c_char_p]
+glib.g_variant_get_size.restype = ctypes.c_size_t
+glib.g_variant_get_size.argtypes = [ctypes.c_void_p]
+glib.g_variant_get_data.restype = ctypes.c_void_p
+glib.g_variant_get_data.argtypes = [ctypes.c_void_p]
+
+HEADER = b"GnomeKeyring\n\r\0\n"
+MASTER_SECRET = b"fixture master secret"
+ITERATIONS = 1000
+SALT = bytes(range(32))
+
+
+def serialize(type_string, text):
+    variant = glib.g_variant_parse(glib.g_variant_type_new(type_string.encode()), text.encode(),
+                                   None, None, None)
+    assert variant, text
+    return ctypes.string_at(glib.g_variant_get_data(variant), glib.g_variant_get_size(variant))
+
+
+def string(value):
+    return "'" + value.replace("\\", "\\\\").replace("'", "\\'") + "'"
+
+
+def byte_array(data):
+    return "@ay [" + ", ".join("byte 0x%02x" % b for b in data) + "]"
+
+
+key = hashlib.pbkdf2_hmac("sha256", MASTER_SECRET, SALT, ITERATIONS, 32)
+
+
+def encrypt_item(attributes, label, created, modified, secret, iv):
+    item = serialize("(a{ss}sttay)", "({%s}, %s, uint64 %d, uint64 %d, %s)" % (
+        ", ".join("%s: %s" % (string(k), string(v)) for k, v in sorted(attributes.items())),
+        string(label), created, modified, byte_array(secret)))
+    padding = 16 - len(item) % 16
+    item += bytes([padding]) * padding
+    encryptor = Cipher(algorithms.AES(key), modes.CBC(iv)).encryptor()
+    blob = encryptor.update(item) + encryptor.finalize() + iv
+    blob += hmac.new(key, blob, hashlib.sha256).digest()
+    hashed = {name: hmac.new(key, value.encode(), hashlib.sha256).digest()
+              for name, value in attributes.items()}
+    return "({%s}, %s)" % (
+        ", ".join("%s: %s" % (string(k), byte_array(v)) for k, v in sorted(hashed.items())),
+        byte_array(blob))
+
+
+items = [
+    encrypt_item({"application": "app", "service": "example.com", "username": "alice",
+                  "xdg:schema": "io.github.tmuntaner.Keyring"},
+                 "Password for example.com: alice", 1700000000, 1700000100, b"hunter2",
+                 bytes([1] * 16)),
+    encrypt_item({"xdg:schema": "org.freedesktop.Secret.Generic", "service": "other",
+                  "username": "bob"},
+                 "Stored by secret-tool", 1700000200, 1700000200, "pässword".encode(),
+                 bytes([2] * 16)),
+]
+keyring = serialize("(uayutua(a{say}ay))", "(uint32 %d, %s, uint32 %d, uint64 %d, uint32 0, [%s])" % (
+    len(SALT), byte_array(SALT), ITERATIONS, 1700000300, ", ".join(items)))
+
+with open(os.path.join(os.path.dirname(__file__), "portal.keyring"), "wb") as f:
+    f.write(HEADER + bytes([1, 0]) + keyring)